@@ -1,32 +1,65 @@
 use bevy::{gltf::Gltf, prelude::*, scene::SceneInstance};
 use bevy_egui::EguiContext;
+use egui::{Color32, RichText};
+use gridly::prelude::Grid;
+use itertools::Itertools;
 use iyes_loopless::state::{CurrentState, NextState};
 use tap::Tap;
 
 use crate::{
     common::{NeedsMaterial, Position, Vec2Ext},
-    cursor::{Bindings, Cursor, CursorBundle},
+    cursor::{Bindings, Cursor, CursorBundle, Hint, HintMarkers},
     load::Textures,
-    main_menu::standard_window,
-    minefield::{query::MinefieldQuery, specific::TILE_SIZE, Minefield},
-    server::{ClientMessage, CommonConnection as Connection},
+    main_menu::{standard_window, GameSelectError, JoinedGame, Menu},
+    minefield::{
+        query::MinefieldQuery,
+        solver::{Solver, Visible},
+        specific::TILE_SIZE,
+        MineCount, Minefield,
+    },
+    replay::Recording,
+    server::{ClientMessage, CommonConnection as Connection, ServerMessage},
 };
 
 use super::{
-    components::{ClientTile, ClientTileBundle, FreezeTimer, FreezeTimerDisplay},
+    components::{
+        ClientTile, ClientTileBundle, FormerPlayers, FreezeTimer, FreezeTimerDisplay, PlayerColor,
+        StageClock,
+    },
     protocol::{AreaAttackRequest, AreaAttackUpdate},
     puppet::{Puppet, PuppetCursorBundle},
+    reconnect::{ServerClosing, Session},
+    replay::Exchange,
+    settings::AreaAttackSettings,
     states::AreaAttack,
 };
 
-pub fn begin_game(mut ctx: ResMut<EguiContext>, sock: Option<ResMut<Connection>>) {
+/// Everything which makes up the board of a game, so that it can be cleared away
+pub type BoardEntities<'w, 's> = Query<
+    'w,
+    's,
+    Entity,
+    Or<(
+        With<Minefield<Entity>>,
+        With<ClientTile>,
+        With<Cursor>,
+        With<Hint>,
+    )>,
+>;
+
+pub fn despawn_board(commands: &mut Commands, board: &BoardEntities) {
+    for entity in board {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+pub fn finish_screen(mut ctx: ResMut<EguiContext>, settings: Option<Res<AreaAttackSettings>>) {
     standard_window(&mut ctx, |ui| {
         ui.vertical_centered(|ui| {
-            if ui.button("Begin game").clicked() {
-                sock.unwrap().repeat_send_unchecked(ClientMessage::Ingame {
-                    data: rmp_serde::to_vec(&AreaAttackRequest::StartGame).unwrap(),
-                });
-            };
+            ui.label(RichText::new("Game over").size(32.0).color(Color32::GOLD));
+            if let Some(seed) = settings.and_then(|settings| settings.seed) {
+                ui.label(format!("Seed: {seed}"));
+            }
         })
     });
 }
@@ -35,21 +68,21 @@ pub fn request_reveal(
     cursor: Query<&Position, (With<Cursor>, Without<Puppet>)>,
     keybinds: Res<Bindings>,
     kb: Res<Input<KeyCode>>,
-    mut sock: ResMut<Connection>,
+    mut requests: EventWriter<AreaAttackRequest>,
     state: Res<CurrentState<AreaAttack>>,
     mut field: MinefieldQuery<&mut ClientTile>,
     puppets: Query<&Puppet>,
 ) {
-    let Some(mut field) = field.get_single() else { return; };
-    let Ok(&position) = cursor.get_single() else { return; };
+    let Some(mut field) = field.get_single() else {
+        return;
+    };
+    let Ok(&position) = cursor.get_single() else {
+        return;
+    };
 
     if kb.just_pressed(keybinds.check) {
         match field.get(position).unwrap() {
-            ClientTile::Unknown => {
-                sock.send_logged(ClientMessage::Ingame {
-                    data: rmp_serde::to_vec(&AreaAttackRequest::Reveal(position)).unwrap(),
-                });
-            }
+            ClientTile::Unknown => requests.send(AreaAttackRequest::Reveal(position)),
             ClientTile::Owned {
                 player,
                 num_neighbors,
@@ -64,10 +97,7 @@ pub fn request_reveal(
                     if marked_count == *num_neighbors {
                         for (position, tile) in field.neighbors(position) {
                             if !matches!(tile, ClientTile::Flag) {
-                                sock.send_logged(ClientMessage::Ingame {
-                                    data: rmp_serde::to_vec(&AreaAttackRequest::Reveal(position))
-                                        .unwrap(),
-                                });
+                                requests.send(AreaAttackRequest::Reveal(position));
                             }
                         }
                     }
@@ -92,9 +122,115 @@ pub fn request_reveal(
     }
 }
 
-pub fn listen_net(mut events: EventWriter<AreaAttackUpdate>, mut sock: ResMut<Connection>) {
-    while let Some(Ok(m)) = sock.recv_message() {
-        events.send(m)
+pub fn show_hint(
+    kb: Res<Input<KeyCode>>,
+    keybinds: Res<Bindings>,
+    cursor: Query<(&Cursor, &Position), Without<Puppet>>,
+    fields: Query<&Minefield<Entity>>,
+    tiles: Query<&ClientTile>,
+    puppets: Query<&Puppet>,
+    mut markers: HintMarkers,
+) {
+    if !kb.just_pressed(keybinds.hint) {
+        return;
+    }
+    let Ok((cursor, &position)) = cursor.get_single() else {
+        return;
+    };
+    let Ok(field) = fields.get(cursor.owning_minefield) else {
+        return;
+    };
+    let solver = Solver::read(field, |tile| {
+        Some(match tiles.get(tile).ok()? {
+            ClientTile::Unknown | ClientTile::Flag => Visible::Hidden,
+            // the numbers on tiles owned by other players are kept from this client
+            ClientTile::Owned { player, .. } if puppets.iter().any(|puppet| puppet == player) => {
+                Visible::Clear
+            }
+            &ClientTile::Owned { num_neighbors, .. } => Visible::Revealed(num_neighbors),
+            ClientTile::Mine => Visible::Mine,
+            ClientTile::Destroyed => Visible::Clear,
+        })
+    });
+    // the number of mines on the field is only known to the server
+    if let Some((hint, chance)) = solver.hint(position, None) {
+        markers.show(hint, chance);
+    }
+}
+
+pub fn listen_net(
+    mut commands: Commands,
+    mut events: EventWriter<AreaAttackUpdate>,
+    mut sock: ResMut<Connection>,
+    mut recording: Option<ResMut<Recording<Exchange>>>,
+    board: BoardEntities,
+) {
+    while let Some(Ok(msg)) = sock.recv_message::<ServerMessage>() {
+        match msg {
+            ServerMessage::Ingame { data } => {
+                let update = match rmp_serde::from_slice::<AreaAttackUpdate>(&data) {
+                    Ok(update) => update,
+                    Err(e) => {
+                        log::warn!("Could not read an update from the game: {e}");
+                        continue;
+                    }
+                };
+                if let Some(recording) = &mut recording {
+                    recording.record(Exchange::Received(update.clone()));
+                }
+                events.send(update)
+            }
+            // the game has been returned to after the connection was lost
+            ServerMessage::Joined {
+                game, you_are_host, ..
+            } => commands.insert_resource(JoinedGame {
+                id: game,
+                host: you_are_host,
+            }),
+            ServerMessage::JoinRejected { message, .. } => {
+                log::info!("Could not return to the game: {message}");
+                despawn_board(&mut commands, &board);
+                commands.insert_resource(GameSelectError(message));
+                commands.insert_resource(NextState(AreaAttack::Inactive));
+                commands.insert_resource(NextState(Menu::GameSelect));
+                return;
+            }
+            ServerMessage::Error { kind, message } if kind.leaves_game() => {
+                log::info!("Left the game: {message}");
+                despawn_board(&mut commands, &board);
+                commands.insert_resource(GameSelectError(message));
+                commands.insert_resource(NextState(AreaAttack::Inactive));
+                commands.insert_resource(NextState(Menu::GameSelect));
+                return;
+            }
+            ServerMessage::Error { message, .. } => log::warn!("The server reported: {message}"),
+            ServerMessage::ShuttingDown { seconds } => {
+                log::info!("The server is shutting down in {seconds}s");
+                commands.insert_resource(ServerClosing::new(seconds));
+            }
+            ServerMessage::Kicked(reason) => {
+                log::warn!("Kicked by the server: {reason}");
+                // there is no point in coming back to a server which does not want this client
+                commands.remove_resource::<Session>();
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Sends the requests made by this client to the server, keeping them in the recording of the game
+pub fn send_requests(
+    mut requests: EventReader<AreaAttackRequest>,
+    mut sock: ResMut<Connection>,
+    mut recording: Option<ResMut<Recording<Exchange>>>,
+) {
+    for request in requests.iter() {
+        sock.send_logged(ClientMessage::Ingame {
+            data: rmp_serde::to_vec(request).unwrap(),
+        });
+        if let Some(recording) = &mut recording {
+            recording.record(Exchange::Sent(request.clone()));
+        }
     }
 }
 
@@ -135,6 +271,8 @@ pub fn reset_field(
                         .id()
                 },
                 template,
+                // where the mines are is only known to the server
+                MineCount::Exact(0),
             );
 
             commands.spawn(field);
@@ -145,12 +283,27 @@ pub fn reset_field(
 pub fn player_update(
     mut events: EventReader<AreaAttackUpdate>,
     mut commands: Commands,
-    mut puppets: Query<(&mut Cursor, &mut Position, &Puppet)>,
+    mut puppets: Query<(
+        Entity,
+        &mut Cursor,
+        &mut Position,
+        &mut Name,
+        &mut PlayerColor,
+        &Puppet,
+    )>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     textures: Res<Textures>,
+    mut former: ResMut<FormerPlayers>,
 ) {
     for ev in events.iter() {
-        if let AreaAttackUpdate::PlayerProperties {
+        if let AreaAttackUpdate::PlayerLeft { id } = ev {
+            if let Some((puppet, cursor, ..)) =
+                puppets.iter().find(|(.., &Puppet(remote))| remote == *id)
+            {
+                former.insert(*id, cursor.tile_material.clone());
+                commands.entity(puppet).despawn_recursive();
+            }
+        } else if let AreaAttackUpdate::PlayerProperties {
             id,
             username,
             color,
@@ -161,10 +314,12 @@ pub fn player_update(
                 emissive: (*color).into(),
                 ..default()
             });
-            if let Some((mut puppet, mut pos, _)) = puppets
+            if let Some((_, mut puppet, mut pos, mut name, mut player_color, _)) = puppets
                 .iter_mut()
-                .find(|(_, _, &Puppet(remote))| remote == *id)
+                .find(|(.., &Puppet(remote))| remote == *id)
             {
+                name.set(username.clone());
+                *player_color = *color;
                 puppet.color = (*color).into();
                 materials.get_mut(&puppet.tile_material).unwrap().emissive = (*color).into();
                 *pos = *position;
@@ -183,7 +338,7 @@ pub fn player_update(
                         },
                         remote: Puppet(*id),
                     })
-                    .insert(NeedsMaterial(mat));
+                    .insert((NeedsMaterial(mat), Name::new(username.clone()), *color));
             }
         }
     }
@@ -232,7 +387,7 @@ pub fn self_update(
                         ..default()
                     },
                 })
-                .insert(NeedsMaterial(material));
+                .insert((NeedsMaterial(material), color));
         } else {
             *save_event = Some(AreaAttackUpdate::SelfChange { color, position })
         }
@@ -242,11 +397,14 @@ pub fn self_update(
 pub fn puppet_control(
     mut events: EventReader<AreaAttackUpdate>,
     mut puppets: Query<(&mut Position, &Puppet)>,
-    mut field: MinefieldQuery<&mut ClientTile>,
+    fields: Query<&Minefield<Entity>>,
+    mut tiles: Query<&mut ClientTile>,
+    mut requests: EventWriter<AreaAttackRequest>,
 ) {
-    if field.get_single().is_none() {
+    let Ok(field) = fields.get_single() else {
         return;
-    }
+    };
+    let mut out_of_step = false;
     for ev in events.iter() {
         match ev {
             AreaAttackUpdate::Reposition { id, position } => {
@@ -258,27 +416,110 @@ pub fn puppet_control(
                 }
             }
             AreaAttackUpdate::TileChanged { position, to } => {
-                *field.get_single().unwrap().get_mut(*position).unwrap() = *to
+                match field
+                    .get(position)
+                    .ok()
+                    .copied()
+                    .flatten()
+                    .and_then(|tile| tiles.get_mut(tile).ok())
+                {
+                    Some(mut tile) => *tile = *to,
+                    None => out_of_step = true,
+                }
+            }
+            AreaAttackUpdate::Snapshot { tiles: runs, .. } => {
+                for (position, to) in field.iter_positions().sorted().zip(runs.decode()) {
+                    if let Ok(mut tile) = tiles.get_mut(field[&position]) {
+                        if *tile != to {
+                            *tile = to;
+                        }
+                    }
+                }
             }
             _ => (),
         }
     }
+    if out_of_step {
+        log::warn!(
+            "The server changed a tile which is not on the field, asking for the whole field"
+        );
+        requests.send(AreaAttackRequest::Snapshot);
+    }
 }
 
 pub fn state_transitions(
     mut events: EventReader<AreaAttackUpdate>,
     mut freeze_timer: ResMut<FreezeTimer>,
+    mut clock: ResMut<StageClock>,
+    stage: Res<CurrentState<AreaAttack>>,
+    settings: Option<Res<AreaAttackSettings>>,
+    mut joined: Option<ResMut<JoinedGame>>,
     mut commands: Commands,
 ) {
     for ev in events.iter() {
         match ev {
-            AreaAttackUpdate::Transition(to) => commands.insert_resource(NextState(*to)),
+            AreaAttackUpdate::Settings(settings) => {
+                freeze_timer.set_duration(settings.freeze_duration);
+                freeze_timer.set_elapsed(settings.freeze_duration);
+                commands.insert_resource(settings.clone());
+            }
+            AreaAttackUpdate::Transition(to) => {
+                commands.insert_resource(NextState(*to));
+                clock.set(
+                    settings
+                        .as_ref()
+                        .and_then(|settings| settings.stage_times(*to))
+                        .map(|times| times.end - times.start),
+                );
+            }
+            AreaAttackUpdate::Snapshot {
+                stage: to,
+                remaining,
+                ..
+            } => {
+                if stage.0 != *to {
+                    commands.insert_resource(NextState(*to));
+                }
+                clock.set(*remaining);
+            }
             AreaAttackUpdate::Freeze => freeze_timer.reset(),
+            AreaAttackUpdate::Session(token) => commands.insert_resource(Session(*token)),
+            AreaAttackUpdate::Host => {
+                if let Some(joined) = &mut joined {
+                    joined.host = true;
+                }
+            }
             _ => (),
         }
     }
 }
 
+/// Shows how long is left until the game moves on to its next stage
+pub fn stage_clock(
+    mut ctx: ResMut<EguiContext>,
+    mut clock: ResMut<StageClock>,
+    stage: Res<CurrentState<AreaAttack>>,
+    time: Res<Time>,
+) {
+    let Some(timer) = Option::as_mut(&mut clock) else {
+        return;
+    };
+    let next = match stage.0 {
+        AreaAttack::Stage1 => "Attack begins",
+        AreaAttack::Attack => "Lock begins",
+        AreaAttack::Lock => "Game ends",
+        _ => return,
+    };
+    let seconds = timer.tick(time.delta()).remaining().as_secs();
+    egui::Area::new("stage_clock")
+        .anchor(egui::Align2::CENTER_TOP, [0.0, 8.0])
+        .show(ctx.ctx_mut(), |ui| {
+            ui.label(
+                RichText::new(format!("{next} in {}:{:02}", seconds / 60, seconds % 60)).size(20.0),
+            );
+        });
+}
+
 pub fn create_freeze_timer(mut commands: Commands, textures: Res<Textures>) {
     commands
         .spawn(
@@ -320,6 +561,7 @@ pub fn draw_tiles(
     textures: Res<Textures>,
     puppets: Query<(&Cursor, &Puppet)>,
     own_cursor: Query<&Cursor, Without<Puppet>>,
+    former: Res<FormerPlayers>,
     gltf: Res<Assets<Gltf>>,
 ) {
     updated_tiles.for_each_mut(|(mut sprite, state, tile_id)| {
@@ -330,14 +572,18 @@ pub fn draw_tiles(
                 player,
                 num_neighbors,
             } => {
-                tile.insert(NeedsMaterial(
-                    puppets
-                        .iter()
-                        .find_map(|(Cursor { tile_material, .. }, &Puppet(rem))| {
-                            (rem == *player).then_some(tile_material.clone())
-                        })
-                        .unwrap_or_else(|| own_cursor.single().tile_material.clone()),
-                ));
+                // a spectator has no cursor of its own, so every owner should be a puppet, or have
+                // been one before it left
+                if let Some(material) = puppets
+                    .iter()
+                    .find_map(|(Cursor { tile_material, .. }, &Puppet(rem))| {
+                        (rem == *player).then_some(tile_material.clone())
+                    })
+                    .or_else(|| former.get(player).cloned())
+                    .or_else(|| Some(own_cursor.get_single().ok()?.tile_material.clone()))
+                {
+                    tile.insert(NeedsMaterial(material));
+                }
                 gltf.get(&textures.mines_3d).unwrap().named_scenes
                     [&format!("f.tile_filled.{num_neighbors}")]
                     .clone()
@@ -353,6 +599,10 @@ pub fn draw_tiles(
     })
 }
 
+pub fn forget_former_players(mut former: ResMut<FormerPlayers>) {
+    former.clear();
+}
+
 pub fn send_position(
     pos: Query<
         &Position,
@@ -362,11 +612,9 @@ pub fn send_position(
             Or<(Added<Position>, Changed<Position>)>,
         ),
     >,
-    mut sock: ResMut<Connection>,
+    mut requests: EventWriter<AreaAttackRequest>,
 ) {
     for pos in pos.iter() {
-        sock.send_logged(ClientMessage::Ingame {
-            data: rmp_serde::to_vec(&AreaAttackRequest::Position(*pos)).unwrap(),
-        });
+        requests.send(AreaAttackRequest::Position(*pos));
     }
 }
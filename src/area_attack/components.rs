@@ -1,120 +1,29 @@
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 
-use bevy::{prelude::*, utils::HashMap};
+use bevy::prelude::*;
 use serde::{Deserialize, Serialize};
 use strum_macros::EnumIter;
 use tap::Tap;
 
-use crate::{
-    common::Position,
-    minefield::{FieldShape, Minefield},
-};
+use crate::{common::Position, server::PlayerId};
 
-use super::{states::AreaAttack, AreaAttackServer};
+#[allow(unused_imports)] // for docs
+use super::states::AreaAttack;
 
 pub const FREEZE_DURATION: Duration = Duration::from_secs(5);
-
-#[derive(Component)]
-pub struct StageTimer {
-    previous_time: Duration,
-    timer: Timer,
-}
-
-impl StageTimer {
-    pub fn tick(&mut self, delta: Duration) -> &Self {
-        self.previous_time = self.timer.elapsed();
-        self.timer.tick(delta);
-        self
-    }
-
-    pub fn has_just_elapsed(&self, duration: Duration) -> bool {
-        self.previous_time < duration && duration < self.timer.elapsed()
-    }
-
-    pub fn just_finished(&mut self) -> bool {
-        self.timer.just_finished()
-    }
-
-    #[allow(dead_code)]
-    pub fn pause(&mut self) {
-        self.timer.pause()
-    }
-
-    pub fn unpause(&mut self) {
-        self.timer.unpause()
-    }
-}
-
-impl Default for StageTimer {
-    fn default() -> Self {
-        Self {
-            previous_time: Duration::from_nanos(0),
-            timer: Timer::new(Duration::from_secs(7 * 60), TimerMode::Once)
-                .tap_mut(|timer| timer.pause()),
-        }
-    }
-}
-
-#[derive(Bundle)]
-pub struct AreaAttackBundle {
-    field: Minefield<Entity>,
-    template: FieldShape,
-    selections: InitialSelections,
-    state: AreaAttack,
-    typed_marker: AreaAttackServer,
-    stage_timer: StageTimer,
-}
-
-impl AreaAttackBundle {
-    pub fn new(
-        commands: &mut Commands,
-        owner: Entity,
-        template: Handle<FieldShape>,
-        template_set: &Res<Assets<FieldShape>>,
-    ) -> Self {
-        let template = template_set.get(&template).unwrap();
-        Self {
-            field: Minefield::new_shaped(
-                |&position| {
-                    commands
-                        .spawn(ServerTileBundle {
-                            tile: ServerTile::Empty,
-                            position,
-                            owner: Owner(owner),
-                        })
-                        .id()
-                },
-                template,
-            ),
-            template: template.clone(),
-            selections: default(),
-            state: AreaAttack::Selecting,
-            typed_marker: AreaAttackServer,
-            stage_timer: default(),
-        }
-    }
-}
-
-#[derive(Bundle)]
-pub struct PlayerBundle {
-    pub color: PlayerColor,
-    pub position: Position,
-    pub frozen: Frozen,
-    pub killed: Killed,
-}
-
-#[derive(Component, Debug, Default, Deref, DerefMut)]
-pub struct Frozen(Option<Duration>);
-
-#[derive(Component, Debug, Default, Deref, DerefMut)]
-pub struct Killed(bool);
-
-#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+/// Time since the game began at which [AreaAttack::Attack] begins
+pub const ATTACK_BEGINS: Duration = Duration::from_secs(3 * 60);
+/// Time since the game began at which [AreaAttack::Lock] begins
+pub const LOCK_BEGINS: Duration = Duration::from_secs(6 * 60);
+/// Time since the game began at which the game is over
+pub const GAME_ENDS: Duration = Duration::from_secs(7 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ServerTile {
     /// No one has claimed the tile, and the tile does not contain a mine
     Empty,
     /// A player has claimed the tile
-    Owned { player: Entity },
+    Owned { player: PlayerId },
     /// There is a mine on this tile, and it has not been revealed
     Mine,
     /// There is a mine on this tile, and it has been revealed
@@ -124,17 +33,7 @@ pub enum ServerTile {
     Destroyed,
 }
 
-#[derive(Component, Deref, Clone, Copy)]
-pub struct Owner(Entity);
-
-#[derive(Bundle)]
-pub struct ServerTileBundle {
-    pub tile: ServerTile,
-    pub position: Position,
-    pub owner: Owner,
-}
-
-#[derive(Component, Serialize, Deserialize, Clone, Copy)]
+#[derive(Component, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
 pub enum ClientTile {
     /// No one has claimed this tile, and it isn't known whether it is blank or contains a mine.
     Unknown,
@@ -144,7 +43,7 @@ pub enum ClientTile {
     /// case a filled tile without numbers will be shown. If this tile is not owned by the client,
     /// then this field will always be zero.
     Owned {
-        player: Entity,
+        player: PlayerId,
         num_neighbors: u8,
     },
     /// There is a mine on this tile which someone has revealed. Therefore it is no longer able to
@@ -163,7 +62,7 @@ pub struct ClientTileBundle {
     pub scene: SceneBundle,
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy, Component, EnumIter, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, Component, EnumIter, PartialEq, Eq, Hash, Debug)]
 pub enum PlayerColor {
     Yellow,
     Green,
@@ -182,14 +81,11 @@ impl From<PlayerColor> for Color {
     }
 }
 
-#[derive(Component, Debug, Deref, DerefMut, Default)]
-pub struct InitialSelections(pub HashMap<Entity, Position>);
-
-#[derive(Debug, Clone, Copy)]
-pub struct RevealTile {
-    pub position: Position,
-    pub player: Entity,
-    pub game: Entity,
+impl From<PlayerColor> for egui::Color32 {
+    fn from(id: PlayerColor) -> Self {
+        let [r, g, b, _] = Color::from(id).as_rgba_f32();
+        egui::Color32::from_rgb((r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8)
+    }
 }
 
 #[derive(Resource, Deref, DerefMut)]
@@ -206,3 +102,18 @@ impl Default for FreezeTimer {
         )
     }
 }
+
+/// The tile materials of players who have left the game, so that the tiles they claimed keep their
+/// color after their cursors are gone
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct FormerPlayers(HashMap<PlayerId, Handle<StandardMaterial>>);
+
+/// Counts down to the end of the current stage, for the stages which are on a clock
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct StageClock(Option<Timer>);
+
+impl StageClock {
+    pub fn set(&mut self, remaining: Option<Duration>) {
+        self.0 = remaining.map(|remaining| Timer::new(remaining, TimerMode::Once));
+    }
+}
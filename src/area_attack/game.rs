@@ -0,0 +1,1141 @@
+//! The rules of Area Attack, kept apart from any particular server. [AreaAttackGame] is a state
+//! machine which is fed requests from players along with the current time, and answers with the
+//! updates that should be sent to each player. Whoever hosts the game is responsible for delivering
+//! those updates and for calling [AreaAttackGame::tick] regularly.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    time::Duration,
+};
+
+use gridly::prelude::*;
+use itertools::Itertools;
+use rand::{rngs::StdRng, Rng};
+use strum::IntoEnumIterator;
+
+use crate::{
+    common::Position,
+    minefield::{FieldShape, Minefield},
+    server::{Access, ErrorKind, GameStatus, PlayerId},
+};
+
+use super::{
+    components::{ClientTile, PlayerColor, ServerTile},
+    protocol::{AreaAttackRequest, AreaAttackUpdate, TileRuns},
+    settings::AreaAttackSettings,
+    states::AreaAttack,
+};
+
+/// Updates produced by the game, each addressed to a single player
+pub type Outgoing = Vec<(PlayerId, AreaAttackUpdate)>;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum JoinError {
+    /// The game has as many players as its settings allow, or every color has been taken
+    #[error("The game is full")]
+    Full,
+    #[error("Another player in the game goes by that name")]
+    NameTaken,
+    /// Players may only join while the game is in its lobby
+    #[error("The game has already begun")]
+    Started,
+}
+
+impl JoinError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            JoinError::Full => ErrorKind::GameFull,
+            JoinError::NameTaken => ErrorKind::NameTaken,
+            JoinError::Started => ErrorKind::GameStarted,
+        }
+    }
+}
+
+/// Why a request was refused without anything being done with it
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum RequestError {
+    #[error("the sender is not in the game")]
+    Stranger,
+    #[error("spectators may only ask to see the field")]
+    Spectating,
+    #[error("{0:?} is not on the field")]
+    OffField(Position),
+}
+
+#[derive(Debug, Clone)]
+pub struct PlayerState {
+    pub username: String,
+    pub color: PlayerColor,
+    pub position: Position,
+    /// The time at which the player was frozen
+    frozen: Option<Duration>,
+    killed: bool,
+}
+
+impl PlayerState {
+    fn is_frozen(&self, now: Duration, freeze_duration: Duration) -> bool {
+        self.frozen
+            .map(|start| now.saturating_sub(start) < freeze_duration)
+            .unwrap_or(false)
+    }
+}
+
+pub struct AreaAttackGame {
+    settings: AreaAttackSettings,
+    field: Minefield<ServerTile>,
+    shape: FieldShape,
+    stage: AreaAttack,
+    /// The time at which the host started the game
+    started: Option<Duration>,
+    host: Option<PlayerId>,
+    players: BTreeMap<PlayerId, PlayerState>,
+    /// Those who are shown everything that happens in the game, but may not take part in it
+    spectators: BTreeSet<PlayerId>,
+    selections: BTreeMap<PlayerId, Position>,
+    rng: StdRng,
+}
+
+impl AreaAttackGame {
+    /// Creates a game on the given field. The settings are assumed to have been validated already.
+    pub fn new(settings: AreaAttackSettings, shape: FieldShape, rng: StdRng) -> Self {
+        Self {
+            field: Minefield::new_shaped(|_| ServerTile::Empty, &shape, settings.mines),
+            settings,
+            shape,
+            stage: AreaAttack::Selecting,
+            started: None,
+            host: None,
+            players: BTreeMap::new(),
+            spectators: BTreeSet::new(),
+            selections: BTreeMap::new(),
+            rng,
+        }
+    }
+
+    pub fn stage(&self) -> AreaAttack {
+        self.stage
+    }
+
+    /// Whether the game has begun and is not over yet
+    pub fn in_progress(&self) -> bool {
+        matches!(
+            self.stage,
+            AreaAttack::Stage1 | AreaAttack::Attack | AreaAttack::Lock
+        )
+    }
+
+    /// The username of every player along with the number of tiles it has claimed, most first
+    pub fn standings(&self) -> Vec<(String, u32)> {
+        let mut claimed: BTreeMap<PlayerId, u32> = BTreeMap::new();
+        for position in self.field.iter_positions() {
+            if let Some(ServerTile::Owned { player }) = self.tile(position) {
+                *claimed.entry(player).or_default() += 1;
+            }
+        }
+        self.players
+            .iter()
+            .map(|(id, player)| {
+                (
+                    player.username.clone(),
+                    claimed.get(id).copied().unwrap_or(0),
+                )
+            })
+            .sorted_by_key(|&(_, tiles)| std::cmp::Reverse(tiles))
+            .collect()
+    }
+
+    /// Whether the player is the one who may start the game
+    pub fn is_host(&self, id: PlayerId) -> bool {
+        self.host == Some(id)
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.players.len() >= self.settings.max_players as usize
+    }
+
+    /// Whether a player in the game already goes by the username
+    pub fn name_taken(&self, username: &str) -> bool {
+        self.players
+            .values()
+            .any(|player| player.username == username)
+    }
+
+    /// How the game is getting on, for the game listing. Players can only join before the game
+    /// has begun.
+    pub fn status(&self) -> GameStatus {
+        GameStatus {
+            access: if self.stage() != AreaAttack::Selecting {
+                Access::Ingame
+            } else if self.is_full() {
+                Access::Full
+            } else {
+                Access::Open
+            },
+            players: self.players.len() as u32,
+            max_players: self.settings.max_players as u32,
+            stage: self.stage().name().to_string(),
+        }
+    }
+
+    pub fn tile(&self, position: Position) -> Option<ServerTile> {
+        self.field.get(position).ok().copied().flatten()
+    }
+
+    fn tile_mut(&mut self, position: Position) -> Option<&mut ServerTile> {
+        self.field.get_mut(position).ok().and_then(Option::as_mut)
+    }
+
+    fn mine_count(&self, position: Position) -> u8 {
+        self.field
+            .iter_neighbors_enumerated(position)
+            .filter(|(_, tile)| matches!(tile, ServerTile::Mine | ServerTile::HardMine))
+            .count() as u8
+    }
+
+    /// Everyone who is shown the game, players and spectators alike
+    fn audience(&self) -> impl Iterator<Item = PlayerId> + '_ {
+        self.players.keys().chain(self.spectators.iter()).copied()
+    }
+
+    fn broadcast(&self, out: &mut Outgoing, update: AreaAttackUpdate) {
+        out.extend(self.audience().map(|id| (id, update.clone())));
+    }
+
+    fn broadcast_except(&self, out: &mut Outgoing, except: PlayerId, update: AreaAttackUpdate) {
+        out.extend(
+            self.audience()
+                .filter(|&id| id != except)
+                .map(|id| (id, update.clone())),
+        );
+    }
+
+    /// Whether a player going by the username could join the game, returning the color it would
+    /// be given
+    pub fn check_join(&self, username: &str) -> Result<PlayerColor, JoinError> {
+        if self.stage != AreaAttack::Selecting {
+            return Err(JoinError::Started);
+        }
+        if self.is_full() {
+            return Err(JoinError::Full);
+        }
+        if self.name_taken(username) {
+            return Err(JoinError::NameTaken);
+        }
+        PlayerColor::iter()
+            .find(|color| self.players.values().all(|player| player.color != *color))
+            .ok_or(JoinError::Full)
+    }
+
+    /// Adds a player to the game, assigning it a color and a starting position. The first player
+    /// to join becomes the host. The player is sent everything it needs to know about the game.
+    pub fn join(
+        &mut self,
+        id: PlayerId,
+        username: String,
+        now: Duration,
+    ) -> Result<Outgoing, JoinError> {
+        let color = self.check_join(&username)?;
+        let position = self
+            .shape
+            .center()
+            .unwrap_or_else(|| self.field.iter_positions().next().unwrap());
+
+        let mut out = Vec::new();
+        self.broadcast(
+            &mut out,
+            AreaAttackUpdate::PlayerProperties {
+                id,
+                username: username.clone(),
+                color,
+                position,
+            },
+        );
+
+        self.host.get_or_insert(id);
+        self.players.insert(
+            id,
+            PlayerState {
+                username,
+                color,
+                position,
+                frozen: None,
+                killed: false,
+            },
+        );
+        out.extend(self.snapshot(id, now));
+        Ok(out)
+    }
+
+    /// Lets someone watch the game without taking part in it
+    pub fn watch(&mut self, id: PlayerId, now: Duration) -> Outgoing {
+        self.spectators.insert(id);
+        self.snapshot(id, now)
+    }
+
+    /// Everything a player or spectator needs to be told to see the game as it is now, whether it
+    /// has just arrived or is returning after losing its connection
+    pub fn snapshot(&self, id: PlayerId, now: Duration) -> Outgoing {
+        let state = self.players.get(&id);
+        if state.is_none() && !self.spectators.contains(&id) {
+            return Vec::new();
+        }
+        let mut updates = vec![
+            AreaAttackUpdate::FieldShape(self.shape.clone()),
+            AreaAttackUpdate::Settings(self.settings.clone()),
+        ];
+        updates.extend(
+            self.players
+                .iter()
+                .filter(|&(&peer_id, _)| peer_id != id)
+                .map(|(&peer_id, peer)| AreaAttackUpdate::PlayerProperties {
+                    id: peer_id,
+                    username: peer.username.clone(),
+                    color: peer.color,
+                    position: peer.position,
+                }),
+        );
+        if let Some(state) = state {
+            updates.push(AreaAttackUpdate::SelfChange {
+                color: state.color,
+                position: state.position,
+            });
+        }
+        updates.push(self.tile_snapshot(id, now));
+        if state.is_some_and(|state| state.killed) {
+            updates.push(AreaAttackUpdate::Killed);
+        }
+        if self.is_host(id) {
+            updates.push(AreaAttackUpdate::Host);
+        }
+        updates.into_iter().map(|update| (id, update)).collect()
+    }
+
+    /// Every tile of the field as the given player sees it, in order of position
+    fn tile_snapshot(&self, viewer: PlayerId, now: Duration) -> AreaAttackUpdate {
+        let tiles = self.field.iter_positions().sorted().map(|position| {
+            match self
+                .selections
+                .iter()
+                .find(|&(_, &selection)| selection == position)
+            {
+                Some((&player, _)) => ClientTile::Owned {
+                    player,
+                    num_neighbors: 0,
+                },
+                None => self
+                    .client_tile(position, viewer)
+                    .unwrap_or(ClientTile::Unknown),
+            }
+        });
+        AreaAttackUpdate::Snapshot {
+            tiles: TileRuns::encode(tiles),
+            stage: self.stage,
+            remaining: self.remaining(now),
+        }
+    }
+
+    /// How long is left until the game moves on from its current stage
+    fn remaining(&self, now: Duration) -> Option<Duration> {
+        let elapsed = now.saturating_sub(self.started?);
+        let times = self.settings.stage_times(self.stage)?;
+        Some(times.end.saturating_sub(elapsed))
+    }
+
+    /// Removes all traces of a player who has left the game, and tells everyone else that it is gone.
+    /// If the player was the host, the player who has been in the game the longest takes over.
+    pub fn leave(&mut self, id: PlayerId) -> Outgoing {
+        let mut out = Vec::new();
+        self.spectators.remove(&id);
+        if self.players.remove(&id).is_some() {
+            self.broadcast(&mut out, AreaAttackUpdate::PlayerLeft { id });
+        }
+        if self.host == Some(id) {
+            // ids are given out in the order that players arrive
+            self.host = self.players.keys().next().copied();
+            out.extend(self.host.map(|host| (host, AreaAttackUpdate::Host)));
+        }
+        if let Some(selection) = self.selections.remove(&id) {
+            self.broadcast(
+                &mut out,
+                AreaAttackUpdate::TileChanged {
+                    position: selection,
+                    to: ClientTile::Unknown,
+                },
+            );
+        }
+        out
+    }
+
+    /// Checks that a request makes sense for the game as it is, which every request must pass
+    /// before it is acted on
+    pub fn validate(
+        &self,
+        player: PlayerId,
+        request: &AreaAttackRequest,
+    ) -> Result<(), RequestError> {
+        if self.spectators.contains(&player) {
+            // spectators may look at the game, but not touch it
+            return match request {
+                AreaAttackRequest::Snapshot => Ok(()),
+                _ => Err(RequestError::Spectating),
+            };
+        }
+        if !self.players.contains_key(&player) {
+            return Err(RequestError::Stranger);
+        }
+        match *request {
+            AreaAttackRequest::Reveal(position) | AreaAttackRequest::Position(position)
+                if !self.field.is_contained(&position) =>
+            {
+                Err(RequestError::OffField(position))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Acts on a request of a player, or refuses it if it does not pass [AreaAttackGame::validate]
+    pub fn handle(
+        &mut self,
+        player: PlayerId,
+        request: AreaAttackRequest,
+        now: Duration,
+    ) -> Outgoing {
+        let mut out = Vec::new();
+        if let Err(e) = self.validate(player, &request) {
+            log::debug!("Refused {request:?}: {e}");
+            return out;
+        }
+
+        match request {
+            AreaAttackRequest::StartGame => self.start(&mut out, player, now),
+            AreaAttackRequest::Reveal(position) if self.stage == AreaAttack::Selecting => {
+                self.select(&mut out, player, position)
+            }
+            AreaAttackRequest::Reveal(position) => self.reveal(&mut out, player, position, now),
+            AreaAttackRequest::Position(position) => {
+                if let Some(state) = self.players.get_mut(&player) {
+                    state.position = position;
+                }
+                self.broadcast_except(
+                    &mut out,
+                    player,
+                    AreaAttackUpdate::Reposition {
+                        id: player,
+                        position,
+                    },
+                );
+            }
+            // colors are assigned by the game
+            AreaAttackRequest::Color(_) => (),
+            AreaAttackRequest::Snapshot => out.push((player, self.tile_snapshot(player, now))),
+        }
+        out
+    }
+
+    /// Moves the game into its next stage once enough time has passed
+    pub fn tick(&mut self, now: Duration) -> Outgoing {
+        let mut out = Vec::new();
+        let Some(started) = self.started else {
+            return out;
+        };
+        let elapsed = now.saturating_sub(started);
+        let settings = &self.settings;
+
+        let new_stage = match self.stage {
+            AreaAttack::Stage1 | AreaAttack::Attack | AreaAttack::Lock
+                if elapsed >= settings.game_ends =>
+            {
+                Some(AreaAttack::Finishing)
+            }
+            AreaAttack::Stage1 | AreaAttack::Attack if elapsed >= settings.lock_begins => {
+                Some(AreaAttack::Lock)
+            }
+            AreaAttack::Stage1 if elapsed >= settings.attack_begins => Some(AreaAttack::Attack),
+            _ => None,
+        };
+
+        if let Some(new_stage) = new_stage {
+            log::debug!("Area attack game transitioning to {new_stage:?}");
+            self.stage = new_stage;
+            self.broadcast(&mut out, AreaAttackUpdate::Transition(new_stage));
+        }
+        out
+    }
+
+    fn start(&mut self, out: &mut Outgoing, player: PlayerId, now: Duration) {
+        if self.host != Some(player) {
+            out.push((player, AreaAttackUpdate::NotHost));
+            return;
+        }
+        if self.stage != AreaAttack::Selecting {
+            return;
+        }
+
+        self.stage = AreaAttack::Stage1;
+        self.started = Some(now);
+        self.broadcast(out, AreaAttackUpdate::Transition(AreaAttack::Stage1));
+
+        // generate minefield while ignoring selected tiles
+        let ignore = self
+            .selections
+            .values()
+            .flat_map(|selection| selection.local_group())
+            .collect_vec();
+        let mines = self
+            .field
+            .choose_multiple(&ignore, &mut self.rng)
+            .into_iter()
+            .map(|(&loc, _)| Position::from(loc))
+            .collect_vec();
+        for position in mines {
+            *self.tile_mut(position).unwrap() = ServerTile::Mine;
+        }
+
+        for (owner, selection) in std::mem::take(&mut self.selections) {
+            self.reveal(out, owner, selection, now);
+        }
+    }
+
+    fn select(&mut self, out: &mut Outgoing, player: PlayerId, requested: Position) {
+        if self
+            .selections
+            .iter()
+            .filter(|&(&owner, _)| owner != player)
+            .any(|(_, selection)| selection.distance(&requested) < 10.0)
+        {
+            return;
+        }
+
+        if let Some(previous_position) = self.selections.remove(&player) {
+            self.broadcast(
+                out,
+                AreaAttackUpdate::TileChanged {
+                    position: previous_position,
+                    to: ClientTile::Unknown,
+                },
+            );
+        }
+        self.broadcast(
+            out,
+            AreaAttackUpdate::TileChanged {
+                position: requested,
+                to: ClientTile::Owned {
+                    player,
+                    num_neighbors: 0,
+                },
+            },
+        );
+        self.selections.insert(player, requested);
+    }
+
+    fn reveal(&mut self, out: &mut Outgoing, player: PlayerId, position: Position, now: Duration) {
+        let mut changed = Vec::new();
+        let mut request_buffer = VecDeque::from([(position, player)]);
+
+        while let Some((position, player)) = request_buffer.pop_front() {
+            let Some(state) = self.players.get(&player) else {
+                continue;
+            };
+            if state.is_frozen(now, self.settings.freeze_duration)
+                || state.killed
+                || !self.stage.can_reveal()
+            {
+                continue;
+            }
+
+            match self.tile(position) {
+                Some(ServerTile::Empty) => {
+                    *self.tile_mut(position).unwrap() = ServerTile::Owned { player };
+                    changed.push(position);
+                    if self.mine_count(position) == 0 {
+                        request_buffer.extend(
+                            self.field
+                                .iter_neighbor_positions(position)
+                                .map(|position| (position, player)),
+                        )
+                    }
+                }
+                Some(ServerTile::Mine) => match self.stage {
+                    AreaAttack::Stage1 => {
+                        *self.tile_mut(position).unwrap() = ServerTile::HardMine;
+                        changed.push(position);
+                        self.players.get_mut(&player).unwrap().frozen = Some(now);
+                        out.push((player, AreaAttackUpdate::Freeze));
+                    }
+                    AreaAttack::Attack => {
+                        for p in position.radius(5) {
+                            let mine = self.rng.gen_bool(0.2);
+                            if let Some(tile) = self.tile_mut(p) {
+                                if !matches!(*tile, ServerTile::Destroyed) {
+                                    *tile = if mine {
+                                        ServerTile::Mine
+                                    } else {
+                                        ServerTile::Empty
+                                    };
+                                    changed.push(p);
+                                }
+                            }
+                        }
+                        for p in position.disk_neighbors(5) {
+                            if let Some(tile) = self.tile_mut(p) {
+                                // The border is emptied so that the neighbors of the reset disk
+                                // are unflagged on the client, and then revealed again by their
+                                // owners so that their numbers are updated.
+                                if let ServerTile::Owned { player } = *tile {
+                                    *tile = ServerTile::Empty;
+                                    changed.push(p);
+                                    request_buffer.push_back((p, player));
+                                }
+                            }
+                        }
+                        *self.tile_mut(position).unwrap() = ServerTile::Destroyed;
+                        changed.push(position);
+                    }
+                    AreaAttack::Lock => {
+                        *self.tile_mut(position).unwrap() = ServerTile::HardMine;
+                        changed.push(position);
+                        self.players.get_mut(&player).unwrap().killed = true;
+                        out.push((player, AreaAttackUpdate::Killed));
+                    }
+                    _ => (),
+                },
+                // do nothing
+                Some(ServerTile::HardMine | ServerTile::Owned { .. } | ServerTile::Destroyed)
+                | None => (),
+            }
+        }
+
+        self.send_tiles(out, changed);
+    }
+
+    /// Notifies every player and spectator of the new state of the given tiles. Only the owner of a
+    /// tile is told how many mines neighbor it.
+    fn send_tiles(&self, out: &mut Outgoing, changed: Vec<Position>) {
+        for position in changed.into_iter().unique() {
+            for viewer in self.audience() {
+                if let Some(to) = self.client_tile(position, viewer) {
+                    out.push((viewer, AreaAttackUpdate::TileChanged { position, to }));
+                }
+            }
+        }
+    }
+
+    /// How a tile looks to the given player
+    fn client_tile(&self, position: Position, viewer: PlayerId) -> Option<ClientTile> {
+        Some(match self.tile(position)? {
+            ServerTile::Empty | ServerTile::Mine => ClientTile::Unknown,
+            ServerTile::Owned { player } => ClientTile::Owned {
+                player,
+                num_neighbors: if viewer == player {
+                    self.mine_count(position)
+                } else {
+                    0
+                },
+            },
+            ServerTile::HardMine => ClientTile::Mine,
+            ServerTile::Destroyed => ClientTile::Destroyed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use itertools::Itertools;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{
+        area_attack::{
+            components::{ClientTile, ServerTile, ATTACK_BEGINS, FREEZE_DURATION, LOCK_BEGINS},
+            protocol::{AreaAttackRequest, AreaAttackUpdate},
+            settings::AreaAttackSettings,
+            states::AreaAttack,
+        },
+        common::Position,
+        minefield::FieldShape,
+        server::{Access, PlayerId},
+    };
+
+    use super::{AreaAttackGame, JoinError, RequestError};
+
+    const HOST: PlayerId = PlayerId(0);
+    const GUEST: PlayerId = PlayerId(1);
+
+    fn square_game(size: usize) -> AreaAttackGame {
+        let rows = vec!["x".repeat(size); size].join("\n");
+        let shape = FieldShape::try_from(rows.as_bytes()).unwrap();
+        let mut game = AreaAttackGame::new(
+            AreaAttackSettings::default(),
+            shape,
+            StdRng::seed_from_u64(0),
+        );
+        game.join(HOST, "host".to_string(), Duration::ZERO).unwrap();
+        game.join(GUEST, "guest".to_string(), Duration::ZERO)
+            .unwrap();
+        game
+    }
+
+    /// Starts the game and then clears every mine so that tests can place their own
+    fn started_game(size: usize) -> AreaAttackGame {
+        let mut game = square_game(size);
+        game.handle(HOST, AreaAttackRequest::StartGame, Duration::ZERO);
+        for (_, tile) in game.field.occupied_entries_mut() {
+            *tile = Some(ServerTile::Empty);
+        }
+        game
+    }
+
+    fn set(game: &mut AreaAttackGame, position: Position, tile: ServerTile) {
+        *game.tile_mut(position).unwrap() = tile;
+    }
+
+    fn received(out: &[(PlayerId, AreaAttackUpdate)], player: PlayerId) -> Vec<&AreaAttackUpdate> {
+        out.iter()
+            .filter_map(|(to, update)| (*to == player).then_some(update))
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_places_same_mines() {
+        let mines = || {
+            let mut game = square_game(30);
+            game.handle(
+                HOST,
+                AreaAttackRequest::Reveal(Position::new(5, 5)),
+                Duration::ZERO,
+            );
+            game.handle(HOST, AreaAttackRequest::StartGame, Duration::ZERO);
+            game.field
+                .iter_positions()
+                .filter(|&position| matches!(game.tile(position), Some(ServerTile::Mine)))
+                .sorted()
+                .collect_vec()
+        };
+        assert_eq!(mines(), mines());
+    }
+
+    #[test]
+    fn fifth_player_is_rejected() {
+        let mut game = square_game(10);
+        game.join(PlayerId(2), "3".to_string(), Duration::ZERO)
+            .unwrap();
+        game.join(PlayerId(3), "4".to_string(), Duration::ZERO)
+            .unwrap();
+        assert!(game.is_full());
+        assert!(matches!(
+            game.join(PlayerId(4), "5".to_string(), Duration::ZERO),
+            Err(JoinError::Full)
+        ));
+    }
+
+    #[test]
+    fn usernames_are_unique() {
+        let mut game = square_game(10);
+        assert!(matches!(
+            game.join(PlayerId(2), "guest".to_string(), Duration::ZERO),
+            Err(JoinError::NameTaken)
+        ));
+        game.leave(GUEST);
+        assert!(game
+            .join(PlayerId(2), "guest".to_string(), Duration::ZERO)
+            .is_ok());
+    }
+
+    #[test]
+    fn players_cannot_join_once_started() {
+        let mut game = square_game(10);
+        game.handle(HOST, AreaAttackRequest::StartGame, Duration::ZERO);
+        assert!(matches!(
+            game.join(PlayerId(2), "late".to_string(), Duration::ZERO),
+            Err(JoinError::Started)
+        ));
+        assert_eq!(game.status().players, 2);
+    }
+
+    #[test]
+    fn status_follows_game() {
+        let mut game = square_game(10);
+        let status = game.status();
+        assert_eq!(
+            (status.access, status.players, status.max_players),
+            (Access::Open, 2, 4)
+        );
+
+        game.join(PlayerId(2), "3".to_string(), Duration::ZERO)
+            .unwrap();
+        game.join(PlayerId(3), "4".to_string(), Duration::ZERO)
+            .unwrap();
+        assert_eq!(game.status().access, Access::Full);
+
+        let status = started_game(10).status();
+        assert_eq!(status.access, Access::Ingame);
+        assert_eq!(status.stage, AreaAttack::Stage1.name());
+    }
+
+    #[test]
+    fn standings_count_claimed_tiles() {
+        let mut game = started_game(10);
+        assert!(game.in_progress());
+        set(
+            &mut game,
+            Position::new(0, 0),
+            ServerTile::Owned { player: GUEST },
+        );
+        set(
+            &mut game,
+            Position::new(1, 0),
+            ServerTile::Owned { player: GUEST },
+        );
+        set(
+            &mut game,
+            Position::new(2, 0),
+            ServerTile::Owned { player: HOST },
+        );
+        let standings = game.standings();
+        assert_eq!(standings[0], ("guest".to_string(), 2));
+        assert_eq!(standings[1].0, "host");
+    }
+
+    #[test]
+    fn requests_off_the_field_are_refused() {
+        let mut game = square_game(10);
+        let outside = Position::new(10, -3);
+        assert_eq!(
+            game.validate(HOST, &AreaAttackRequest::Position(outside)),
+            Err(RequestError::OffField(outside))
+        );
+        assert!(game
+            .handle(HOST, AreaAttackRequest::Position(outside), Duration::ZERO)
+            .is_empty());
+        assert!(game
+            .handle(HOST, AreaAttackRequest::Reveal(outside), Duration::ZERO)
+            .is_empty());
+        assert_eq!(
+            game.validate(PlayerId(7), &AreaAttackRequest::Snapshot),
+            Err(RequestError::Stranger)
+        );
+
+        let mut game = started_game(10);
+        assert!(game
+            .handle(GUEST, AreaAttackRequest::Reveal(outside), Duration::ZERO)
+            .is_empty());
+        assert!(game
+            .players
+            .values()
+            .all(|player| player.position != outside));
+    }
+
+    #[test]
+    fn selections_keep_their_distance() {
+        let mut game = square_game(30);
+        game.handle(
+            HOST,
+            AreaAttackRequest::Reveal(Position::new(5, 5)),
+            Duration::ZERO,
+        );
+
+        let too_close = game.handle(
+            GUEST,
+            AreaAttackRequest::Reveal(Position::new(8, 8)),
+            Duration::ZERO,
+        );
+        assert!(too_close.is_empty());
+
+        let far_enough = game.handle(
+            GUEST,
+            AreaAttackRequest::Reveal(Position::new(20, 20)),
+            Duration::ZERO,
+        );
+        assert_eq!(far_enough.len(), 2);
+        assert_eq!(game.selections[&GUEST], Position::new(20, 20));
+    }
+
+    #[test]
+    fn host_passes_on_when_leaving() {
+        let mut game = square_game(10);
+        game.join(PlayerId(2), "third".to_string(), Duration::ZERO)
+            .unwrap();
+        let out = game.leave(HOST);
+        assert!(game.is_host(GUEST));
+        assert!(matches!(
+            received(&out, GUEST)[..],
+            [AreaAttackUpdate::PlayerLeft { .. }, AreaAttackUpdate::Host]
+        ));
+        assert!(!received(&out, PlayerId(2))
+            .iter()
+            .any(|update| matches!(update, AreaAttackUpdate::Host)));
+
+        // the new host is the one who may start the game
+        game.handle(GUEST, AreaAttackRequest::StartGame, Duration::ZERO);
+        assert_eq!(game.stage(), AreaAttack::Stage1);
+    }
+
+    #[test]
+    fn only_host_starts_game() {
+        let mut game = square_game(30);
+        game.handle(
+            HOST,
+            AreaAttackRequest::Reveal(Position::new(5, 5)),
+            Duration::ZERO,
+        );
+
+        let out = game.handle(GUEST, AreaAttackRequest::StartGame, Duration::ZERO);
+        assert!(matches!(
+            received(&out, GUEST)[..],
+            [AreaAttackUpdate::NotHost]
+        ));
+        assert_eq!(game.stage(), AreaAttack::Selecting);
+
+        let out = game.handle(HOST, AreaAttackRequest::StartGame, Duration::ZERO);
+        assert_eq!(game.stage(), AreaAttack::Stage1);
+        assert!(matches!(
+            received(&out, GUEST)[0],
+            AreaAttackUpdate::Transition(AreaAttack::Stage1)
+        ));
+
+        // the host's selection is safe to reveal
+        for position in Position::new(5, 5).local_group() {
+            assert!(!matches!(game.tile(position), Some(ServerTile::Mine)));
+        }
+        assert!(matches!(
+            game.tile(Position::new(5, 5)),
+            Some(ServerTile::Owned { player: HOST })
+        ));
+
+        let mines = game
+            .field
+            .occupied_entries()
+            .filter(|(_, tile)| matches!(tile, Some(ServerTile::Mine)))
+            .count();
+        assert_eq!(mines, game.field.mines);
+    }
+
+    #[test]
+    fn stages_follow_clock() {
+        let mut game = started_game(10);
+        let start = Duration::from_secs(1);
+        game.started = Some(start);
+
+        assert!(game.tick(start + ATTACK_BEGINS / 2).is_empty());
+        game.tick(start + ATTACK_BEGINS);
+        assert_eq!(game.stage(), AreaAttack::Attack);
+        game.tick(start + LOCK_BEGINS);
+        assert_eq!(game.stage(), AreaAttack::Lock);
+        game.tick(start + LOCK_BEGINS * 2);
+        assert_eq!(game.stage(), AreaAttack::Finishing);
+    }
+
+    #[test]
+    fn stage1_mine_freezes() {
+        let mut game = started_game(10);
+        set(&mut game, Position::new(0, 0), ServerTile::Mine);
+        set(&mut game, Position::new(9, 9), ServerTile::Mine);
+
+        let out = game.handle(
+            HOST,
+            AreaAttackRequest::Reveal(Position::new(0, 0)),
+            Duration::ZERO,
+        );
+        assert!(received(&out, HOST)
+            .iter()
+            .any(|update| matches!(update, AreaAttackUpdate::Freeze)));
+        assert_eq!(game.tile(Position::new(0, 0)), Some(ServerTile::HardMine));
+
+        // frozen players cannot reveal
+        let frozen = Duration::from_secs(1);
+        game.handle(HOST, AreaAttackRequest::Reveal(Position::new(9, 8)), frozen);
+        assert_eq!(game.tile(Position::new(9, 8)), Some(ServerTile::Empty));
+
+        game.handle(
+            HOST,
+            AreaAttackRequest::Reveal(Position::new(9, 8)),
+            FREEZE_DURATION,
+        );
+        assert_eq!(
+            game.tile(Position::new(9, 8)),
+            Some(ServerTile::Owned { player: HOST })
+        );
+    }
+
+    #[test]
+    fn attack_resets_disk() {
+        let mut game = started_game(30);
+        game.tick(ATTACK_BEGINS);
+        assert_eq!(game.stage(), AreaAttack::Attack);
+
+        let center = Position::new(15, 15);
+        set(&mut game, center, ServerTile::Mine);
+        let inner = Position::new(17, 15);
+        set(&mut game, inner, ServerTile::Owned { player: GUEST });
+        let border = center.disk_neighbors(5).next().unwrap();
+        set(&mut game, border, ServerTile::Owned { player: GUEST });
+
+        let out = game.handle(HOST, AreaAttackRequest::Reveal(center), Duration::ZERO);
+
+        assert_eq!(game.tile(center), Some(ServerTile::Destroyed));
+        assert!(matches!(
+            game.tile(inner),
+            Some(ServerTile::Empty | ServerTile::Mine)
+        ));
+        // the border tile is returned to its owner after the reset
+        assert!(matches!(
+            game.tile(border),
+            Some(ServerTile::Owned { player: GUEST })
+        ));
+        assert!(received(&out, GUEST).iter().any(|update| matches!(
+            update,
+            AreaAttackUpdate::TileChanged {
+                position,
+                to: ClientTile::Destroyed
+            } if *position == center
+        )));
+    }
+
+    #[test]
+    fn snapshot_restores_view() {
+        let mut game = started_game(10);
+        set(&mut game, Position::new(9, 9), ServerTile::Mine);
+        game.handle(
+            GUEST,
+            AreaAttackRequest::Reveal(Position::new(8, 8)),
+            Duration::ZERO,
+        );
+        game.handle(
+            HOST,
+            AreaAttackRequest::Reveal(Position::new(0, 0)),
+            Duration::ZERO,
+        );
+
+        let now = Duration::from_secs(60);
+        let snapshot = game.snapshot(GUEST, now);
+        let updates = received(&snapshot, GUEST);
+        assert_eq!(updates.len(), snapshot.len());
+        assert!(matches!(updates[0], AreaAttackUpdate::FieldShape(_)));
+        assert!(updates.iter().any(|update| matches!(
+            update,
+            AreaAttackUpdate::PlayerProperties { id, .. } if *id == HOST
+        )));
+        let Some(AreaAttackUpdate::Snapshot {
+            tiles,
+            stage,
+            remaining,
+        }) = updates.last()
+        else {
+            panic!("the snapshot should end with the tiles of the field");
+        };
+        assert_eq!(*stage, AreaAttack::Stage1);
+        assert_eq!(*remaining, Some(ATTACK_BEGINS - now));
+
+        // the guest sees the number on its own tile, and every other tile as its owner's
+        let tiles: Vec<_> = game
+            .field
+            .iter_positions()
+            .sorted()
+            .zip(tiles.decode())
+            .collect();
+        assert_eq!(tiles.len(), 10 * 10);
+        assert!(tiles.contains(&(
+            Position::new(8, 8),
+            ClientTile::Owned {
+                player: GUEST,
+                num_neighbors: 1
+            }
+        )));
+        assert!(tiles.contains(&(Position::new(9, 9), ClientTile::Unknown)));
+        let owned_by_host = tiles
+            .iter()
+            .filter(|(_, tile)| {
+                *tile
+                    == ClientTile::Owned {
+                        player: HOST,
+                        num_neighbors: 0,
+                    }
+            })
+            .count();
+        assert_eq!(owned_by_host, 10 * 10 - 2);
+    }
+
+    #[test]
+    fn spectators_watch_without_playing() {
+        let mut game = started_game(10);
+        let spectator = PlayerId(7);
+        let snapshot = game.watch(spectator, Duration::ZERO);
+        let updates = received(&snapshot, spectator);
+        assert!(!updates
+            .iter()
+            .any(|update| matches!(update, AreaAttackUpdate::SelfChange { .. })));
+        assert_eq!(
+            updates
+                .iter()
+                .filter(|update| matches!(update, AreaAttackUpdate::PlayerProperties { .. }))
+                .count(),
+            2
+        );
+
+        // the spectator follows the players' cursors and tiles, but cannot see their numbers
+        let out = game.handle(
+            HOST,
+            AreaAttackRequest::Position(Position::new(3, 3)),
+            Duration::ZERO,
+        );
+        assert!(matches!(
+            received(&out, spectator)[..],
+            [AreaAttackUpdate::Reposition { id: HOST, .. }]
+        ));
+        set(&mut game, Position::new(9, 9), ServerTile::Mine);
+        let out = game.handle(
+            HOST,
+            AreaAttackRequest::Reveal(Position::new(8, 8)),
+            Duration::ZERO,
+        );
+        assert!(matches!(
+            received(&out, spectator)[..],
+            [AreaAttackUpdate::TileChanged {
+                to: ClientTile::Owned {
+                    player: HOST,
+                    num_neighbors: 0
+                },
+                ..
+            }]
+        ));
+
+        // anything but asking for the field is refused
+        let out = game.handle(
+            spectator,
+            AreaAttackRequest::Reveal(Position::new(0, 0)),
+            Duration::ZERO,
+        );
+        assert!(out.is_empty());
+        assert_eq!(game.tile(Position::new(0, 0)), Some(ServerTile::Empty));
+        let out = game.handle(spectator, AreaAttackRequest::Snapshot, Duration::ZERO);
+        assert!(matches!(
+            received(&out, spectator)[..],
+            [AreaAttackUpdate::Snapshot { .. }]
+        ));
+    }
+
+    #[test]
+    fn lock_mine_kills() {
+        let mut game = started_game(10);
+        game.tick(LOCK_BEGINS);
+        assert_eq!(game.stage(), AreaAttack::Lock);
+        set(&mut game, Position::new(0, 0), ServerTile::Mine);
+        set(&mut game, Position::new(9, 9), ServerTile::Mine);
+
+        let now = LOCK_BEGINS;
+        let out = game.handle(GUEST, AreaAttackRequest::Reveal(Position::new(0, 0)), now);
+        assert!(received(&out, GUEST)
+            .iter()
+            .any(|update| matches!(update, AreaAttackUpdate::Killed)));
+        assert!(received(&out, HOST)
+            .iter()
+            .all(|update| !matches!(update, AreaAttackUpdate::Killed)));
+        assert_eq!(game.tile(Position::new(0, 0)), Some(ServerTile::HardMine));
+
+        // the dead stay dead, even once a freeze would have worn off
+        let later = now + FREEZE_DURATION * 2;
+        game.handle(GUEST, AreaAttackRequest::Reveal(Position::new(9, 8)), later);
+        assert_eq!(game.tile(Position::new(9, 8)), Some(ServerTile::Empty));
+    }
+}
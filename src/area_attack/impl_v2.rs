@@ -1,126 +1,418 @@
-use std::collections::{HashMap, HashSet};
+use std::{collections::HashMap, time::Duration};
 
+use anyhow::anyhow;
 use futures_util::{stream::FuturesUnordered, StreamExt};
-use rand::seq::SliceRandom;
-use tokio::sync::mpsc::{error::SendError, UnboundedSender};
+use itertools::Itertools;
+use rand::seq::IteratorRandom;
+use tokio::{
+    sync::{
+        mpsc::{error::TrySendError, Sender},
+        watch,
+    },
+    time::Instant,
+};
 
 use crate::{
-    minefield::Minefield,
-    server::Greeting,
+    server::{GameSettings, Greeting, PlayerId, SessionToken},
     server_v2::{
-        app::player_connector_pair,
-        double_channel::DoubleChannel,
-        game::{GamemodeInitializer, SessionObjects},
+        app::{player_connector_pair, Arrival, ArrivalKind, Refusal},
+        config::overlay,
+        double_channel::{DoubleChannel, CHANNEL_CAPACITY},
+        game::{GameLimits, GameResult, GamemodeInitializer, SessionObjects, LEAVE_NOTICE},
+        shutdown::Shutdown,
         FIELDS,
     },
 };
 
-use super::{components::{ServerTile, PlayerColor}, protocol::AreaAttackRequest};
+use super::{
+    game::{AreaAttackGame, JoinError, Outgoing},
+    protocol::{AreaAttackUpdate, RECONNECT_GRACE},
+    settings::AreaAttackSettings,
+    states::AreaAttack,
+};
+
+/// How often the game checks whether it should move on to the next stage
+const TICK_RATE: Duration = Duration::from_millis(100);
 
 #[derive(Default)]
 struct PlayerSet {
-    map: HashMap<Greeting, SendOnlyPlayer>,
-    taken_colors: HashSet<PlayerColor>,
+    map: HashMap<PlayerId, SendOnlyPlayer>,
+    /// Players who have lost their connection, by the token they may come back with
+    away: HashMap<SessionToken, AwayPlayer>,
+}
+
+struct AwayPlayer {
+    id: PlayerId,
+    /// The time at which the connection was lost
+    since: Duration,
 }
 
 impl PlayerSet {
-    fn register(&mut self, info: Greeting, chan: DoubleChannel<Vec<u8>>) -> Player {
-        // assign a color to the player
-        // notify other players of this player
-        // register player into map
-        // return double-channel player
-        todo!()
+    fn register(
+        &mut self,
+        arrival: Arrival,
+        chan: DoubleChannel<Vec<u8>>,
+        game: &mut AreaAttackGame,
+        now: Duration,
+    ) -> Option<Player> {
+        let id = arrival.id;
+        match game.join(id, arrival.greeting.username.clone(), now) {
+            Ok(out) => {
+                let player = Player {
+                    id,
+                    token: arrival.token,
+                    info: arrival.greeting,
+                    connector: chan,
+                    spectating: false,
+                };
+                self.map.insert(id, player.sender());
+                self.deliver(vec![(id, AreaAttackUpdate::Session(arrival.token))]);
+                self.deliver(out);
+                Some(player)
+            }
+            // arrivals are checked before they are let in, so this is only ever the host, whose
+            // channel is closed to end the game
+            Err(e) => {
+                log::warn!("{} could not join the game: {e}", arrival.greeting.username);
+                None
+            }
+        }
+    }
+
+    /// Gives a player who lost its connection its place back, and shows it the game as it is now
+    fn resume(
+        &mut self,
+        arrival: Arrival,
+        chan: DoubleChannel<Vec<u8>>,
+        game: &AreaAttackGame,
+        now: Duration,
+    ) -> Option<Player> {
+        let AwayPlayer { id, .. } = self.away.remove(&arrival.token)?;
+        log::debug!("{} returned to the game", arrival.greeting.username);
+        let player = Player {
+            id,
+            token: arrival.token,
+            info: arrival.greeting,
+            connector: chan,
+            spectating: false,
+        };
+        self.map.insert(id, player.sender());
+        self.deliver(game.snapshot(id, now));
+        Some(player)
+    }
+
+    fn watch(
+        &mut self,
+        arrival: Arrival,
+        chan: DoubleChannel<Vec<u8>>,
+        game: &mut AreaAttackGame,
+        now: Duration,
+    ) -> Option<Player> {
+        let id = arrival.id;
+        log::debug!("{} is watching the game", arrival.greeting.username);
+        let player = Player {
+            id,
+            token: arrival.token,
+            info: arrival.greeting,
+            connector: chan,
+            spectating: true,
+        };
+        self.map.insert(id, player.sender());
+        self.deliver(game.watch(id, now));
+        Some(player)
+    }
+
+    /// Lets go of a player who has left the game for good
+    fn remove(&mut self, player: &Player, game: &mut AreaAttackGame) {
+        self.map.remove(&player.id);
+        self.deliver(game.leave(player.id));
+    }
+
+    /// Keeps the place of a player who lost its connection, until [PlayerSet::expire] gives it up.
+    /// Spectators have no place to keep, and are let go straight away.
+    fn disconnect(&mut self, player: &Player, game: &mut AreaAttackGame, now: Duration) {
+        if player.spectating {
+            self.remove(player, game);
+            return;
+        }
+        self.map.remove(&player.id);
+        self.away.insert(
+            player.token,
+            AwayPlayer {
+                id: player.id,
+                since: now,
+            },
+        );
+    }
+
+    /// Forgets players who have been away for longer than the grace period, returning their ids
+    fn expire(&mut self, now: Duration) -> Vec<PlayerId> {
+        let mut expired = Vec::new();
+        self.away.retain(|_, away| {
+            let keep = now.saturating_sub(away.since) < RECONNECT_GRACE;
+            if !keep {
+                expired.push(away.id);
+            }
+            keep
+        });
+        expired
+    }
+
+    /// Whether every player has left, leaving at most spectators to watch an empty game
+    fn is_empty(&self) -> bool {
+        self.map.values().all(|player| player.spectating) && self.away.is_empty()
+    }
+
+    /// Shows players who fell behind the game as it is now, once they have read everything that
+    /// was waiting for them
+    fn catch_up(&mut self, game: &AreaAttackGame, now: Duration) {
+        let caught_up = self
+            .map
+            .iter_mut()
+            .filter(|(_, player)| player.behind && player.connector.capacity() == CHANNEL_CAPACITY)
+            .map(|(&id, player)| {
+                player.behind = false;
+                id
+            })
+            .collect_vec();
+        for id in caught_up {
+            log::debug!("A player caught up with the game");
+            self.deliver(game.snapshot(id, now));
+        }
+    }
+
+    fn deliver(&mut self, out: Outgoing) {
+        for (id, update) in out {
+            if let Some(player) = self.map.get_mut(&id) {
+                player.send(&update);
+            }
+        }
     }
 }
 
 struct Player {
-    is_host: bool,
+    id: PlayerId,
+    token: SessionToken,
     info: Greeting,
-    color: PlayerColor,
     connector: DoubleChannel<Vec<u8>>,
+    spectating: bool,
 }
 
 impl Player {
-    fn send(&mut self, message: Vec<u8>) -> Result<(), SendError<Vec<u8>>> {
-        self.connector.send(message)
-    }
-
     async fn recv_owned(mut self) -> (Option<Vec<u8>>, Self) {
         (self.connector.recv().await, self)
     }
 
     fn sender(&self) -> SendOnlyPlayer {
         SendOnlyPlayer {
-            is_host: self.is_host,
             info: self.info.clone(),
-            color: self.color,
             connector: self.connector.sender(),
+            spectating: self.spectating,
+            behind: false,
         }
     }
 }
 
 struct SendOnlyPlayer {
-    is_host: bool,
     info: Greeting,
-    color: PlayerColor,
-    connector: UnboundedSender<Vec<u8>>,
+    connector: Sender<Vec<u8>>,
+    spectating: bool,
+    /// Whether updates were dropped because the player did not read them quickly enough. The game
+    /// never waits on a single player, so it is sent nothing more until it catches up.
+    behind: bool,
+}
+
+impl SendOnlyPlayer {
+    fn send(&mut self, update: &AreaAttackUpdate) {
+        if self.behind {
+            return;
+        }
+        match self.connector.try_send(rmp_serde::to_vec(update).unwrap()) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                log::debug!("Player {} fell behind the game", self.info.username);
+                self.behind = true;
+            }
+            Err(TrySendError::Closed(_)) => log::debug!(
+                "Player {} is no longer listening to the game",
+                self.info.username
+            ),
+        }
+    }
+}
+
+impl From<JoinError> for Refusal {
+    fn from(error: JoinError) -> Self {
+        match error {
+            JoinError::Full => Refusal::NoRoom,
+            JoinError::NameTaken => Refusal::NameTaken,
+            JoinError::Started => Refusal::Started,
+        }
+    }
 }
 
 pub struct IAreaAttack;
 
 impl GamemodeInitializer for IAreaAttack {
-    fn create(&self, _: Vec<u8>, info: Greeting) -> SessionObjects {
-        // area attack has no params for now
+    fn create(
+        &self,
+        params: Vec<u8>,
+        host: Arrival,
+        limits: GameLimits,
+        mut shutdown: Shutdown,
+    ) -> anyhow::Result<SessionObjects> {
+        let mut settings = AreaAttackSettings::decode(&params)?;
+        settings.validate(|name| FIELDS.contains_key(name))?;
+        settings.max_players = settings.max_players.min(limits.max_players);
+        let mut rng = settings.rng();
+        let field_shape = match &settings.field {
+            Some(name) => FIELDS[name].clone(),
+            None => FIELDS
+                .iter()
+                // sorted so that the seed always picks the same field
+                .sorted_by_key(|&(name, _)| name)
+                .choose(&mut rng)
+                .ok_or_else(|| anyhow!("The server has no fields to play on"))?
+                .1
+                .clone(),
+        };
+        settings.validate_field(&field_shape)?;
 
         let (host_channel, host_listener) = DoubleChannel::<Vec<u8>>::double();
         let (connector_front, mut player_receiver) = player_connector_pair();
-        let field_shape = FIELDS.choose(&mut rand::thread_rng()).unwrap().clone();
-        let field = Minefield::new_shaped(|_| ServerTile::Empty, &field_shape);
-
-        let mut player_set = PlayerSet::default();
+        let mut game = AreaAttackGame::new(settings, field_shape, rng);
+        let (status_sender, status) = watch::channel(game.status());
 
         let main_task = tokio::spawn(async move {
-            let host = player_set.register(info, host_listener);
+            let epoch = Instant::now();
+            let mut player_set = PlayerSet::default();
             let mut task_queue = FuturesUnordered::new();
-            task_queue.push(host.recv_owned());
+            task_queue.extend(
+                player_set
+                    .register(host, host_listener, &mut game, epoch.elapsed())
+                    .map(Player::recv_owned),
+            );
+
+            let mut ticker = tokio::time::interval(TICK_RATE);
+            // once the server is shutting down, the time by which the game must have ended
+            let mut stop_at = None;
+            let mut interrupted = false;
 
             loop {
                 tokio::select! {
-                    Some(player) = player_receiver.recv() => {
-                        let player_listener = player_receiver.respond().await.unwrap();
-                        player_set.register(player, player_listener);
+                    deadline = shutdown.begun(), if stop_at.is_none() => {
+                        stop_at = Some(deadline);
                     }
-                    Some((player_msg, mut player)) = task_queue.next() => {
-                        if let Some(msg) = player_msg {
-                            if let Ok(message) = rmp_serde::from_slice(&msg) {
-                                handle_message(message, &mut player_set, &mut player);
+                    Some(arrival) = player_receiver.recv() => {
+                        let refusal = match arrival.kind {
+                            // a player is only ever let in once, even if it asks again before
+                            // its notice of leaving has arrived
+                            _ if player_set.map.contains_key(&arrival.id) => {
+                                Some(Refusal::NoRoom)
+                            }
+                            // checked before answering, since the player cannot be turned away
+                            // once it has been let in
+                            ArrivalKind::Joining => game
+                                .check_join(&arrival.greeting.username)
+                                .err()
+                                .map(Refusal::from),
+                            ArrivalKind::Returning
+                                if !player_set.away.contains_key(&arrival.token) =>
+                            {
+                                Some(Refusal::NoRoom)
+                            }
+                            _ => None,
+                        };
+                        if let Some(refusal) = refusal {
+                            player_receiver.refuse(refusal).await;
+                            continue;
+                        }
+                        // the player may have given up on entering before it was answered
+                        let Some(player_listener) = player_receiver.respond().await else {
+                            continue;
+                        };
+                        let now = epoch.elapsed();
+                        let player = match arrival.kind {
+                            ArrivalKind::Joining => {
+                                player_set.register(arrival, player_listener, &mut game, now)
+                            }
+                            ArrivalKind::Returning => {
+                                player_set.resume(arrival, player_listener, &game, now)
+                            }
+                            ArrivalKind::Spectating => {
+                                player_set.watch(arrival, player_listener, &mut game, now)
+                            }
+                        };
+                        task_queue.extend(player.map(Player::recv_owned));
+                    }
+                    Some((player_msg, player)) = task_queue.next() => match player_msg {
+                        Some(msg) if msg == LEAVE_NOTICE => {
+                            log::debug!("{} left the game", player.info.username);
+                            player_set.remove(&player, &mut game);
+                        }
+                        Some(msg) => {
+                            match rmp_serde::from_slice(&msg) {
+                                Ok(request) => player_set
+                                    .deliver(game.handle(player.id, request, epoch.elapsed())),
+                                Err(e) => log::debug!(
+                                    "Could not read a request from {}: {e}",
+                                    player.info.username
+                                ),
                             }
                             task_queue.push(player.recv_owned());
                         }
+                        None => {
+                            log::debug!("{} lost its connection to the game", player.info.username);
+                            player_set.disconnect(&player, &mut game, epoch.elapsed());
+                        }
+                    },
+                    _ = ticker.tick() => {
+                        let now = epoch.elapsed();
+                        for id in player_set.expire(now) {
+                            log::debug!("A player did not return in time and has left the game");
+                            player_set.deliver(game.leave(id));
+                        }
+                        if player_set.is_empty() {
+                            break;
+                        }
+                        // a game which has not begun or is already over has nothing to finish
+                        if stop_at.is_some_and(|deadline| {
+                            !game.in_progress() || Instant::now() >= deadline
+                        }) {
+                            interrupted = game.in_progress();
+                            break;
+                        }
+                        player_set.deliver(game.tick(now));
+                        player_set.catch_up(&game, now);
                     }
                 }
+                status_sender.send_if_modified(|status| {
+                    let current = game.status();
+                    let changed = *status != current;
+                    *status = current;
+                    changed
+                });
             }
+            // a game which never began has no result
+            (game.stage() != AreaAttack::Selecting).then(|| GameResult {
+                stage: game.stage().name().to_string(),
+                interrupted,
+                standings: game.standings(),
+            })
         });
 
-        SessionObjects {
+        Ok(SessionObjects {
             host_channel,
             connector: connector_front,
             main_task,
-        }
+            status,
+        })
     }
-}
 
-fn handle_message(
-    msg: AreaAttackRequest,
-    senders: &mut PlayerSet,
-    player: &mut Player,
-) {
-    use AreaAttackRequest::*;
-    match msg {
-        StartGame => (),
-        Reveal(_) => (),
-        Position(_) => (),
-        Color(_) => (),
+    fn default_args(&self, settings: toml::Value) -> anyhow::Result<Vec<u8>> {
+        let settings = overlay(&AreaAttackSettings::default(), settings)?;
+        settings.validate(|name| FIELDS.contains_key(name))?;
+        Ok(settings.encode())
     }
 }
 
@@ -129,3 +421,185 @@ impl IAreaAttack {
         Box::new(Self)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        area_attack::{
+            protocol::{AreaAttackRequest, AreaAttackUpdate},
+            states::AreaAttack,
+            AREA_ATTACK_MARKER,
+        },
+        server::{ClientMessage, ErrorKind, ServerMessage},
+        server_v2::{
+            config::ServerConfig,
+            testing::{TestClient, TestServer},
+        },
+    };
+
+    /// Creates a game with the default settings, returning its id
+    async fn create_game(host: &mut TestClient) -> u64 {
+        host.send(ClientMessage::Create {
+            game: AREA_ATTACK_MARKER,
+            args: Vec::new(),
+        })
+        .await;
+        host.wait_for(|message| match message {
+            ServerMessage::Joined { game, .. } => Some(game),
+            _ => None,
+        })
+        .await
+    }
+
+    fn update(message: ServerMessage) -> Option<AreaAttackUpdate> {
+        match message {
+            ServerMessage::Ingame { data } => Some(rmp_serde::from_slice(&data).unwrap()),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn departures_are_broadcast() {
+        let server = TestServer::start(ServerConfig::default()).await;
+        let mut host = TestClient::connect(server.address, "host").await;
+        let game = create_game(&mut host).await;
+
+        let mut guest = TestClient::connect(server.address, "guest").await;
+        guest.send(ClientMessage::Join { game }).await;
+        let guest_id = host
+            .wait_for(|message| match update(message) {
+                Some(AreaAttackUpdate::PlayerProperties { id, username, .. })
+                    if username == "guest" =>
+                {
+                    Some(id)
+                }
+                _ => None,
+            })
+            .await;
+
+        guest.close().await;
+        let left = host
+            .wait_for(|message| match update(message) {
+                Some(AreaAttackUpdate::PlayerLeft { id }) => Some(id),
+                _ => None,
+            })
+            .await;
+        assert_eq!(left, guest_id);
+    }
+
+    #[tokio::test]
+    async fn empty_messages_do_not_leave_the_game() {
+        let server = TestServer::start(ServerConfig::default()).await;
+        let mut host = TestClient::connect(server.address, "host").await;
+        let game = create_game(&mut host).await;
+
+        let mut guest = TestClient::connect(server.address, "guest").await;
+        guest.send(ClientMessage::Join { game }).await;
+        guest
+            .wait_for(|message| match update(message) {
+                Some(AreaAttackUpdate::Session(_)) => Some(()),
+                _ => None,
+            })
+            .await;
+        guest.send(ClientMessage::Ingame { data: Vec::new() }).await;
+        let kind = guest
+            .wait_for(|message| match message {
+                ServerMessage::Error { kind, .. } => Some(kind),
+                _ => None,
+            })
+            .await;
+        assert_eq!(kind, ErrorKind::Unreadable);
+
+        // the guest is still in the game, and its peers have not heard otherwise
+        let snapshot = rmp_serde::to_vec(&AreaAttackRequest::Snapshot).unwrap();
+        guest
+            .send(ClientMessage::Ingame {
+                data: snapshot.clone(),
+            })
+            .await;
+        guest
+            .wait_for(|message| match update(message) {
+                Some(AreaAttackUpdate::Snapshot { .. }) => Some(()),
+                _ => None,
+            })
+            .await;
+        host.send(ClientMessage::Ingame { data: snapshot }).await;
+        let left = host
+            .wait_for(|message| match update(message) {
+                Some(AreaAttackUpdate::PlayerLeft { .. }) => Some(true),
+                Some(AreaAttackUpdate::Snapshot { .. }) => Some(false),
+                _ => None,
+            })
+            .await;
+        assert!(!left);
+    }
+
+    #[tokio::test]
+    async fn games_cannot_be_joined_once_begun() {
+        let server = TestServer::start(ServerConfig::default()).await;
+        let mut host = TestClient::connect(server.address, "host").await;
+        let game = create_game(&mut host).await;
+        host.send(ClientMessage::Ingame {
+            data: rmp_serde::to_vec(&AreaAttackRequest::StartGame).unwrap(),
+        })
+        .await;
+        host.wait_for(|message| match update(message) {
+            Some(AreaAttackUpdate::Transition(AreaAttack::Stage1)) => Some(()),
+            _ => None,
+        })
+        .await;
+
+        let mut late = TestClient::connect(server.address, "late").await;
+        late.send(ClientMessage::Join { game }).await;
+        let kind = late
+            .wait_for(|message| match message {
+                ServerMessage::JoinRejected { kind, .. } => Some(kind),
+                _ => None,
+            })
+            .await;
+        assert_eq!(kind, ErrorKind::GameStarted);
+    }
+
+    #[tokio::test]
+    async fn usernames_are_unique_within_a_game() {
+        let server = TestServer::start(ServerConfig::default()).await;
+        let mut host = TestClient::connect(server.address, "twin").await;
+        let game = create_game(&mut host).await;
+
+        let mut twin = TestClient::connect(server.address, "twin").await;
+        twin.send(ClientMessage::Join { game }).await;
+        let kind = twin
+            .wait_for(|message| match message {
+                ServerMessage::JoinRejected { kind, .. } => Some(kind),
+                _ => None,
+            })
+            .await;
+        assert_eq!(kind, ErrorKind::NameTaken);
+
+        // the name is only taken within the game
+        create_game(&mut twin).await;
+        let mut guest = TestClient::connect(server.address, "guest").await;
+        guest.send(ClientMessage::Join { game }).await;
+        let guest_id = host
+            .wait_for(|message| match update(message) {
+                Some(AreaAttackUpdate::PlayerProperties { id, username, .. })
+                    if username == "guest" =>
+                {
+                    Some(id)
+                }
+                _ => None,
+            })
+            .await;
+        let host_id = guest
+            .wait_for(|message| match update(message) {
+                Some(AreaAttackUpdate::PlayerProperties { id, username, .. })
+                    if username == "twin" =>
+                {
+                    Some(id)
+                }
+                _ => None,
+            })
+            .await;
+        assert_ne!(guest_id, host_id);
+    }
+}
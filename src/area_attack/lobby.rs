@@ -0,0 +1,74 @@
+//! The screen shown while a game waits to be begun, which lists the players who have joined it
+//! so far. Only the host can begin the game.
+
+use bevy::prelude::*;
+use bevy_egui::EguiContext;
+use egui::RichText;
+
+use crate::{
+    cursor::Cursor,
+    main_menu::{standard_window, JoinedGame, ServerLogin},
+};
+
+use super::{
+    components::PlayerColor, protocol::AreaAttackRequest, puppet::Puppet,
+    settings::AreaAttackSettings,
+};
+
+pub fn lobby(
+    mut ctx: ResMut<EguiContext>,
+    settings: Option<Res<AreaAttackSettings>>,
+    (joined, login): (Option<Res<JoinedGame>>, Res<ServerLogin>),
+    own: Query<&PlayerColor, (With<Cursor>, Without<Puppet>)>,
+    puppets: Query<(&Puppet, &Name, &PlayerColor)>,
+    mut requests: EventWriter<AreaAttackRequest>,
+) {
+    standard_window(&mut ctx, |ui| {
+        ui.vertical_centered(|ui| {
+            if let Some(joined) = &joined {
+                ui.heading(format!("Game {}", joined.id));
+            }
+            if let Some(settings) = settings {
+                ui.label(format!(
+                    "Field: {}",
+                    settings.field.as_deref().unwrap_or("random")
+                ));
+                ui.label(format!("Mines: {}", settings.mines));
+                ui.label(format!(
+                    "Stages: attack at {}s, lock at {}s, end at {}s",
+                    settings.attack_begins.as_secs(),
+                    settings.lock_begins.as_secs(),
+                    settings.game_ends.as_secs()
+                ));
+                ui.label(format!(
+                    "Freezes last {}s, up to {} players",
+                    settings.freeze_duration.as_secs(),
+                    settings.max_players
+                ));
+                if let Some(seed) = settings.seed {
+                    ui.label(format!("Seed: {seed}"));
+                }
+            }
+
+            ui.separator();
+            ui.label("Players");
+            if let Ok(&color) = own.get_single() {
+                ui.label(RichText::new(format!("{} (you)", login.username)).color(color));
+            }
+            let mut players: Vec<_> = puppets.iter().collect();
+            players.sort_by_key(|(&Puppet(id), ..)| id);
+            for (_, name, &color) in players {
+                ui.label(RichText::new(name.as_str()).color(color));
+            }
+            ui.separator();
+
+            if joined.is_some_and(|joined| joined.host) {
+                if ui.button("Begin game").clicked() {
+                    requests.send(AreaAttackRequest::StartGame);
+                }
+            } else {
+                ui.label("Waiting for the host to begin the game");
+            }
+        })
+    });
+}
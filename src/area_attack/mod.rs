@@ -2,24 +2,37 @@ use bevy::{prelude::*, utils::Uuid};
 
 mod client_systems;
 mod components;
+mod game;
 mod impl_v2;
+mod lobby;
 mod protocol;
 pub mod puppet;
+mod reconnect;
+mod replay;
 mod server_systems;
+mod settings;
+mod spectate;
 mod states;
 
 use iyes_loopless::prelude::*;
 use server_systems::*;
 
 use crate::{
-    area_attack::{components::FreezeTimer, protocol::AreaAttackUpdate},
-    main_menu::{Menu, ToGame},
-    server::{GameMarker, LocalEvent},
+    area_attack::{
+        components::{ClientTile, FormerPlayers, FreezeTimer, StageClock},
+        protocol::AreaAttackUpdate,
+    },
+    cursor::{clear_hints, destroy_hints},
+    main_menu::{Menu, Spectating, ToGame},
+    replay::{tick_recording, watching_area_attack, Recording},
+    server::{CommonConnection as Connection, GameMarker, LocalEvent},
 };
 
 pub use impl_v2::IAreaAttack;
+pub use replay::Exchange;
+pub use settings::AreaAttackSettings;
 
-use self::{components::RevealTile, protocol::AreaAttackRequest, states::AreaAttack};
+use self::{protocol::AreaAttackRequest, states::AreaAttack};
 
 pub const AREA_ATTACK_MARKER: GameMarker = GameMarker(
     match Uuid::try_parse("040784a0-e905-44a9-b698-14a71a29b3fd") {
@@ -34,22 +47,15 @@ pub struct AreaAttackServer;
 impl Plugin for AreaAttackServer {
     fn build(&self, app: &mut App) {
         app.add_event::<LocalEvent<AreaAttackRequest>>()
-            .add_event::<RevealTile>()
             .add_system_set(
                 ConditionSet::new()
                     .run_not_in_state(Menu::Loading)
-                    .with_system(broadcast_positions)
                     .with_system(create_game)
-                    .with_system(reveal_tiles)
                     .with_system(unmark_init_access)
                     .with_system(prepare_player)
-                    .with_system(unfreeze_players)
-                    .with_system(send_tiles)
                     .with_system(net_events)
-                    .with_system(initial_transition)
+                    .with_system(game_requests)
                     .with_system(stage_transitions)
-                    .with_system(update_selecting_tile)
-                    .with_system(update_tile_playing)
                     .into(),
             );
     }
@@ -62,24 +68,84 @@ impl Plugin for AreaAttackClient {
         use AreaAttack::*;
         app.add_loopless_state(Inactive)
             .init_resource::<FreezeTimer>()
+            .init_resource::<StageClock>()
+            .init_resource::<FormerPlayers>()
             .add_event::<AreaAttackUpdate>()
+            .add_event::<AreaAttackRequest>()
             .add_system(|mut commands: Commands, mut ev: EventReader<ToGame>| {
                 if ev.iter().any(|e| **e == AREA_ATTACK_MARKER) {
                     // transition from menu into game
-                    commands.insert_resource(NextState(Selecting))
+                    commands.insert_resource(NextState(Selecting));
+                    commands.insert_resource(Recording::<Exchange>::default());
                 }
             })
-            .add_system(client_systems::begin_game.run_in_state(Selecting))
+            .add_system(
+                lobby::lobby
+                    .run_in_state(Selecting)
+                    .run_not_in_state(Menu::Replay)
+                    .run_unless_resource_exists::<Spectating>(),
+            )
+            .add_system(
+                client_systems::finish_screen
+                    .run_in_state(Finishing)
+                    .run_not_in_state(Menu::Replay),
+            )
             .add_exit_system(Menu::Loading, client_systems::create_freeze_timer)
+            .add_enter_system(Inactive, destroy_hints)
+            .add_enter_system(Inactive, client_systems::forget_former_players)
+            // recording and playback
+            .add_system(tick_recording::<Exchange>)
+            .add_enter_system(Finishing, replay::save_replay)
+            .add_enter_system(Inactive, replay::stop_recording)
+            .add_enter_system(
+                Menu::Replay,
+                replay::start_replay.run_if(watching_area_attack),
+            )
+            .add_system(
+                replay::play_replay
+                    .run_in_state(Menu::Replay)
+                    .run_if(watching_area_attack),
+            )
+            .add_exit_system(
+                Menu::Replay,
+                replay::stop_replay.run_if(watching_area_attack),
+            )
+            // keeping the connection
+            .add_system(reconnect::reconnect.run_if_resource_exists::<reconnect::Reconnecting>())
+            .add_enter_system(Inactive, reconnect::forget_session)
+            .add_system(
+                reconnect::closing_notice
+                    .run_not_in_state(Inactive)
+                    .run_if_resource_exists::<reconnect::ServerClosing>(),
+            )
+            // watching a game
+            .add_system(
+                spectate::spectator_panel
+                    .run_not_in_state(Inactive)
+                    .run_if_resource_exists::<Spectating>(),
+            )
+            .add_enter_system(Inactive, spectate::stop_spectating)
+            // the player's own input, which a replay has none of
             .add_system_set(
                 ConditionSet::new()
                     .run_not_in_state(Inactive)
+                    .run_if_resource_exists::<Connection>()
                     .with_system(client_systems::send_position)
                     .with_system(client_systems::request_reveal)
+                    .with_system(client_systems::show_hint)
+                    .with_system(client_systems::listen_net)
+                    .with_system(client_systems::send_requests)
+                    .with_system(reconnect::detect_disconnect)
+                    .into(),
+            )
+            .add_system_set(
+                ConditionSet::new()
+                    .run_not_in_state(Inactive)
+                    .with_system(clear_hints::<ClientTile>)
                     .with_system(client_systems::draw_tiles)
                     .with_system(client_systems::freeze_timer)
+                    .with_system(client_systems::stage_clock)
                     // Systems for receiving network events
-                    .with_system(client_systems::listen_net)
                     .with_system(client_systems::reset_field)
                     .with_system(client_systems::player_update)
                     .with_system(client_systems::self_update)
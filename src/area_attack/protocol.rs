@@ -1,29 +1,43 @@
-use bevy::prelude::*;
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 
-use crate::{common::Position, minefield::FieldShape};
+use crate::{
+    common::Position,
+    minefield::FieldShape,
+    server::{PlayerId, SessionToken},
+};
 
 use super::{
     components::{ClientTile, PlayerColor},
+    settings::AreaAttackSettings,
     states::AreaAttack,
 };
 
+/// How long a player who loses its connection keeps its place in the game
+pub const RECONNECT_GRACE: Duration = Duration::from_secs(60);
+
 #[derive(Serialize, Deserialize, Clone)]
 pub enum AreaAttackUpdate {
     FieldShape(FieldShape),
+    /// The settings the game was created with, sent to every player who joins
+    Settings(AreaAttackSettings),
     /// Can both be sent on the creation of a new player as well as when a player updates its
     /// properties, and will be sent in a batch to any player who joins the game
     PlayerProperties {
-        id: Entity,
+        id: PlayerId,
         username: String,
         color: PlayerColor,
         position: Position,
     },
     Reposition {
-        id: Entity,
+        id: PlayerId,
         position: Position,
     },
+    /// The player has left the game for good, and so has given up its place in it
+    PlayerLeft {
+        id: PlayerId,
+    },
     /// Will be sent to the player if the game autosets its properties (e.g. on initial join)
     SelfChange {
         color: PlayerColor,
@@ -34,20 +48,90 @@ pub enum AreaAttackUpdate {
         to: ClientTile,
     },
     Transition(AreaAttack),
+    /// Every tile of the field as the receiving player sees it, along with the stage of the game
+    /// and how long is left of it. Sent after [AreaAttackUpdate::FieldShape] to players who join
+    /// or return to a game, and on [AreaAttackRequest::Snapshot].
+    Snapshot {
+        tiles: TileRuns,
+        stage: AreaAttack,
+        /// Time left until the next stage, if the stage is on a clock
+        remaining: Option<Duration>,
+    },
     /// Indicates to the player that they have been frozen at this time
     Freeze,
     /// The client has attempted to select a mine in the [AreaAttack::Lock] stage and thus died
     Killed,
     /// Issued to a client when it attempts to join a full game
     Full,
+    /// Sent to a player when it joins, to be given back with [ClientMessage::Rejoin] if its
+    /// connection is lost
+    ///
+    /// [ClientMessage::Rejoin]: crate::server::ClientMessage::Rejoin
+    Session(SessionToken),
+    /// Sent to the host of the game when it joins or returns, and to the player who becomes the
+    /// host when the one before it leaves
+    Host,
 
     NotHost,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum AreaAttackRequest {
     StartGame,
     Reveal(Position),
     Position(Position),
     Color(PlayerColor),
+    /// Asks for an [AreaAttackUpdate::Snapshot], for a client whose view of the field has gone
+    /// out of step with the server
+    Snapshot,
+}
+
+/// The tiles of a field in order of their position, with each run of equal tiles stored once
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TileRuns(Vec<(u32, ClientTile)>);
+
+impl TileRuns {
+    pub fn encode(tiles: impl IntoIterator<Item = ClientTile>) -> Self {
+        let mut runs: Vec<(u32, ClientTile)> = Vec::new();
+        for tile in tiles {
+            match runs.last_mut() {
+                Some((count, last)) if *last == tile => *count += 1,
+                _ => runs.push((1, tile)),
+            }
+        }
+        Self(runs)
+    }
+
+    pub fn decode(&self) -> impl Iterator<Item = ClientTile> + '_ {
+        self.0
+            .iter()
+            .flat_map(|&(count, tile)| std::iter::repeat_n(tile, count as usize))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{area_attack::components::ClientTile, server::PlayerId};
+
+    use super::TileRuns;
+
+    #[test]
+    fn tile_runs_round_trip() {
+        let owned = ClientTile::Owned {
+            player: PlayerId(3),
+            num_neighbors: 2,
+        };
+        let tiles = [
+            vec![ClientTile::Unknown; 40],
+            vec![owned; 3],
+            vec![ClientTile::Mine],
+            vec![ClientTile::Unknown; 12],
+        ]
+        .concat();
+
+        let runs = TileRuns::encode(tiles.iter().copied());
+        assert_eq!(runs.0.len(), 4);
+        assert_eq!(runs.decode().collect::<Vec<_>>(), tiles);
+        assert_eq!(TileRuns::encode([]).decode().count(), 0);
+    }
 }
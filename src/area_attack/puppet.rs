@@ -3,13 +3,13 @@
 
 use bevy::prelude::*;
 
-use crate::{common::Position, cursor::Cursor};
+use crate::{common::Position, cursor::Cursor, server::PlayerId};
 
 #[derive(Component, Deref, Copy, Clone)]
-pub struct Puppet(pub Entity);
+pub struct Puppet(pub PlayerId);
 
-impl PartialEq<Entity> for Puppet {
-    fn eq(&self, other: &Entity) -> bool {
+impl PartialEq<PlayerId> for Puppet {
+    fn eq(&self, other: &PlayerId) -> bool {
         **self == *other
     }
 }
@@ -0,0 +1,220 @@
+//! Keeps a client in its game through a lost connection. The client connects to the server again
+//! and asks for its place back with the token that it was given when it joined, after which the
+//! game sends it everything it missed. A connection which the server closes because it is
+//! shutting down is not reconnected.
+
+use std::time::Duration;
+
+use bevy::{
+    prelude::*,
+    tasks::{IoTaskPool, Task},
+};
+use bevy_egui::EguiContext;
+use futures_lite::future;
+use iyes_loopless::state::NextState;
+
+use crate::{
+    main_menu::{standard_window, Menu, ServerLogin},
+    server::{
+        ClientMessage, CommonConnection as Connection, Greeting, HandshakeReply, MessageError,
+        SessionToken,
+    },
+};
+
+use super::{
+    client_systems::{despawn_board, BoardEntities},
+    protocol::RECONNECT_GRACE,
+    states::AreaAttack,
+};
+
+/// How long to wait between attempts to connect again
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Present once the server has said that it is shutting down, counting down to when it does
+#[derive(Resource)]
+pub struct ServerClosing(Timer);
+
+impl ServerClosing {
+    pub fn new(seconds: u32) -> Self {
+        Self(Timer::new(
+            Duration::from_secs(seconds as u64),
+            TimerMode::Once,
+        ))
+    }
+}
+
+/// The token this client was given on joining the game it is in
+#[derive(Resource, Deref)]
+pub struct Session(pub SessionToken);
+
+/// Present while the connection to the server is lost
+#[derive(Resource)]
+pub struct Reconnecting {
+    /// The time at which the connection was lost
+    since: Duration,
+    next_attempt: Duration,
+    /// An attempt to connect which is underway, kept off the frame since it blocks
+    connecting: Option<Task<Result<Connection, Box<MessageError>>>>,
+    /// A connection which has been opened but cannot be used yet
+    pending: Option<Connection>,
+    /// Whether the pending connection has greeted the server, and waits for its answer
+    greeted: bool,
+}
+
+pub fn detect_disconnect(
+    mut commands: Commands,
+    sock: Res<Connection>,
+    (session, closing): (Option<Res<Session>>, Option<Res<ServerClosing>>),
+    time: Res<Time>,
+) {
+    if !sock.is_disconnected() {
+        return;
+    }
+    commands.remove_resource::<Connection>();
+    // a server which has shut down is not coming back
+    if session.is_some() && closing.is_none() {
+        log::info!("Connection to the server was lost, trying to reconnect");
+        commands.insert_resource(Reconnecting {
+            since: time.elapsed(),
+            next_attempt: time.elapsed(),
+            connecting: None,
+            pending: None,
+            greeted: false,
+        });
+    } else {
+        commands.insert_resource(NextState(AreaAttack::Inactive));
+        commands.insert_resource(NextState(Menu::MainMenu));
+    }
+}
+
+pub fn reconnect(
+    mut commands: Commands,
+    mut ctx: ResMut<EguiContext>,
+    mut reconnecting: ResMut<Reconnecting>,
+    (login, session): (Res<ServerLogin>, Res<Session>),
+    time: Res<Time>,
+    board: BoardEntities,
+) {
+    let now = time.elapsed();
+    let remaining = RECONNECT_GRACE.saturating_sub(now.saturating_sub(reconnecting.since));
+    let mut give_up = remaining.is_zero();
+    standard_window(&mut ctx, |ui| {
+        ui.vertical_centered(|ui| {
+            ui.label("The connection to the server was lost");
+            ui.label(format!("Reconnecting, {}s left", remaining.as_secs()));
+            give_up |= ui.button("Leave game").clicked();
+        })
+    });
+    if give_up {
+        leave_game(&mut commands, &board);
+        return;
+    }
+
+    if reconnecting
+        .pending
+        .as_ref()
+        .is_some_and(Connection::is_disconnected)
+    {
+        reconnecting.pending = None;
+    }
+    if reconnecting.pending.is_none()
+        && reconnecting.connecting.is_none()
+        && now >= reconnecting.next_attempt
+    {
+        reconnecting.next_attempt = now + RETRY_INTERVAL;
+        let address = login.address.clone();
+        reconnecting.connecting =
+            Some(IoTaskPool::get().spawn(async move { Connection::connect(&address) }));
+    }
+    if let Some(connected) = reconnecting
+        .connecting
+        .as_mut()
+        .and_then(|task| future::block_on(future::poll_once(task)))
+    {
+        reconnecting.connecting = None;
+        match connected {
+            Ok(sock) => {
+                reconnecting.pending = Some(sock);
+                reconnecting.greeted = false;
+            }
+            Err(e) => log::debug!("Could not reconnect: {e}"),
+        }
+    }
+    if !reconnecting
+        .pending
+        .as_ref()
+        .is_some_and(Connection::is_ready)
+    {
+        return;
+    }
+
+    let reconnecting = &mut *reconnecting;
+    let sock = reconnecting.pending.as_mut().unwrap();
+    if !reconnecting.greeted {
+        if sock.try_send(Greeting::new(login.username.clone())).is_ok() {
+            reconnecting.greeted = true;
+        } else {
+            reconnecting.pending = None;
+        }
+        return;
+    }
+    match sock.recv_message::<HandshakeReply>() {
+        Some(Ok(HandshakeReply::Accepted { .. })) => (),
+        Some(Ok(HandshakeReply::Rejected(reason))) => {
+            // the server has been replaced with one that this client cannot talk to
+            log::warn!("Could not reconnect: {reason}");
+            leave_game(&mut commands, &board);
+            return;
+        }
+        Some(Err(e)) => {
+            log::debug!("Could not reconnect: {e}");
+            reconnecting.pending = None;
+            return;
+        }
+        None => return,
+    }
+
+    let mut sock = reconnecting.pending.take().unwrap();
+    if sock
+        .try_send(ClientMessage::Rejoin { token: **session })
+        .is_ok()
+    {
+        // the game sends the board again from the start
+        despawn_board(&mut commands, &board);
+        commands.insert_resource(sock);
+        commands.remove_resource::<Reconnecting>();
+    } else {
+        log::debug!("Could not ask for a place back in the game");
+    }
+}
+
+/// Warns the player of the server shutting down, along with how long is left
+pub fn closing_notice(
+    mut ctx: ResMut<EguiContext>,
+    mut closing: ResMut<ServerClosing>,
+    time: Res<Time>,
+) {
+    let seconds = closing.0.tick(time.delta()).remaining().as_secs();
+    egui::Area::new("server_closing")
+        .anchor(egui::Align2::CENTER_TOP, [0.0, 40.0])
+        .show(ctx.ctx_mut(), |ui| {
+            ui.colored_label(
+                egui::Color32::RED,
+                format!("The server shuts down in {seconds}s"),
+            );
+        });
+}
+
+fn leave_game(commands: &mut Commands, board: &BoardEntities) {
+    despawn_board(commands, board);
+    commands.remove_resource::<Reconnecting>();
+    commands.remove_resource::<Session>();
+    commands.insert_resource(NextState(AreaAttack::Inactive));
+    commands.insert_resource(NextState(Menu::MainMenu));
+}
+
+pub fn forget_session(mut commands: Commands) {
+    commands.remove_resource::<Session>();
+    commands.remove_resource::<Reconnecting>();
+    commands.remove_resource::<ServerClosing>();
+}
@@ -0,0 +1,121 @@
+//! Recording of Area Attack games from the side of one client, and playback of them through the
+//! same systems that draw a game in progress. A recorded game holds everything the client sent and
+//! received, so that the view of the player who recorded it can be played back exactly.
+
+use std::time::Duration;
+
+use bevy::prelude::*;
+use iyes_loopless::state::NextState;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    common::Position,
+    cursor::Cursor,
+    replay::{Playback, RecordedGame, Recording, Replay},
+};
+
+use super::{
+    client_systems::{despawn_board, BoardEntities},
+    protocol::{AreaAttackRequest, AreaAttackUpdate},
+    puppet::Puppet,
+    settings::AreaAttackSettings,
+    states::AreaAttack,
+};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Exchange {
+    Sent(AreaAttackRequest),
+    Received(AreaAttackUpdate),
+}
+
+pub fn stop_recording(mut commands: Commands) {
+    commands.remove_resource::<Recording<Exchange>>();
+}
+
+pub fn save_replay(
+    recording: Option<Res<Recording<Exchange>>>,
+    settings: Option<Res<AreaAttackSettings>>,
+) {
+    let Some(recording) = recording else {
+        return;
+    };
+    let exchanges = recording.entries();
+    let Some(shape) = exchanges.iter().find_map(|(_, exchange)| match exchange {
+        Exchange::Received(AreaAttackUpdate::FieldShape(shape)) => Some(shape.clone()),
+        _ => None,
+    }) else {
+        return;
+    };
+    Replay {
+        seed: settings
+            .and_then(|settings| settings.seed)
+            .unwrap_or_default(),
+        shape,
+        game: RecordedGame::AreaAttack(exchanges),
+    }
+    .save();
+}
+
+/// How far the updates of a replay have been handed to the client systems
+#[derive(Resource, Default)]
+pub struct ReplayProgress {
+    shown: Duration,
+    /// Index of the first exchange which has not been played yet
+    next: usize,
+}
+
+pub fn start_replay(mut commands: Commands) {
+    commands.insert_resource(ReplayProgress::default());
+    commands.insert_resource(NextState(AreaAttack::Selecting));
+}
+
+/// Hands the received updates up to the time of the replay to the client systems, as if they had
+/// just come from the server. Going back in time clears the board and starts over.
+pub fn play_replay(
+    mut commands: Commands,
+    playback: Res<Playback>,
+    mut progress: ResMut<ReplayProgress>,
+    mut updates: EventWriter<AreaAttackUpdate>,
+    mut own_cursor: Query<&mut Position, (With<Cursor>, Without<Puppet>)>,
+    board: BoardEntities,
+) {
+    let RecordedGame::AreaAttack(exchanges) = &playback.replay.game else {
+        return;
+    };
+    if playback.time < progress.shown {
+        despawn_board(&mut commands, &board);
+        *progress = ReplayProgress::default();
+        commands.insert_resource(NextState(AreaAttack::Selecting));
+    }
+    progress.shown = playback.time;
+
+    while let Some((_, exchange)) = exchanges
+        .get(progress.next)
+        .filter(|(time, _)| *time <= playback.time)
+    {
+        progress.next += 1;
+        match exchange {
+            // the server never sends this client its own position
+            Exchange::Sent(AreaAttackRequest::Position(position)) => {
+                if let Ok(mut cursor) = own_cursor.get_single_mut() {
+                    *cursor = *position;
+                }
+            }
+            Exchange::Sent(_) => (),
+            Exchange::Received(update) => {
+                updates.send(update.clone());
+                // the field has to be spawned before any of its tiles can be changed
+                if matches!(update, AreaAttackUpdate::FieldShape(_)) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+pub fn stop_replay(mut commands: Commands, board: BoardEntities) {
+    despawn_board(&mut commands, &board);
+    commands.remove_resource::<ReplayProgress>();
+    commands.remove_resource::<AreaAttackSettings>();
+    commands.insert_resource(NextState(AreaAttack::Inactive));
+}
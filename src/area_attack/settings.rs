@@ -0,0 +1,377 @@
+//! Settings chosen by the player who creates an Area Attack game. They travel to the server inside
+//! the arguments of [ClientMessage::Create](crate::server::ClientMessage::Create), and are echoed
+//! back to every player who joins the game once the server has accepted them.
+
+use std::{ops::Range, time::Duration};
+
+use bevy::prelude::*;
+use egui::{DragValue, Slider, TextEdit, Ui};
+use rand::{rngs::StdRng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use strum::IntoEnumIterator;
+
+use crate::{
+    minefield::{Difficulty, FieldShape, MineCount},
+    server::GameSettings,
+};
+
+use super::{
+    components::{PlayerColor, ATTACK_BEGINS, FREEZE_DURATION, GAME_ENDS, LOCK_BEGINS},
+    states::AreaAttack,
+};
+
+#[derive(Resource, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AreaAttackSettings {
+    /// Name of the field shape to play on. If none is given, the server picks one at random.
+    pub field: Option<String>,
+    pub mines: MineCount,
+    /// Time since the game began at which [AreaAttack::Attack](super::states::AreaAttack::Attack)
+    /// begins
+    pub attack_begins: Duration,
+    /// Time since the game began at which [AreaAttack::Lock](super::states::AreaAttack::Lock)
+    /// begins
+    pub lock_begins: Duration,
+    /// Time since the game began at which the game is over
+    pub game_ends: Duration,
+    /// How long a player cannot reveal tiles after revealing a mine in the first stage
+    pub freeze_duration: Duration,
+    pub max_players: u8,
+    /// The seed behind every random decision made in the game. If none is given, the server picks
+    /// one, which is then shown to every player so that the game can be played again.
+    pub seed: Option<u64>,
+}
+
+/// The longest that a player may be frozen for
+const MAX_FREEZE: Duration = Duration::from_secs(60);
+
+impl Default for AreaAttackSettings {
+    fn default() -> Self {
+        Self {
+            field: None,
+            mines: Difficulty::default().into(),
+            attack_begins: ATTACK_BEGINS,
+            lock_begins: LOCK_BEGINS,
+            game_ends: GAME_ENDS,
+            freeze_duration: FREEZE_DURATION,
+            max_players: PlayerColor::iter().count() as u8,
+            seed: None,
+        }
+    }
+}
+
+/// Settings as they were before games could be seeded
+#[derive(Serialize, Deserialize)]
+struct SettingsV1 {
+    field: Option<String>,
+    mines: MineCount,
+    attack_begins: Duration,
+    lock_begins: Duration,
+    game_ends: Duration,
+    freeze_duration: Duration,
+    max_players: u8,
+}
+
+impl From<SettingsV1> for AreaAttackSettings {
+    fn from(v1: SettingsV1) -> Self {
+        Self {
+            field: v1.field,
+            mines: v1.mines,
+            attack_begins: v1.attack_begins,
+            lock_begins: v1.lock_begins,
+            game_ends: v1.game_ends,
+            freeze_duration: v1.freeze_duration,
+            max_players: v1.max_players,
+            seed: None,
+        }
+    }
+}
+
+/// The form in which settings are sent over the network. Whenever the settings change shape, a new
+/// variant should be added so that older clients can be told apart from malformed ones.
+#[derive(Serialize, Deserialize)]
+enum VersionedSettings {
+    V1(SettingsV1),
+    V2(AreaAttackSettings),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SettingsError {
+    #[error("The settings could not be read, possibly because they are from another version")]
+    Unreadable(#[from] rmp_serde::decode::Error),
+    #[error("There is no field named \"{0}\"")]
+    UnknownField(String),
+    #[error("There must be at least one mine, and at least one tile without a mine")]
+    MineCount,
+    #[error("Every stage must begin after the one before it")]
+    StageOrder,
+    #[error("A freeze must last between 1 and {} seconds", MAX_FREEZE.as_secs())]
+    FreezeDuration,
+    #[error("A game must allow between 1 and {} players", PlayerColor::iter().count())]
+    MaxPlayers,
+}
+
+impl AreaAttackSettings {
+    /// Reads settings from the arguments of a create request. Empty arguments stand for the
+    /// default settings.
+    pub fn decode(args: &[u8]) -> Result<Self, SettingsError> {
+        if args.is_empty() {
+            return Ok(Self::default());
+        }
+        Ok(match rmp_serde::from_slice(args)? {
+            VersionedSettings::V1(settings) => settings.into(),
+            VersionedSettings::V2(settings) => settings,
+        })
+    }
+
+    /// Creates the random number generator for a game played with these settings, picking a seed
+    /// first if none was chosen
+    pub fn rng(&mut self) -> StdRng {
+        StdRng::seed_from_u64(*self.seed.get_or_insert_with(rand::random))
+    }
+
+    /// Checks that the settings describe a playable game. `field_exists` reports whether the
+    /// server knows of a field by the given name.
+    pub fn validate(&self, field_exists: impl Fn(&str) -> bool) -> Result<(), SettingsError> {
+        if let Some(field) = &self.field {
+            if !field_exists(field) {
+                return Err(SettingsError::UnknownField(field.clone()));
+            }
+        }
+        if !match self.mines {
+            MineCount::Density(density) => density > 0.0 && density < 1.0,
+            MineCount::Exact(count) => count > 0,
+        } {
+            return Err(SettingsError::MineCount);
+        }
+        if self.attack_begins.is_zero()
+            || self.lock_begins <= self.attack_begins
+            || self.game_ends <= self.lock_begins
+        {
+            return Err(SettingsError::StageOrder);
+        }
+        if self.freeze_duration < Duration::from_secs(1) || self.freeze_duration > MAX_FREEZE {
+            return Err(SettingsError::FreezeDuration);
+        }
+        if self.max_players == 0 || self.max_players as usize > PlayerColor::iter().count() {
+            return Err(SettingsError::MaxPlayers);
+        }
+        Ok(())
+    }
+
+    /// Checks that the settings can be played on the field which was chosen for them, which
+    /// [validate](Self::validate) cannot until one has been
+    pub fn validate_field(&self, field: &FieldShape) -> Result<(), SettingsError> {
+        match self.mines {
+            MineCount::Exact(count) if count >= field.decode().count() => {
+                Err(SettingsError::MineCount)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Describes the settings in a single line, for players choosing a game to join
+    pub fn summary(&self) -> String {
+        format!(
+            "{} field, {}, {} players, attack at {}s, lock at {}s, end at {}s",
+            self.field.as_deref().unwrap_or("random"),
+            self.mines,
+            self.max_players,
+            self.attack_begins.as_secs(),
+            self.lock_begins.as_secs(),
+            self.game_ends.as_secs()
+        )
+    }
+
+    /// Time since the game began during which the given stage is played, for the stages which are
+    /// on a clock
+    pub fn stage_times(&self, stage: AreaAttack) -> Option<Range<Duration>> {
+        match stage {
+            AreaAttack::Stage1 => Some(Duration::ZERO..self.attack_begins),
+            AreaAttack::Attack => Some(self.attack_begins..self.lock_begins),
+            AreaAttack::Lock => Some(self.lock_begins..self.game_ends),
+            AreaAttack::Inactive | AreaAttack::Selecting | AreaAttack::Finishing => None,
+        }
+    }
+}
+
+/// Edits a duration in whole seconds
+fn seconds(ui: &mut Ui, label: &str, duration: &mut Duration) {
+    ui.horizontal(|ui| {
+        ui.label(label);
+        let mut secs = duration.as_secs();
+        ui.add(DragValue::new(&mut secs).suffix(" s"));
+        *duration = Duration::from_secs(secs);
+    });
+}
+
+impl GameSettings for AreaAttackSettings {
+    fn ui(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Field:");
+            let mut name = self.field.clone().unwrap_or_default();
+            ui.add(TextEdit::singleline(&mut name).hint_text("random"));
+            self.field = (!name.is_empty()).then_some(name);
+        });
+        ui.horizontal(|ui| {
+            ui.label("Mines:");
+            for difficulty in Difficulty::iter() {
+                ui.selectable_value(&mut self.mines, difficulty.into(), difficulty.to_string());
+            }
+        });
+        ui.horizontal(|ui| {
+            if ui
+                .selectable_label(matches!(self.mines, MineCount::Density(_)), "density")
+                .clicked()
+            {
+                self.mines = Difficulty::default().into();
+            }
+            if ui
+                .selectable_label(matches!(self.mines, MineCount::Exact(_)), "count")
+                .clicked()
+            {
+                self.mines = MineCount::Exact(100);
+            }
+            match &mut self.mines {
+                MineCount::Density(density) => {
+                    let mut percent = *density * 100.0;
+                    ui.add(Slider::new(&mut percent, 1.0..=99.0).suffix("%"));
+                    *density = percent / 100.0;
+                }
+                MineCount::Exact(count) => {
+                    ui.add(DragValue::new(count).suffix(" mines"));
+                }
+            }
+        });
+        seconds(ui, "Attack begins at:", &mut self.attack_begins);
+        seconds(ui, "Lock begins at:", &mut self.lock_begins);
+        seconds(ui, "Game ends at:", &mut self.game_ends);
+        seconds(ui, "Freeze duration:", &mut self.freeze_duration);
+        ui.horizontal(|ui| {
+            ui.label("Max players:");
+            ui.add(Slider::new(
+                &mut self.max_players,
+                1..=PlayerColor::iter().count() as u8,
+            ));
+        });
+        ui.horizontal(|ui| {
+            ui.label("Seed:");
+            let mut seed = self.seed.map(|seed| seed.to_string()).unwrap_or_default();
+            ui.add(TextEdit::singleline(&mut seed).hint_text("random"));
+            self.seed = seed.parse().ok();
+        });
+    }
+
+    fn check(&self) -> Result<(), String> {
+        // only the server knows which fields it has
+        self.validate(|_| true).map_err(|e| e.to_string())
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        rmp_serde::to_vec(&VersionedSettings::V2(self.clone())).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        minefield::{FieldShape, MineCount},
+        server::GameSettings,
+    };
+
+    use super::{AreaAttackSettings, SettingsError, SettingsV1, VersionedSettings};
+
+    #[test]
+    fn settings_survive_the_wire() {
+        let settings = AreaAttackSettings {
+            field: Some("circle_100_100".to_string()),
+            mines: MineCount::Exact(300),
+            max_players: 2,
+            seed: Some(7),
+            ..Default::default()
+        };
+        let decoded = AreaAttackSettings::decode(&settings.encode()).unwrap();
+        assert_eq!(decoded, settings);
+        assert_eq!(
+            AreaAttackSettings::decode(&[]).unwrap(),
+            AreaAttackSettings::default()
+        );
+        assert!(AreaAttackSettings::decode(&[0xc1]).is_err());
+    }
+
+    #[test]
+    fn older_settings_are_read() {
+        let defaults = AreaAttackSettings::default();
+        let v1 = VersionedSettings::V1(SettingsV1 {
+            field: None,
+            mines: defaults.mines,
+            attack_begins: defaults.attack_begins,
+            lock_begins: defaults.lock_begins,
+            game_ends: defaults.game_ends,
+            freeze_duration: defaults.freeze_duration,
+            max_players: defaults.max_players,
+        });
+        let decoded = AreaAttackSettings::decode(&rmp_serde::to_vec(&v1).unwrap()).unwrap();
+        assert_eq!(decoded, defaults);
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        let defaults = AreaAttackSettings::default();
+        assert!(defaults.validate(|_| false).is_ok());
+
+        let unknown = AreaAttackSettings {
+            field: Some("nowhere".to_string()),
+            ..defaults.clone()
+        };
+        assert!(matches!(
+            unknown.validate(|name| name == "circle_100_100"),
+            Err(SettingsError::UnknownField(_))
+        ));
+
+        let backwards = AreaAttackSettings {
+            lock_begins: Duration::from_secs(1),
+            ..defaults.clone()
+        };
+        assert!(matches!(
+            backwards.validate(|_| true),
+            Err(SettingsError::StageOrder)
+        ));
+
+        let crowded = AreaAttackSettings {
+            max_players: 5,
+            ..defaults.clone()
+        };
+        assert!(matches!(
+            crowded.validate(|_| true),
+            Err(SettingsError::MaxPlayers)
+        ));
+
+        for freeze_duration in [Duration::ZERO, Duration::from_secs(3600)] {
+            let frozen = AreaAttackSettings {
+                freeze_duration,
+                ..defaults.clone()
+            };
+            assert!(matches!(
+                frozen.validate(|_| true),
+                Err(SettingsError::FreezeDuration)
+            ));
+        }
+    }
+
+    #[test]
+    fn mines_must_fit_on_the_field() {
+        let field = FieldShape::try_from(b"xxx\nxxx\nxxx".as_slice()).unwrap();
+        let settings = |count| AreaAttackSettings {
+            mines: MineCount::Exact(count),
+            ..Default::default()
+        };
+        assert!(settings(8).validate_field(&field).is_ok());
+        assert!(matches!(
+            settings(9).validate_field(&field),
+            Err(SettingsError::MineCount)
+        ));
+        assert!(AreaAttackSettings::default().validate_field(&field).is_ok());
+    }
+}
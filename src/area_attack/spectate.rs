@@ -0,0 +1,91 @@
+//! Watching a game without taking part in it. A spectator has no cursor of its own, so the camera
+//! is panned freely, or made to follow the puppet cursor of one of the players.
+
+use bevy::prelude::*;
+use bevy_egui::EguiContext;
+use egui::RichText;
+use iyes_loopless::state::{CurrentState, NextState};
+
+use crate::{
+    cursor::Bindings,
+    main_menu::{Menu, Spectating},
+    server::PlayerId,
+};
+
+use super::{
+    client_systems::{despawn_board, BoardEntities},
+    components::PlayerColor,
+    puppet::Puppet,
+    states::AreaAttack,
+};
+
+/// Lists the players of the game being watched, any of which the camera can be made to follow
+/// until the camera is panned again
+pub fn spectator_panel(
+    mut commands: Commands,
+    mut ctx: ResMut<EguiContext>,
+    puppets: Query<(&Puppet, &Name, &PlayerColor, &Transform)>,
+    mut camera: Query<&mut Transform, (With<Camera>, Without<Puppet>)>,
+    (input, bindings, stage): (
+        Res<Input<KeyCode>>,
+        Res<Bindings>,
+        Res<CurrentState<AreaAttack>>,
+    ),
+    mut following: Local<Option<PlayerId>>,
+    board: BoardEntities,
+) {
+    let panning = [
+        bindings.camera_up,
+        bindings.camera_down,
+        bindings.camera_left,
+        bindings.camera_right,
+    ];
+    if input.any_pressed(panning) {
+        *following = None;
+    }
+
+    let mut leave = false;
+    egui::Window::new("Spectating")
+        .anchor(egui::Align2::RIGHT_TOP, [-8.0, 8.0])
+        .resizable(false)
+        .collapsible(false)
+        .show(ctx.ctx_mut(), |ui| {
+            if stage.0 == AreaAttack::Selecting {
+                ui.label("Waiting for the host to begin the game");
+            }
+            let mut players: Vec<_> = puppets.iter().collect();
+            players.sort_by_key(|(&Puppet(id), ..)| id);
+            for (&Puppet(id), name, &color, _) in players {
+                let label = RichText::new(name.as_str()).color(color);
+                if ui.selectable_label(*following == Some(id), label).clicked() {
+                    *following = (*following != Some(id)).then_some(id);
+                }
+            }
+            ui.separator();
+            leave = ui.button("Stop watching").clicked();
+        });
+
+    if leave {
+        despawn_board(&mut commands, &board);
+        commands.insert_resource(NextState(AreaAttack::Inactive));
+        commands.insert_resource(NextState(Menu::MainMenu));
+        return;
+    }
+
+    let Some(followed) = *following else {
+        return;
+    };
+    match puppets.iter().find(|(&Puppet(id), ..)| id == followed) {
+        Some((.., target)) => {
+            let mut camera = camera.single_mut();
+            camera.translation.x = target.translation.x;
+            camera.translation.z = target.translation.z;
+        }
+        // the player has left the game
+        None => *following = None,
+    }
+}
+
+pub fn stop_spectating(mut commands: Commands) {
+    commands.remove_resource::<Spectating>();
+}
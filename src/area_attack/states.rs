@@ -19,6 +19,18 @@ pub enum AreaAttack {
 }
 
 impl AreaAttack {
+    /// Names the stage for players who are not in the game
+    pub fn name(&self) -> &'static str {
+        match self {
+            AreaAttack::Inactive => "Inactive",
+            AreaAttack::Selecting => "Waiting to begin",
+            AreaAttack::Stage1 => "Stage 1",
+            AreaAttack::Attack => "Attack",
+            AreaAttack::Lock => "Lock",
+            AreaAttack::Finishing => "Finished",
+        }
+    }
+
     pub fn can_reveal(&self) -> bool {
         matches!(
             self,
@@ -13,7 +13,9 @@ use strum_macros::EnumIter;
 
 use crate::cursor::CursorPosition;
 
-#[derive(PartialEq, Clone, Copy, Debug, Component, Eq, Hash, Serialize, Deserialize)]
+#[derive(
+    PartialEq, Clone, Copy, Debug, Component, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
 pub struct Position {
     pub x: isize,
     pub y: isize,
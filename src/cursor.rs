@@ -1,12 +1,14 @@
 use crate::area_attack::puppet::Puppet;
-use crate::common::{CheckCell, FlagCell, InitCheckCell, Position, Vec2Ext};
+use crate::common::{CheckCell, FlagCell, InitCheckCell, NeedsMaterial, Position, Vec2Ext};
+use crate::load::Textures;
 use crate::main_menu::Menu;
 use crate::minefield::specific::TILE_SIZE;
 use crate::minefield::Minefield;
+use bevy::ecs::system::SystemParam;
 use bevy::input::mouse::MouseWheel;
 use bevy::math::Vec3Swizzles;
 use bevy::{prelude::*, render::camera::Camera};
-use iyes_loopless::prelude::IntoConditionalSystem;
+use iyes_loopless::prelude::{ConditionHelpers, IntoConditionalSystem};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 /// The entity field describes the minefield which it is placed on
@@ -34,6 +36,8 @@ pub struct Bindings {
     pub pause: KeyCode,
     pub flag: KeyCode,
     pub check: KeyCode,
+    /// Marks a tile which is safe to reveal, or the least risky one if there is none
+    pub hint: KeyCode,
     // camera panning
     pub camera_up: KeyCode,
     pub camera_down: KeyCode,
@@ -47,6 +51,7 @@ impl Default for Bindings {
             pause: KeyCode::Escape,
             flag: KeyCode::F,
             check: KeyCode::Space,
+            hint: KeyCode::H,
             camera_up: KeyCode::W,
             camera_down: KeyCode::S,
             camera_left: KeyCode::A,
@@ -84,10 +89,16 @@ pub fn pointer_cursor(
     tiles: Query<&Transform>, // Does not include only tiles, but can be queried for tiles
     minefields: Query<&Minefield<Entity>>,
 ) {
-    let Ok((cursor, mut cursor_position)) = cursors.get_single_mut() else { return; };
-    let Ok(minefield) = minefields.get(cursor.owning_minefield) else { return; };
+    let Ok((cursor, mut cursor_position)) = cursors.get_single_mut() else {
+        return;
+    };
+    let Ok(minefield) = minefields.get(cursor.owning_minefield) else {
+        return;
+    };
     let open_position = minefield.iter_positions().next().unwrap();
-    let Ok(root_tile) = tiles.get(minefield[&open_position]) else { return; };
+    let Ok(root_tile) = tiles.get(minefield[&open_position]) else {
+        return;
+    };
 
     // code borrowed from bevy cheatbook
     // get the camera info and transform
@@ -255,6 +266,59 @@ pub fn init_check_cell(
     }
 }
 
+/// Marks the tile that a hint suggests revealing next
+#[derive(Component)]
+pub struct Hint;
+
+/// Everything needed to place a hint on the field
+#[derive(SystemParam)]
+pub struct HintMarkers<'w, 's> {
+    commands: Commands<'w, 's>,
+    hints: Query<'w, 's, Entity, With<Hint>>,
+    textures: Res<'w, Textures>,
+    materials: ResMut<'w, Assets<StandardMaterial>>,
+}
+
+impl HintMarkers<'_, '_> {
+    /// Replaces any previous hint with a marker over the given tile, colored from green for a tile
+    /// which is certain to be safe towards red for one which is likely to contain a mine
+    pub fn show(&mut self, position: Position, chance: f32) {
+        for hint in &self.hints {
+            self.commands.entity(hint).despawn_recursive();
+        }
+        let material = self.materials.add(StandardMaterial {
+            emissive: Color::rgb(chance, 1.0 - chance, 0.0),
+            ..default()
+        });
+        self.commands
+            .spawn(SceneBundle {
+                scene: self.textures.cursor.clone(),
+                transform: Transform {
+                    translation: position.absolute(TILE_SIZE, TILE_SIZE).extend_xz(0.5),
+                    scale: Vec3::splat(0.8),
+                    ..default()
+                },
+                ..default()
+            })
+            .insert((Hint, NeedsMaterial(material)));
+    }
+}
+
+pub fn destroy_hints(mut commands: Commands, hints: Query<Entity, With<Hint>>) {
+    hints.for_each(|hint| commands.entity(hint).despawn_recursive())
+}
+
+/// Removes hints as soon as any tile of the given kind changes, since they may no longer hold
+pub fn clear_hints<T: Component>(
+    mut commands: Commands,
+    hints: Query<Entity, With<Hint>>,
+    changed: Query<(), Changed<T>>,
+) {
+    if !changed.is_empty() {
+        hints.for_each(|hint| commands.entity(hint).despawn_recursive())
+    }
+}
+
 #[derive(Resource, Deref, DerefMut)]
 pub struct ScaleFactor(pub f32);
 
@@ -283,6 +347,11 @@ impl Plugin for CursorPlugin {
             .add_system(pan_camera)
             .add_system(translate_cursor)
             .add_system(zoom_camera)
-            .add_system(pointer_cursor.run_not_in_state(Menu::Pause));
+            // a replay moves the cursor to where the player had it
+            .add_system(
+                pointer_cursor
+                    .run_not_in_state(Menu::Pause)
+                    .run_not_in_state(Menu::Replay),
+            );
     }
 }
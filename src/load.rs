@@ -39,6 +39,12 @@ impl Field {
     pub fn take_one(&self, rng: &mut impl Rng) -> &Handle<FieldShape> {
         self.handles.choose(rng).unwrap()
     }
+
+    /// The name of a field, which is the name of the file it was loaded from
+    pub fn name(handle: &Handle<FieldShape>, asset_server: &AssetServer) -> Option<String> {
+        let path = asset_server.get_handle_path(handle)?;
+        Some(path.path().file_stem()?.to_string_lossy().into_owned())
+    }
 }
 
 impl Textures {
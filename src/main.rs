@@ -9,11 +9,15 @@ mod load;
 mod main_menu;
 mod minefield;
 mod registry;
+mod replay;
 mod server;
 #[cfg(feature = "server")]
 mod server_v2;
 mod singleplayer;
 mod state;
+mod storage;
+
+use std::{net::IpAddr, path::PathBuf};
 
 use bevy::prelude::*;
 
@@ -35,7 +39,17 @@ struct Args {
 #[derive(Subcommand)]
 enum Mode {
     Client,
-    Server { address: Option<String> },
+    Server {
+        /// The address to accept connections at, in place of the one in the configuration
+        #[arg(long)]
+        bind: Option<IpAddr>,
+        /// The port to accept connections at, in place of the one in the configuration
+        #[arg(long)]
+        port: Option<u16>,
+        /// The configuration file to read, quicksweeper-server.toml by default
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
 }
 
 fn client_app() -> App {
@@ -56,6 +70,7 @@ fn client_app() -> App {
     // gamemodes
     .add_plugin(singleplayer::SingleplayerMode)
     .add_plugin(area_attack::AreaAttackClient)
+    .add_plugin(replay::ReplayViewer)
     // framerate
     .add_plugin(bevy_framepace::FramepacePlugin)
     .add_startup_system(framerate_limit);
@@ -70,11 +85,20 @@ fn main() {
     match Args::parse().mode {
         None | Some(Mode::Client) => client_app().run(),
         #[allow(unused)]
-        Some(Mode::Server { address }) => {
+        Some(Mode::Server { bind, port, config }) => {
             #[cfg(feature = "server")]
             {
-                // server::server_app(address)
-                server_v2::srv_start(address.unwrap_or("192.168.131.1".to_string()));
+                let mut config = match server_v2::config::ServerConfig::load(config.as_deref()) {
+                    Ok(config) => config,
+                    Err(e) => {
+                        eprintln!("{e:#}");
+                        std::process::exit(1);
+                    }
+                };
+                config.bind = bind.unwrap_or(config.bind);
+                config.port = port.unwrap_or(config.port);
+                // server::server_app(Some(config.bind.to_string()), config.port)
+                server_v2::srv_start(config);
             }
             #[cfg(not(feature = "server"))]
             {
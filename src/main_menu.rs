@@ -1,4 +1,8 @@
-use std::net::TcpStream;
+use std::{
+    cmp::Reverse,
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+};
 
 use bevy::prelude::*;
 use bevy_egui::EguiContext;
@@ -7,15 +11,23 @@ use iyes_loopless::{
     prelude::{AppLooplessStateExt, IntoConditionalSystem},
     state::{CurrentState, NextState},
 };
+use strum::IntoEnumIterator;
+use strum_macros::EnumIter;
 use tungstenite::{handshake::client::Response, ClientHandshake, HandshakeError, WebSocket};
 
 use crate::{
     cursor::Bindings,
+    minefield::{
+        generate::{Generation, Seed},
+        Difficulty,
+    },
     registry::{GameRegistry, REGISTRY},
     server::{
-        ActiveGame, ClientMessage, CommonConnection as Connection, GameMarker, Greeting,
-        ServerMessage,
+        Access, ActiveGame, ClientMessage, CommonConnection as Connection, GameListChange,
+        GameMarker, GameSettings, Greeting, HandshakeReply, LanServers, ServerMessage,
+        PROTOCOL_VERSION,
     },
+    singleplayer::save::SavedGame,
     Singleplayer,
 };
 
@@ -25,12 +37,30 @@ pub enum Menu {
     MainMenu,
     ServerSelect,
     GameSelect,
+    HighScores,
+    Replays,
+    /// Watching a recorded game
+    Replay,
 
     // active/pause states
     Ingame,
     Pause,
 }
 
+/// The server that the client is connected to, kept so that the connection can be made again if it
+/// is lost
+#[derive(Resource, Clone)]
+pub struct ServerLogin {
+    pub address: String,
+    pub username: String,
+    /// The gamemodes which both the server and this client support
+    pub gamemodes: Vec<GameMarker>,
+}
+
+/// How long to wait for the server to answer the greeting. Servers from before the handshake was
+/// introduced never answer at all.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
 type ClientResult =
     Result<(WebSocket<TcpStream>, Response), HandshakeError<ClientHandshake<TcpStream>>>;
 
@@ -38,11 +68,13 @@ type ClientResult =
 struct MenuFields {
     remote_addr: String,
     username: String,
-    remote_select_err: &'static str,
+    remote_select_err: String,
     #[cfg(target_arch = "wasm32")]
     trying_connection: Option<Connection>,
     #[cfg(not(target_arch = "wasm32"))]
     trying_connection: Option<ClientResult>,
+    /// A connection which has sent its greeting, along with the time it was sent at
+    greeted: Option<(Connection, Duration)>,
 }
 
 pub fn standard_window<F, R>(
@@ -59,7 +91,13 @@ where
         .show(ctx.ctx_mut(), add_contents)
 }
 
-fn run_main_menu(mut commands: Commands, mut ctx: ResMut<EguiContext>) {
+fn run_main_menu(
+    mut commands: Commands,
+    mut ctx: ResMut<EguiContext>,
+    mut difficulty: ResMut<Difficulty>,
+    mut generation: ResMut<Generation>,
+    mut seed: ResMut<Seed>,
+) {
     standard_window(&mut ctx, |ui| {
         ui.vertical_centered(|ui| {
             let initial_height = ui.available_height();
@@ -72,6 +110,40 @@ fn run_main_menu(mut commands: Commands, mut ctx: ResMut<EguiContext>) {
                 commands.insert_resource(NextState(Singleplayer::PreGame));
                 commands.insert_resource(NextState(Menu::Ingame));
             }
+            if ui
+                .add_enabled(SavedGame::exists(), egui::Button::new("Continue"))
+                .clicked()
+            {
+                if let Some(saved) = SavedGame::load() {
+                    commands.insert_resource(saved);
+                    commands.insert_resource(NextState(Singleplayer::Game));
+                    commands.insert_resource(NextState(Menu::Ingame));
+                }
+            }
+            ui.horizontal(|ui| {
+                for preset in Difficulty::iter() {
+                    ui.selectable_value(&mut *difficulty, preset, preset.to_string());
+                }
+            });
+            let mut no_guess = *generation == Generation::NoGuess;
+            ui.checkbox(&mut no_guess, "No guessing");
+            *generation = if no_guess {
+                Generation::NoGuess
+            } else {
+                Generation::Random
+            };
+            ui.horizontal(|ui| {
+                ui.label("Seed:");
+                let mut text = seed.0.map(|seed| seed.to_string()).unwrap_or_default();
+                ui.add(TextEdit::singleline(&mut text).hint_text("random"));
+                seed.0 = text.parse().ok();
+            });
+            if ui.button("High scores").clicked() {
+                commands.insert_resource(NextState(Menu::HighScores))
+            }
+            if ui.button("Replays").clicked() {
+                commands.insert_resource(NextState(Menu::Replays))
+            }
             if ui.button("Connect to server").clicked() {
                 commands.insert_resource(NextState(Menu::ServerSelect))
             }
@@ -85,22 +157,24 @@ fn server_select_menu(
     mut commands: Commands,
     mut ctx: ResMut<EguiContext>,
     mut fields: Local<MenuFields>,
+    time: Res<Time>,
+    lan: Option<Res<LanServers>>,
 ) {
     standard_window(&mut ctx, |ui| {
-        let (focus_lost, focus_gained) = ui
+        let (focus_lost, focus_gained, picked) = ui
             .vertical_centered(|ui| {
                 ui.horizontal(|ui| {
                     if ui.button("back").clicked() {
                         commands.insert_resource(NextState(Menu::MainMenu))
                     }
-                    ui.colored_label(Color32::RED, fields.remote_select_err);
+                    ui.colored_label(Color32::RED, &fields.remote_select_err);
                 });
 
                 let r1 = ui
                     .horizontal(|ui| {
                         ui.label("Server address:");
                         ui.add_enabled(
-                            fields.trying_connection.is_none(),
+                            fields.trying_connection.is_none() && fields.greeted.is_none(),
                             TextEdit::singleline(&mut fields.remote_addr),
                         )
                     })
@@ -110,15 +184,21 @@ fn server_select_menu(
                     .horizontal(|ui| {
                         ui.label("Username:");
                         ui.add_enabled(
-                            fields.trying_connection.is_none(),
+                            fields.trying_connection.is_none() && fields.greeted.is_none(),
                             TextEdit::singleline(&mut fields.username),
                         )
                     })
                     .inner;
 
+                let picked = lan.and_then(|lan| {
+                    let connecting = fields.trying_connection.is_some() || fields.greeted.is_some();
+                    lan_server_list(ui, &lan, !connecting)
+                });
+
                 (
                     r1.lost_focus() || r2.lost_focus(),
                     r1.gained_focus() || r2.gained_focus(),
+                    picked,
                 )
             })
             .inner;
@@ -144,30 +224,57 @@ fn server_select_menu(
                         }
                         Err(e) => {
                             eprintln!("{e}");
-                            fields.remote_select_err = "Failed to perform handshake with server";
+                            fields.remote_select_err =
+                                "Failed to perform handshake with server".into();
                             None
                         }
                     }
                 }
             } {
                 socket
-                    .try_send(Greeting {
-                        username: fields.username.clone(),
-                    })
+                    .try_send(Greeting::new(fields.username.clone()))
                     .map_err(|_| {
-                        fields.remote_select_err = "Failure in initializing connection";
+                        fields.remote_select_err = "Failure in initializing connection".into();
                     })?;
-
-                socket.try_send(ClientMessage::Games).map_err(|_| {
-                    fields.remote_select_err = "Could not retrieve games on the server"
-                })?;
-
-                commands.insert_resource(socket);
-                commands.insert_resource(NextState(Menu::GameSelect));
+                fields.greeted = Some((socket, time.elapsed()));
+            }
+        } else if let Some((mut socket, sent)) = fields.greeted.take() {
+            ui.label("Waiting for the server to answer...");
+            match socket.recv_message::<HandshakeReply>() {
+                Some(Ok(HandshakeReply::Accepted { gamemodes })) => {
+                    commands.insert_resource(socket);
+                    commands.insert_resource(ServerLogin {
+                        address: fields.remote_addr.clone(),
+                        username: fields.username.clone(),
+                        gamemodes,
+                    });
+                    commands.insert_resource(NextState(Menu::GameSelect));
+                }
+                Some(Ok(HandshakeReply::Rejected(reason))) => {
+                    fields.remote_select_err = reason.to_string();
+                }
+                Some(Err(e)) if e.disconnected() => {
+                    fields.remote_select_err = "The server closed the connection".into();
+                }
+                Some(Err(e)) => {
+                    log::debug!("Could not read the answer to the greeting: {e}");
+                    fields.remote_select_err =
+                        "The server could not be understood, so it is likely from another version"
+                            .into();
+                }
+                None if time.elapsed() - sent > HANDSHAKE_TIMEOUT => {
+                    fields.remote_select_err =
+                        "The server did not answer, so it may be older than this client".into();
+                }
+                None => fields.greeted = Some((socket, sent)),
             }
         }
         // execute requests to connect to server
-        else if focus_lost && ui.input().key_pressed(Key::Enter) {
+        else if (focus_lost && ui.input().key_pressed(Key::Enter)) || picked.is_some() {
+            if let Some(address) = picked {
+                fields.remote_addr = address.to_string();
+                fields.remote_select_err.clear();
+            }
             let addr = format!("ws://{}/", fields.remote_addr);
             #[cfg(target_arch = "wasm32")]
             {
@@ -176,45 +283,204 @@ fn server_select_menu(
             #[cfg(not(target_arch = "wasm32"))]
             {
                 let stream = TcpStream::connect(&fields.remote_addr).map_err(|_| {
-                    fields.remote_select_err = "Could not find that address";
+                    fields.remote_select_err = "Could not find that address".into();
                 })?;
 
                 stream.set_nonblocking(true).map_err(|_| {
-                    fields.remote_select_err = "Unable to set nonblocking connection mode";
+                    fields.remote_select_err = "Unable to set nonblocking connection mode".into();
                 })?;
 
                 fields.trying_connection = Some(tungstenite::client(addr, stream));
             }
         } else if focus_gained {
-            fields.remote_select_err = "";
+            fields.remote_select_err.clear();
         }
 
         Result::<_, ()>::Ok(())
     });
 }
 
+/// Lists the servers which have announced themselves on the local network, returning the address
+/// of the one the player chose to connect to
+fn lan_server_list(ui: &mut Ui, lan: &LanServers, enabled: bool) -> Option<SocketAddr> {
+    ui.separator();
+    ui.label("Servers on your network");
+    if !lan.is_listening() {
+        ui.label(RichText::new("Another client on this machine is already looking").weak());
+        return None;
+    }
+    let mut servers: Vec<_> = lan.servers().collect();
+    servers.sort_by_key(|&(address, _)| address);
+    if servers.is_empty() {
+        ui.label(RichText::new("None found yet").weak());
+    }
+    let mut picked = None;
+    for (address, announcement) in servers {
+        ui.horizontal(|ui| {
+            let compatible = announcement.version == PROTOCOL_VERSION;
+            let details = if compatible {
+                format!("{address}, {} games", announcement.games)
+            } else {
+                format!("{address}, another version")
+            };
+            ui.label(&announcement.name);
+            ui.label(RichText::new(details).weak());
+            if ui
+                .add_enabled(enabled && compatible, egui::Button::new("Connect"))
+                .clicked()
+            {
+                picked = Some(address);
+            }
+        });
+    }
+    picked
+}
+
+fn lan_discovery(mut lan: ResMut<LanServers>, time: Res<Time>) {
+    lan.poll(time.elapsed());
+}
+
 /// Event issued when a multiplayer game has been selected. The corresponding game's client
 /// implementation should then pick up this event and
 #[derive(Deref)]
 pub struct ToGame(pub GameMarker);
 
+/// An error from the server which the game select menu shows until the player does something else
+#[derive(Resource)]
+pub struct GameSelectError(pub String);
+
+/// Present while the game that was entered is only being watched, rather than played
+#[derive(Resource)]
+pub struct Spectating;
+
+/// The order in which the game select menu lists games
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug, EnumIter)]
+enum GameOrder {
+    #[default]
+    Newest,
+    MostPlayers,
+    Gamemode,
+}
+
+impl GameOrder {
+    fn name(&self) -> &'static str {
+        match self {
+            GameOrder::Newest => "newest",
+            GameOrder::MostPlayers => "most players",
+            GameOrder::Gamemode => "gamemode",
+        }
+    }
+
+    fn sort(&self, games: &mut [&ActiveGame]) {
+        match self {
+            GameOrder::Newest => games.sort_by_key(|game| Reverse(game.id)),
+            GameOrder::MostPlayers => {
+                games.sort_by_key(|game| (Reverse(game.status.players), Reverse(game.id)))
+            }
+            GameOrder::Gamemode => games.sort_by_key(|game| {
+                let name = REGISTRY
+                    .get(&game.marker)
+                    .map(|descriptor| &descriptor.name);
+                (name, Reverse(game.id))
+            }),
+        }
+    }
+}
+
+/// How the game select menu lists the games on the server
+#[derive(Default)]
+struct GameListing {
+    order: GameOrder,
+    /// Whether games which cannot be joined are left out of the list
+    only_joinable: bool,
+}
+
+/// The game which the server last let this client into
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct JoinedGame {
+    pub id: u64,
+    /// Whether this client created the game, and so is the one to begin it
+    pub host: bool,
+}
+
 fn game_select_menu(
     mut commands: Commands,
     mut ctx: ResMut<EguiContext>,
-    mut games: Local<Vec<ActiveGame>>,
+    // whether a request to enter a game awaits an answer, and if so, whether it asked to watch
+    (mut games, mut listing, mut entering): (
+        Local<Vec<ActiveGame>>,
+        Local<GameListing>,
+        Local<Option<bool>>,
+    ),
     mut selected_gamemode: Local<(Option<String>, Option<GameMarker>)>,
-    mut socket: ResMut<Connection>,
+    mut settings: Local<Option<(GameMarker, Box<dyn GameSettings>)>>,
+    (mut socket, login, error): (
+        ResMut<Connection>,
+        Res<ServerLogin>,
+        Option<Res<GameSelectError>>,
+    ),
     mut start_game: EventWriter<ToGame>,
 ) {
     struct GameSelectResponse {
         go_back: egui::Response,
         reload: egui::Response,
-        create: Option<GameMarker>,
-        join_game: Option<(u64, GameMarker)>,
+        create: Option<(GameMarker, Vec<u8>)>,
+        join_game: Option<u64>,
+        spectate_game: Option<u64>,
     }
 
-    if let Some(Ok(ServerMessage::ActiveGames(v))) = socket.recv_message() {
-        *games = v;
+    while let Some(Ok(message)) = socket.recv_message() {
+        match message {
+            ServerMessage::ActiveGames(v) => *games = v,
+            ServerMessage::GamesChanged(changes) => {
+                for change in changes {
+                    match change {
+                        GameListChange::Updated(game) => {
+                            match games.iter_mut().find(|listed| listed.id == game.id) {
+                                Some(listed) => *listed = game,
+                                None => games.push(game),
+                            }
+                        }
+                        GameListChange::Removed(id) => games.retain(|game| game.id != id),
+                    }
+                }
+            }
+            ServerMessage::Joined {
+                game,
+                marker,
+                you_are_host,
+            } => {
+                if let Some(spectating) = entering.take() {
+                    commands.insert_resource(JoinedGame {
+                        id: game,
+                        host: you_are_host,
+                    });
+                    if spectating {
+                        commands.insert_resource(Spectating);
+                    } else {
+                        commands.remove_resource::<Spectating>();
+                    }
+                    start_game.send(ToGame(marker));
+                    commands.insert_resource(NextState(Menu::Ingame));
+                    // anything after this belongs to the game
+                    return;
+                }
+            }
+            ServerMessage::JoinRejected { message, .. } => {
+                *entering = None;
+                commands.insert_resource(GameSelectError(message))
+            }
+            ServerMessage::Error { message, .. } => {
+                commands.insert_resource(GameSelectError(message))
+            }
+            ServerMessage::ShuttingDown { seconds } => commands.insert_resource(GameSelectError(
+                format!("The server is shutting down in {seconds}s"),
+            )),
+            ServerMessage::Kicked(reason) => {
+                commands.insert_resource(GameSelectError(format!("Kicked by the server: {reason}")))
+            }
+            _ => (),
+        }
     }
 
     let response = standard_window(&mut ctx, |ui| {
@@ -225,19 +491,78 @@ fn game_select_menu(
             let reload = ui.button("reload🔁");
             ui.end_row();
 
+            if let Some(GameSelectError(error)) = error.as_deref() {
+                ui.label("");
+                ui.colored_label(Color32::RED, error);
+                ui.end_row();
+            }
+            if entering.is_some() {
+                ui.label("");
+                ui.label("Joining...");
+                ui.end_row();
+            }
+
+            egui::ComboBox::from_label("sort by")
+                .selected_text(listing.order.name())
+                .show_ui(ui, |ui| {
+                    for order in GameOrder::iter() {
+                        ui.selectable_value(&mut listing.order, order, order.name());
+                    }
+                });
+            ui.label("");
+            ui.checkbox(&mut listing.only_joinable, "joinable only");
+            ui.end_row();
+
+            // nothing more may be asked of the server until it has answered
+            ui.set_enabled(entering.is_none());
+
             let mut join_game = None;
-            for game in games.iter() {
+            let mut spectate_game = None;
+            let mut listed = games
+                .iter()
+                .filter(|game| !listing.only_joinable || game.status.access == Access::Open)
+                .collect::<Vec<_>>();
+            listing.order.sort(&mut listed);
+            for game in listed {
                 if let Some(descriptor) = REGISTRY.get(&game.marker) {
+                    let joinable = game.status.access == Access::Open;
+                    // games which cannot be joined are greyed out
+                    let text = |text: String| {
+                        let text = RichText::new(text);
+                        if joinable {
+                            text
+                        } else {
+                            text.weak()
+                        }
+                    };
                     ui.vertical(|ui| {
-                        ui.label(&descriptor.name);
-                        ui.label(&descriptor.description);
+                        ui.label(text(descriptor.name.clone()).strong());
+                        ui.label(text(format!("hosted by {}", game.host)));
+                        if let Some(settings) = (descriptor.describe_settings)(&game.settings) {
+                            ui.label(text(settings).small());
+                        }
                     });
 
-                    ui.label(""); // empty
+                    ui.vertical(|ui| {
+                        ui.label(text(format!(
+                            "{}/{} players",
+                            game.status.players, game.status.max_players
+                        )));
+                        ui.label(text(game.status.stage.clone()));
+                        ui.label(text(game.status.access.name().to_string()));
+                    });
 
-                    if ui.button("Join").clicked() {
-                        join_game = Some((game.id, game.marker))
-                    }
+                    ui.horizontal(|ui| {
+                        if ui
+                            .add_enabled(joinable, egui::Button::new("Join"))
+                            .clicked()
+                        {
+                            join_game = Some(game.id)
+                        }
+                        if ui.button("Watch").clicked() {
+                            spectate_game = Some(game.id)
+                        }
+                    });
 
                     ui.end_row();
                 } else {
@@ -253,7 +578,10 @@ fn game_select_menu(
                         .unwrap_or("<Choose gamemode>"),
                 )
                 .show_ui(ui, |ui| {
-                    for (&marker, descriptor) in REGISTRY.iter() {
+                    for (&marker, descriptor) in REGISTRY
+                        .iter()
+                        .filter(|(marker, _)| login.gamemodes.contains(marker))
+                    {
                         ui.selectable_value(
                             &mut *selected_gamemode,
                             (Some(descriptor.name.clone()), Some(marker)),
@@ -261,14 +589,40 @@ fn game_select_menu(
                         );
                     }
                 });
-            let create = ui.button("+create").clicked();
+            // the settings are replaced whenever a different gamemode is chosen
+            if let Some(marker) = selected_gamemode.1 {
+                if !matches!(&*settings, Some((current, _)) if *current == marker) {
+                    *settings = REGISTRY
+                        .get(&marker)
+                        .map(|descriptor| (marker, (descriptor.settings)()));
+                }
+            }
+            let checked = settings.as_ref().map(|(_, settings)| settings.check());
+            let create = ui
+                .add_enabled(
+                    matches!(checked, Some(Ok(()))),
+                    egui::Button::new("+create"),
+                )
+                .clicked();
             ui.end_row();
 
+            if let Some((_, settings)) = &mut *settings {
+                ui.collapsing("settings", |ui| settings.ui(ui));
+                if let Some(Err(e)) = checked {
+                    ui.colored_label(Color32::RED, e);
+                }
+                ui.end_row();
+            }
+
             GameSelectResponse {
                 go_back,
                 reload,
-                create: create.then_some(selected_gamemode.1).flatten(),
+                create: settings
+                    .as_ref()
+                    .filter(|_| create)
+                    .map(|(marker, settings)| (*marker, settings.encode())),
                 join_game,
+                spectate_game,
             }
         })
     })
@@ -277,21 +631,36 @@ fn game_select_menu(
     .unwrap()
     .inner;
 
+    let acted = response.go_back.clicked()
+        || response.reload.clicked()
+        || response.create.is_some()
+        || response.join_game.is_some()
+        || response.spectate_game.is_some();
+    if acted {
+        commands.remove_resource::<GameSelectError>();
+    }
+    // the game is only entered once the server answers with the game that was joined
     if response.go_back.clicked() {
+        *entering = None;
         commands.insert_resource(NextState(Menu::MainMenu));
     } else if response.reload.clicked() {
         socket.send_logged(ClientMessage::Games);
-    } else if let Some(mode) = response.create {
-        socket.send_logged(ClientMessage::Create {
-            game: mode,
-            args: Vec::new(),
-        });
-        start_game.send(ToGame(mode));
-        commands.insert_resource(NextState(Menu::Ingame));
-    } else if let Some((game, marker)) = response.join_game {
+    } else if let Some((mode, args)) = response.create {
+        socket.send_logged(ClientMessage::Create { game: mode, args });
+        *entering = Some(false);
+    } else if let Some(game) = response.join_game {
         socket.send_logged(ClientMessage::Join { game });
-        start_game.send(ToGame(marker));
-        commands.insert_resource(NextState(Menu::Ingame));
+        *entering = Some(false);
+    } else if let Some(game) = response.spectate_game {
+        socket.send_logged(ClientMessage::Spectate { game });
+        *entering = Some(true);
+    }
+}
+
+/// Asks the server to keep the game select menu up to date on the games it lists
+fn subscribe_games(socket: Option<ResMut<Connection>>) {
+    if let Some(mut socket) = socket {
+        socket.send_logged(ClientMessage::SubscribeGames);
     }
 }
 
@@ -340,7 +709,15 @@ impl Plugin for MainMenuPlugin {
             .add_system(poll_connection)
             .add_system(run_main_menu.run_in_state(Menu::MainMenu))
             .add_system(server_select_menu.run_in_state(Menu::ServerSelect))
+            .add_enter_system(Menu::ServerSelect, |mut commands: Commands| {
+                commands.insert_resource(LanServers::listen())
+            })
+            .add_system(lan_discovery.run_if_resource_exists::<LanServers>())
+            .add_exit_system(Menu::ServerSelect, |mut commands: Commands| {
+                commands.remove_resource::<LanServers>()
+            })
             .add_system(game_select_menu.run_in_state(Menu::GameSelect))
+            .add_enter_system(Menu::GameSelect, subscribe_games)
             .add_system(pause)
             .add_enter_system(Menu::MainMenu, |mut commands: Commands| {
                 commands.remove_resource::<Connection>()
@@ -0,0 +1,78 @@
+use std::fmt::Display;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use strum::IntoEnumIterator;
+use strum_macros::{Display, EnumIter};
+
+/// How many mines a field is filled with
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum MineCount {
+    /// The fraction of tiles on the field which contain a mine
+    Density(f32),
+    /// An exact number of mines, capped to the number of tiles on the field
+    Exact(usize),
+}
+
+impl MineCount {
+    /// The number of mines to place on a field with the given amount of cells
+    pub fn of(&self, amnt_cells: usize) -> usize {
+        match *self {
+            MineCount::Density(density) => {
+                ((amnt_cells as f32 * density).round() as usize).min(amnt_cells)
+            }
+            MineCount::Exact(count) => count.min(amnt_cells),
+        }
+    }
+}
+
+impl Display for MineCount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (Difficulty::of(*self), self) {
+            (Some(difficulty), _) => write!(f, "{difficulty}"),
+            (None, MineCount::Density(density)) => write!(f, "{:.0}% mines", density * 100.0),
+            (None, MineCount::Exact(count)) => write!(f, "{count} mines"),
+        }
+    }
+}
+
+/// Preset mine densities, loosely following those of classic minesweeper
+#[derive(
+    Resource,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    EnumIter,
+    Display,
+    Default,
+)]
+pub enum Difficulty {
+    Beginner,
+    Intermediate,
+    #[default]
+    Expert,
+}
+
+impl From<Difficulty> for MineCount {
+    fn from(difficulty: Difficulty) -> Self {
+        MineCount::Density(match difficulty {
+            Difficulty::Beginner => 0.12,
+            Difficulty::Intermediate => 0.16,
+            Difficulty::Expert => 0.2,
+        })
+    }
+}
+
+impl Difficulty {
+    /// Finds the preset that a mine count was made from, if any
+    pub fn of(mines: MineCount) -> Option<Self> {
+        Self::iter().find(|&difficulty| MineCount::from(difficulty) == mines)
+    }
+}
@@ -6,16 +6,19 @@ use gridly::{
     vector::{Columns, Rows},
 };
 use gridly_grids::SparseGrid;
+use itertools::Itertools;
 use rand::{seq::IteratorRandom, Rng};
 
 use crate::common::{Contains, Position};
 use std::ops::{Deref, DerefMut};
 
-use super::FieldShape;
+use super::{FieldShape, MineCount};
 
 #[derive(Component)]
 pub struct Minefield<T: Clone + PartialEq> {
     pub field: SparseGrid<Option<T>>,
+    /// The number of mines which are placed on the field
+    pub mines: usize,
     pub remaining_blank: usize,
 }
 
@@ -33,14 +36,12 @@ impl<T: Clone + PartialEq> DerefMut for Minefield<T> {
     }
 }
 
-/// Converts from the total amount of cells there are on the board to the amount of cells without
-/// mines there should be on the board
-fn field_density(val: usize) -> usize {
-    val * 8 / 10
-}
-
 impl<T: Clone + PartialEq> Minefield<T> {
-    pub fn new_shaped<F>(mut make_entity: F, template: &FieldShape) -> Self
+    pub fn new_shaped<F>(
+        mut make_entity: F,
+        template: &FieldShape,
+        mines: impl Into<MineCount>,
+    ) -> Self
     where
         F: FnMut(&Position) -> T,
     {
@@ -51,24 +52,32 @@ impl<T: Clone + PartialEq> Minefield<T> {
         }
 
         let amnt_cells = field.occupied_entries().count();
+        let mines = mines.into().of(amnt_cells);
 
         Self {
-            remaining_blank: field_density(amnt_cells),
+            mines,
+            remaining_blank: amnt_cells - mines,
             field,
         }
     }
 
+    /// Forgets which tiles have been revealed, so that the field can be played again
+    pub fn reset_blank(&mut self) {
+        self.remaining_blank = self.occupied_entries().count() - self.mines;
+    }
+
     pub fn choose_multiple(
         &self,
         exclude: &impl Contains<Position>,
         rng: &mut impl Rng,
     ) -> impl IntoIterator<Item = (&Location, T)> {
-        let num_entries = self.field.occupied_entries().count();
-
         self.occupied_entries()
             .filter_map(|(a, b)| b.clone().map(|b| (a, b)))
             .filter(|&(&pos, _)| !exclude.contains(&pos.into()))
-            .choose_multiple(rng, num_entries - self.remaining_blank)
+            // the grid is stored in a hash map, so the tiles are put in a fixed order to make sure
+            // that the same random number generator always chooses the same tiles
+            .sorted_by_key(|&(&pos, _)| Position::from(pos))
+            .choose_multiple(rng, self.mines)
     }
 
     pub fn iter_positions(&self) -> impl Iterator<Item = Position> + '_ {
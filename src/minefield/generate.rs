@@ -0,0 +1,455 @@
+//! Placement of mines once the first tiles of a field have been revealed
+
+use std::collections::HashSet;
+
+use bevy::prelude::*;
+use itertools::Itertools;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use crate::common::Position;
+
+use super::solver::{Solver, Visible};
+
+/// How mines are laid out on a field
+#[derive(Resource, Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Generation {
+    /// Mines are scattered uniformly at random, which may leave the player with guesses to make
+    #[default]
+    Random,
+    /// Mines are moved around until the whole field can be solved by deduction alone
+    NoGuess,
+}
+
+/// The seed that the next singleplayer game is generated from. If none is given, a random one is
+/// picked when the game begins.
+#[derive(Resource, Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Seed(pub Option<u64>);
+
+/// The random number generator behind every random decision in a singleplayer game, which keeps
+/// the seed it was made from so that it can be shown to the player. The first board of a game is
+/// decided by the seed and the first tile revealed, and each retry after that continues from where
+/// the previous board left the generator.
+#[derive(Resource)]
+pub struct SeededRng {
+    pub seed: u64,
+    pub rng: StdRng,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+pub struct Layout {
+    pub mines: HashSet<Position>,
+    /// Tiles which must be revealed to the player alongside the first click. These are only needed
+    /// when some part of the field cannot be reached by deduction at all, such as an island which
+    /// is disconnected from the rest of the field, or when the field holds too many mines to be
+    /// solved by deduction alone.
+    pub openings: Vec<Position>,
+}
+
+/// Plays through a field by deduction alone, as a player who never guesses would
+struct Simulation<'a> {
+    solver: Solver,
+    mines: &'a mut HashSet<Position>,
+}
+
+impl<'a> Simulation<'a> {
+    /// Begins playing the field from the given revealed tiles
+    fn new(
+        cells: &[Position],
+        revealed: impl IntoIterator<Item = Position>,
+        mines: &'a mut HashSet<Position>,
+    ) -> Self {
+        let mut sim = Self {
+            solver: Solver::new(cells.iter().map(|&cell| (cell, Visible::Hidden))),
+            mines,
+        };
+        for position in revealed {
+            sim.reveal(position);
+        }
+        sim
+    }
+
+    fn number(&self, position: Position) -> u8 {
+        position
+            .neighbors()
+            .iter()
+            .filter(|neighbor| self.mines.contains(neighbor))
+            .count() as u8
+    }
+
+    /// Reveals a safe tile, along with everything around it if it has no neighboring mines
+    fn reveal(&mut self, position: Position) {
+        let mut queue = vec![position];
+        while let Some(position) = queue.pop() {
+            if !matches!(self.solver.get(position), Some(Visible::Hidden)) {
+                continue;
+            }
+            let number = self.number(position);
+            self.solver.set(position, Visible::Revealed(number));
+            if number == 0 {
+                queue.extend(position.neighbors());
+            }
+        }
+    }
+
+    fn is_hidden(&self, position: Position) -> bool {
+        matches!(self.solver.get(position), Some(Visible::Hidden))
+    }
+
+    fn is_revealed(&self, position: Position) -> bool {
+        matches!(self.solver.get(position), Some(Visible::Revealed(_)))
+    }
+
+    /// Deduces as much of the field as possible, getting past places where deduction is stuck as
+    /// `unstick` says to
+    fn solve(&mut self, cells: &[Position], unstick: &mut Unstick, rng: &mut impl Rng) -> Outcome {
+        let mut outcome = Outcome::Solved;
+        loop {
+            let found = self.solver.deduce();
+            if !found.safe.is_empty() {
+                for position in found.safe {
+                    self.reveal(position);
+                }
+                continue;
+            }
+
+            let hidden_safe = cells
+                .iter()
+                .copied()
+                .filter(|&cell| self.is_hidden(cell) && !self.mines.contains(&cell))
+                .collect_vec();
+            if hidden_safe.is_empty() {
+                return outcome;
+            }
+
+            match unstick {
+                Unstick::Repair { clear } => {
+                    // only a number with mines hidden around it can be satisfied by moving them
+                    let stuck = cells
+                        .iter()
+                        .copied()
+                        .filter(|&cell| {
+                            self.is_revealed(cell)
+                                && cell.neighbors().iter().any(|&neighbor| {
+                                    self.is_hidden(neighbor) && self.mines.contains(&neighbor)
+                                })
+                        })
+                        .collect_vec();
+                    let Some(&stuck) = stuck.choose(rng) else {
+                        return Outcome::Stuck;
+                    };
+                    match self.repair(stuck, cells, clear, rng) {
+                        Repair::Hidden => outcome = Outcome::Repaired,
+                        // what has been revealed no longer matches the mines
+                        Repair::Revealed => return Outcome::Repaired,
+                        Repair::NoRoom => return Outcome::Stuck,
+                    }
+                }
+                Unstick::Open(openings) => {
+                    let opening = *hidden_safe.choose(rng).unwrap();
+                    openings.push(opening);
+                    self.reveal(opening);
+                }
+            }
+        }
+    }
+
+    /// Moves mines away from the hidden neighbors of a revealed tile, so that its number becomes
+    /// satisfied by the mines already known around it. The mines are moved to tiles which no
+    /// revealed number can see, so that nothing the player has deduced so far is contradicted.
+    /// Near the end of a field every hidden tile is seen by some number, and the mines are moved
+    /// into its empty revealed stretches instead, outside of `clear`.
+    fn repair(
+        &mut self,
+        stuck: Position,
+        cells: &[Position],
+        clear: &HashSet<Position>,
+        rng: &mut impl Rng,
+    ) -> Repair {
+        let displaced = stuck
+            .neighbors()
+            .into_iter()
+            .filter(|&neighbor| self.is_hidden(neighbor) && self.mines.contains(&neighbor))
+            .collect_vec();
+        let room = cells
+            .iter()
+            .copied()
+            .filter(|&cell| {
+                self.is_hidden(cell)
+                    && !self.mines.contains(&cell)
+                    && !cell
+                        .neighbors()
+                        .iter()
+                        .any(|&neighbor| self.is_revealed(neighbor))
+            })
+            .collect_vec();
+        if room.len() < displaced.len() {
+            let room = cells
+                .iter()
+                .copied()
+                .filter(|&cell| {
+                    matches!(self.solver.get(cell), Some(Visible::Revealed(0)))
+                        && !clear.contains(&cell)
+                })
+                .collect_vec();
+            if room.len() < displaced.len() {
+                return Repair::NoRoom;
+            }
+            self.move_mines(&displaced, &room, rng);
+            return Repair::Revealed;
+        }
+
+        self.move_mines(&displaced, &room, rng);
+        // the numbers around the old mines have changed, but the numbers around the new ones have
+        // not, since none of them have been revealed
+        for position in displaced.iter().flat_map(|mine| mine.neighbors()) {
+            if self.is_revealed(position) {
+                let number = self.number(position);
+                self.solver.set(position, Visible::Revealed(number));
+            }
+        }
+        Repair::Hidden
+    }
+
+    fn move_mines(&mut self, from: &[Position], room: &[Position], rng: &mut impl Rng) {
+        for (&from, &to) in from.iter().zip(room.choose_multiple(rng, from.len())) {
+            self.mines.remove(&from);
+            self.mines.insert(to);
+        }
+    }
+}
+
+/// What a [Simulation] does when deduction gets stuck
+enum Unstick<'a> {
+    /// Moves mines out of the way, giving up if there is no room for them. Mines are never moved
+    /// onto the `clear` tiles.
+    Repair { clear: &'a HashSet<Position> },
+    /// Reveals a safe tile, which is added to the openings
+    Open(&'a mut Vec<Position>),
+}
+
+/// Where [Simulation::repair] moved the mines which were in the way
+enum Repair {
+    /// To hidden tiles which no number sees, so the simulation can go on
+    Hidden,
+    /// To tiles which have already been revealed, so the field must be played again
+    Revealed,
+    /// Nowhere, since there was no room for them
+    NoRoom,
+}
+
+/// How far a [Simulation] got through the field
+enum Outcome {
+    /// The whole field was deduced without anything being changed
+    Solved,
+    /// Mines were moved to get past where deduction was stuck. Moving mines changes numbers which
+    /// earlier deductions may have relied on, so the field must be played again to be sure.
+    Repaired,
+    /// Deduction got stuck, and mines could not be moved out of the way
+    Stuck,
+}
+
+/// How many layouts are tried before giving up on one that can be solved from the start alone
+const MAX_ATTEMPTS: usize = 8;
+/// How many times a layout is played through and repaired before a fresh one is tried instead
+const MAX_PASSES: usize = 6;
+
+/// Picks a tile of every part of the field which deduction cannot reach from the start, such as an
+/// island which is disconnected from the rest of the field
+fn isolated_openings(cells: &[Position], start: &[Position], rng: &mut impl Rng) -> Vec<Position> {
+    let mut unreached: HashSet<Position> = cells.iter().copied().collect();
+    let flood = |from: Position, unreached: &mut HashSet<Position>| {
+        let mut region = Vec::new();
+        let mut queue = vec![from];
+        while let Some(position) = queue.pop() {
+            if unreached.remove(&position) {
+                region.push(position);
+                queue.extend(position.neighbors());
+            }
+        }
+        region
+    };
+    for &position in start {
+        flood(position, &mut unreached);
+    }
+    // cells are sorted, so the regions are always found in the same order
+    cells
+        .iter()
+        .filter_map(|&cell| {
+            let region = flood(cell, &mut unreached);
+            region.choose(rng).copied()
+        })
+        .collect()
+}
+
+/// Lays out mines on the given cells so that, starting from the revealed `start` tiles, the whole
+/// field can be solved without guessing. Layouts are repaired for a bounded number of passes, and
+/// replaced with fresh ones when repairs fail.
+///
+/// The returned openings are tiles which the game reveals for the players besides `start`. They
+/// are always given for regions of the field which deduction cannot reach at all, such as islands,
+/// so a field in one piece normally has none. Only a field too crowded for any layout to be solved
+/// within the attempts falls back on giving openings wherever deduction gets stuck, which keeps
+/// the field solvable at the cost of revealing more of it. Either way, the mines number
+/// `mine_count` unless the field has too few cells for them.
+pub fn no_guess_layout(
+    cells: &[Position],
+    start: &[Position],
+    mine_count: usize,
+    rng: &mut impl Rng,
+) -> Layout {
+    // sorted so that the same random number generator always produces the same layout
+    let cells = cells.iter().copied().sorted().collect_vec();
+    let mut openings = isolated_openings(&cells, start, rng);
+    // openings are kept clear all around, so that each one opens up its region
+    let clear: HashSet<Position> = start
+        .iter()
+        .copied()
+        .chain(openings.iter().flat_map(|opening| opening.local_group()))
+        .collect();
+    let candidates = cells
+        .iter()
+        .copied()
+        .filter(|cell| !clear.contains(cell))
+        .collect_vec();
+
+    let mut mines = HashSet::new();
+    for _ in 0..MAX_ATTEMPTS {
+        mines = candidates
+            .choose_multiple(rng, mine_count.min(candidates.len()))
+            .copied()
+            .collect();
+        for _ in 0..MAX_PASSES {
+            let revealed = start.iter().chain(&openings).copied();
+            match Simulation::new(&cells, revealed, &mut mines).solve(
+                &cells,
+                &mut Unstick::Repair { clear: &clear },
+                rng,
+            ) {
+                Outcome::Solved => return Layout { mines, openings },
+                Outcome::Repaired => (),
+                Outcome::Stuck => break,
+            }
+        }
+    }
+
+    log::debug!("No layout could be solved without guessing, so openings are added instead");
+    let revealed = start.iter().chain(&openings).copied().collect_vec();
+    Simulation::new(&cells, revealed, &mut mines).solve(
+        &cells,
+        &mut Unstick::Open(&mut openings),
+        rng,
+    );
+    Layout { mines, openings }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use itertools::Itertools;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{
+        common::Position,
+        minefield::{
+            solver::{Solver, Visible},
+            FieldShape,
+        },
+    };
+
+    use super::{no_guess_layout, Layout};
+
+    /// Checks that revealing the start and the openings lets deduction clear the whole field
+    fn assert_solvable(cells: &[Position], start: &[Position], layout: &Layout) {
+        let mut solver = Solver::new(cells.iter().map(|&cell| (cell, Visible::Hidden)));
+        let number = |position: Position| {
+            position
+                .neighbors()
+                .iter()
+                .filter(|neighbor| layout.mines.contains(neighbor))
+                .count() as u8
+        };
+        let mut queue = start.iter().chain(&layout.openings).copied().collect_vec();
+        loop {
+            while let Some(position) = queue.pop() {
+                assert!(!layout.mines.contains(&position));
+                if let Some(Visible::Hidden) = solver.get(position) {
+                    solver.set(position, Visible::Revealed(number(position)));
+                    if number(position) == 0 {
+                        queue.extend(position.neighbors());
+                    }
+                }
+            }
+            let found = solver.deduce();
+            if found.safe.is_empty() {
+                break;
+            }
+            queue.extend(found.safe);
+        }
+
+        for &cell in cells {
+            if !layout.mines.contains(&cell) {
+                assert!(matches!(solver.get(cell), Some(Visible::Revealed(_))));
+            }
+        }
+    }
+
+    #[test]
+    fn circle_is_solvable_in_time() {
+        let bytes = std::fs::read("assets/fields/circle_100_100.field").unwrap();
+        let shape = FieldShape::try_from(bytes.as_slice()).unwrap();
+        let cells = shape.decode().collect_vec();
+        let start = shape.center().unwrap().local_group().to_vec();
+        let mine_count = cells.len() / 5;
+
+        for seed in 0..4 {
+            let began = Instant::now();
+            let layout =
+                no_guess_layout(&cells, &start, mine_count, &mut StdRng::seed_from_u64(seed));
+            assert!(began.elapsed() < Duration::from_secs(30));
+
+            assert_eq!(layout.mines.len(), mine_count);
+            // the first click is all it takes on a field in one piece, without falling back on
+            // openings
+            assert!(layout.openings.is_empty(), "seed {seed}");
+            assert_solvable(&cells, &start, &layout);
+        }
+    }
+
+    #[test]
+    fn first_click_is_enough() {
+        let shape = FieldShape::try_from(vec!["x".repeat(30); 16].join("\n").as_bytes()).unwrap();
+        let cells = shape.decode().collect_vec();
+        let start = Position::new(3, 3).local_group().to_vec();
+        for seed in 0..8 {
+            let layout = no_guess_layout(&cells, &start, 99, &mut StdRng::seed_from_u64(seed));
+            assert_eq!(layout.mines.len(), 99);
+            assert!(layout.openings.is_empty());
+            assert_solvable(&cells, &start, &layout);
+        }
+    }
+
+    #[test]
+    fn islands_are_opened() {
+        let shape =
+            FieldShape::try_from("xxxxxx   xxxxx\nxxxxxx   xxxxx\nxxxxxx   xxxxx".as_bytes())
+                .unwrap();
+        let cells = shape.decode().collect_vec();
+        let start = Position::new(1, 1).local_group().to_vec();
+
+        let layout = no_guess_layout(&cells, &start, 6, &mut StdRng::seed_from_u64(0));
+        assert_eq!(layout.mines.len(), 6);
+        // only the island is opened
+        assert_eq!(layout.openings.len(), 1);
+        assert!(layout.openings[0].x >= 9);
+        assert_solvable(&cells, &start, &layout);
+    }
+}
@@ -1,11 +1,15 @@
 use bevy::prelude::*;
 
+mod difficulty;
 mod field;
+pub mod generate;
 mod load;
 pub mod query;
+pub mod solver;
 pub mod specific;
 pub mod systems;
 
+pub use difficulty::{Difficulty, MineCount};
 pub use field::*;
 pub use load::FieldShape;
 
@@ -19,6 +23,7 @@ pub struct MinefieldPlugin;
 impl Plugin for MinefieldPlugin {
     fn build(&self, app: &mut App) {
         app.add_asset::<load::FieldShape>()
+            .init_resource::<generate::Generation>()
             .add_asset_loader(load::FieldLoader)
             .add_event::<GameOutcome>();
     }
@@ -11,9 +11,8 @@ use bevy::{
 };
 use gridly::prelude::*;
 use ouroboros::self_referencing;
-use rand::Rng;
 
-use crate::common::{Contains, Position};
+use crate::common::Position;
 
 use super::Minefield;
 
@@ -32,24 +31,6 @@ where
     'world: 'all,
     'state: 'all,
 {
-    pub fn get(
-        &'all mut self,
-        entity: Entity,
-    ) -> Option<AdjoinedMinefield<'all, 'world, 'state, Tile>> {
-        self.minefield_query
-            .contains(entity)
-            .then_some(AdjoinedMinefield {
-                tile_query: &mut self.tile_query,
-                minefield: BorrowedMinefieldBuilder {
-                    minefield_query: &self.minefield_query,
-                    minefield_builder: |query: &&Query<&Minefield<Entity>>| {
-                        query.get(entity).unwrap()
-                    },
-                }
-                .build(),
-            })
-    }
-
     pub fn get_single(&'all mut self) -> Option<AdjoinedMinefield<'all, 'world, 'state, Tile>> {
         self.minefield_query
             .get_single()
@@ -101,22 +82,6 @@ where
     'state: 'all,
     'all: 'query,
 {
-    pub fn choose_multiple(
-        &'query mut self,
-        exclude: &'all impl Contains<Position>,
-        rng: &'all mut impl Rng,
-        mut op: impl for<'every> FnMut(Position, QueryItem<'every, Tile>),
-    ) {
-        self.minefield
-            .choose_multiple(exclude, rng)
-            .into_iter()
-            .map(|(loc, u)| (Position::from(*loc), u))
-            .for_each(|(pos, tile_id)| {
-                let tile = self.tile_query.get_mut(tile_id).unwrap();
-                op(pos, tile)
-            });
-    }
-
     pub fn neighbors(
         &self,
         position: Position,
@@ -129,10 +94,6 @@ where
             })
     }
 
-    pub fn neighbor_positions(&self, position: Position) -> impl Iterator<Item = Position> + '_ {
-        self.minefield.iter_neighbor_positions(position)
-    }
-
     pub fn neighbor_cells(&self, position: Position) -> impl Iterator<Item = ROQueryItem<Tile>> {
         self.neighbors(position).map(|(_, tile)| tile)
     }
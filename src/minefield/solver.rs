@@ -0,0 +1,476 @@
+//! Deduces the contents of hidden tiles from the numbers shown on revealed ones, the same way a
+//! player would. Each revealed number becomes a constraint on the hidden tiles around it, and the
+//! solver applies two kinds of reasoning to those constraints:
+//!
+//! * single-point: a number which is already satisfied by known mines makes the rest of its tiles
+//!   safe, and a number which needs every one of its tiles makes all of them mines
+//! * linked: two overlapping numbers are compared, and if the difference between them can only be
+//!   explained by one side being entirely mines, the other side must be entirely safe
+//!
+//! When nothing more can be deduced, the solver can still estimate how likely each hidden tile is
+//! to contain a mine, which is enough to give the player a hint or to drive a bot.
+
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+
+use bevy::prelude::*;
+use itertools::Itertools;
+
+use crate::common::Position;
+
+use super::{specific::MineCellState, Minefield};
+
+/// Groups of linked hidden tiles larger than this have their probabilities estimated from each
+/// number separately, rather than by counting every possible arrangement of their mines
+const MAX_ENUMERATED: usize = 20;
+
+/// What can be seen of a single tile
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Visible {
+    /// Nothing is known about the tile
+    Hidden,
+    /// The tile is known to contain a mine
+    Mine,
+    /// The tile has been revealed, showing the number of mines around it
+    Revealed(u8),
+    /// The tile is known not to contain a mine, but the number of mines around it is not known
+    Clear,
+}
+
+impl From<&MineCellState> for Visible {
+    fn from(state: &MineCellState) -> Self {
+        match state {
+            MineCellState::Revealed(number) => Visible::Revealed(*number),
+            // flags are only the player's guesses
+            _ => Visible::Hidden,
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct Deductions {
+    /// Hidden tiles which are certain not to contain a mine
+    pub safe: BTreeSet<Position>,
+    /// Hidden tiles which are certain to contain a mine
+    pub mines: BTreeSet<Position>,
+}
+
+impl Deductions {
+    pub fn is_empty(&self) -> bool {
+        self.safe.is_empty() && self.mines.is_empty()
+    }
+}
+
+/// A revealed number, reduced to the undecided tiles around it and how many of those contain mines
+#[derive(Debug)]
+struct Constraint {
+    tiles: BTreeSet<Position>,
+    mines: usize,
+}
+
+#[derive(Clone, Default)]
+pub struct Solver {
+    tiles: HashMap<Position, Visible>,
+    /// Hidden tiles which have been deduced to be safe, but whose numbers are not yet known
+    safe: BTreeSet<Position>,
+}
+
+impl Solver {
+    pub fn new(tiles: impl IntoIterator<Item = (Position, Visible)>) -> Self {
+        Self {
+            tiles: tiles.into_iter().collect(),
+            safe: BTreeSet::new(),
+        }
+    }
+
+    /// Reads what can be seen of a field whose tiles are entities. `visible` looks up what the
+    /// tile with the given entity shows.
+    pub fn read(field: &Minefield<Entity>, visible: impl Fn(Entity) -> Option<Visible>) -> Self {
+        Self::new(
+            field.occupied_entries().filter_map(|(&location, tile)| {
+                Some((Position::from(location), visible((*tile)?)?))
+            }),
+        )
+    }
+
+    pub fn get(&self, position: Position) -> Option<Visible> {
+        self.tiles.get(&position).copied()
+    }
+
+    pub fn set(&mut self, position: Position, tile: Visible) {
+        if !matches!(tile, Visible::Hidden) {
+            self.safe.remove(&position);
+        }
+        self.tiles.insert(position, tile);
+    }
+
+    /// Hidden tiles next to the given one which have not been decided either way
+    fn undecided_neighbors(&self, position: Position) -> impl Iterator<Item = Position> + '_ {
+        position.neighbors().into_iter().filter(|neighbor| {
+            matches!(self.tiles.get(neighbor), Some(Visible::Hidden))
+                && !self.safe.contains(neighbor)
+        })
+    }
+
+    fn constraint(&self, position: Position) -> Option<Constraint> {
+        let Some(Visible::Revealed(number)) = self.get(position) else {
+            return None;
+        };
+        let tiles: BTreeSet<_> = self.undecided_neighbors(position).collect();
+        if tiles.is_empty() {
+            return None;
+        }
+        let known_mines = position
+            .neighbors()
+            .into_iter()
+            .filter(|neighbor| matches!(self.tiles.get(neighbor), Some(Visible::Mine)))
+            .count();
+        Some(Constraint {
+            tiles,
+            mines: (number as usize).saturating_sub(known_mines),
+        })
+    }
+
+    /// Every constraint on the field, keyed by the revealed tile it comes from
+    fn constraints(&self) -> HashMap<Position, Constraint> {
+        self.tiles
+            .keys()
+            .filter_map(|&position| Some((position, self.constraint(position)?)))
+            .collect()
+    }
+
+    /// Finds every hidden tile whose contents follow from the revealed numbers. Tiles found to be
+    /// mines are marked as such within the solver, so that further deductions can build on them.
+    /// Tiles found to be safe are remembered until they are revealed.
+    pub fn deduce(&mut self) -> Deductions {
+        let mut found = Deductions::default();
+
+        loop {
+            let constraints = self.constraints();
+            let mut round = Deductions::default();
+
+            for constraint in constraints.values() {
+                if constraint.mines == 0 {
+                    round.safe.extend(&constraint.tiles);
+                } else if constraint.mines == constraint.tiles.len() {
+                    round.mines.extend(&constraint.tiles);
+                }
+            }
+
+            if round.is_empty() {
+                for (a, b) in linked_pairs(&constraints) {
+                    let only_a = a.tiles.difference(&b.tiles).copied().collect_vec();
+                    let only_b = b.tiles.difference(&a.tiles).copied().collect_vec();
+                    let difference = b.mines as isize - a.mines as isize;
+
+                    if difference == only_b.len() as isize {
+                        round.mines.extend(only_b);
+                        round.safe.extend(only_a);
+                    } else if -difference == only_a.len() as isize {
+                        round.mines.extend(only_a);
+                        round.safe.extend(only_b);
+                    }
+                }
+            }
+
+            if round.is_empty() {
+                break;
+            }
+            for &mine in &round.mines {
+                self.tiles.insert(mine, Visible::Mine);
+            }
+            self.safe.extend(&round.safe);
+            found.safe.extend(round.safe);
+            found.mines.extend(round.mines);
+        }
+
+        found
+    }
+
+    /// The chance of each hidden tile containing a mine. Tiles next to revealed numbers are given
+    /// the fraction of arrangements consistent with those numbers in which they hold a mine. The
+    /// rest of the hidden tiles share whatever mines are left over, which can only be worked out
+    /// if the total number of mines on the field is known.
+    pub fn probabilities(&self, total_mines: Option<usize>) -> HashMap<Position, f32> {
+        let mut solver = self.clone();
+        solver.deduce();
+
+        let mut probabilities: HashMap<_, _> =
+            solver.safe.iter().map(|&tile| (tile, 0.0)).collect();
+        let known_mines = solver
+            .tiles
+            .iter()
+            .filter(|(_, tile)| matches!(tile, Visible::Mine))
+            .map(|(&position, _)| position)
+            .collect_vec();
+        probabilities.extend(known_mines.iter().map(|&mine| (mine, 1.0)));
+
+        let constraints = solver.constraints().into_values().collect_vec();
+        for component in components(&constraints) {
+            let tiles = component
+                .iter()
+                .flat_map(|&i| &constraints[i].tiles)
+                .copied()
+                .unique()
+                .collect_vec();
+            let linked = component.iter().map(|&i| &constraints[i]).collect_vec();
+            if tiles.len() <= MAX_ENUMERATED {
+                probabilities.extend(enumerate(&tiles, &linked));
+            } else {
+                probabilities.extend(tiles.iter().map(|&tile| {
+                    let (sum, count) = linked
+                        .iter()
+                        .filter(|constraint| constraint.tiles.contains(&tile))
+                        .fold((0.0, 0), |(sum, count), constraint| {
+                            let local = constraint.mines as f32 / constraint.tiles.len() as f32;
+                            (sum + local, count + 1)
+                        });
+                    (tile, sum / count as f32)
+                }));
+            }
+        }
+
+        if let Some(total_mines) = total_mines {
+            let interior = solver
+                .tiles
+                .iter()
+                .filter(|(position, tile)| {
+                    matches!(tile, Visible::Hidden) && !probabilities.contains_key(position)
+                })
+                .map(|(&position, _)| position)
+                .collect_vec();
+            let expected: f32 = probabilities.values().sum();
+            let leftover = (total_mines as f32 - expected).max(0.0);
+            let chance = (leftover / interior.len() as f32).min(1.0);
+            probabilities.extend(interior.into_iter().map(|tile| (tile, chance)));
+        }
+
+        probabilities
+    }
+
+    /// Picks the hidden tile the player should reveal next: the closest tile to `near` which is
+    /// certain to be safe, or otherwise the tile least likely to contain a mine. The chance of the
+    /// tile containing a mine is given along with it.
+    pub fn hint(&self, near: Position, total_mines: Option<usize>) -> Option<(Position, f32)> {
+        self.probabilities(total_mines)
+            .into_iter()
+            .filter(|&(_, chance)| chance < 1.0)
+            .min_by(|&(a, chance_a), &(b, chance_b)| {
+                chance_a
+                    .total_cmp(&chance_b)
+                    .then_with(|| near.distance(&a).total_cmp(&near.distance(&b)))
+                    .then_with(|| a.cmp(&b))
+            })
+    }
+}
+
+/// Pairs of constraints whose tiles overlap. Each pair is only given once.
+fn linked_pairs(
+    constraints: &HashMap<Position, Constraint>,
+) -> impl Iterator<Item = (&Constraint, &Constraint)> {
+    constraints.iter().flat_map(move |(&position, a)| {
+        // constraints can only share tiles if they are at most two tiles apart
+        (-2..=2)
+            .cartesian_product(-2..=2)
+            .map(move |(x, y)| position + Position::new(x, y))
+            .filter(move |other| position < *other)
+            .filter_map(|other| constraints.get(&other))
+            .filter(move |b| !a.tiles.is_disjoint(&b.tiles))
+            .map(move |b| (a, b))
+    })
+}
+
+/// Splits constraints into groups which share hidden tiles, given as indices into `constraints`
+fn components(constraints: &[Constraint]) -> Vec<Vec<usize>> {
+    let mut by_tile: HashMap<Position, Vec<usize>> = HashMap::new();
+    for (i, constraint) in constraints.iter().enumerate() {
+        for &tile in &constraint.tiles {
+            by_tile.entry(tile).or_default().push(i);
+        }
+    }
+
+    let mut visited = HashSet::new();
+    let mut components = Vec::new();
+    for start in 0..constraints.len() {
+        if !visited.insert(start) {
+            continue;
+        }
+        let mut component = vec![start];
+        let mut queue = VecDeque::from([start]);
+        while let Some(i) = queue.pop_front() {
+            for tile in &constraints[i].tiles {
+                for &linked in &by_tile[tile] {
+                    if visited.insert(linked) {
+                        component.push(linked);
+                        queue.push_back(linked);
+                    }
+                }
+            }
+        }
+        components.push(component);
+    }
+    components
+}
+
+/// Counts every arrangement of mines on `tiles` which satisfies the constraints, giving each tile
+/// the fraction of arrangements in which it holds a mine
+fn enumerate(tiles: &[Position], constraints: &[&Constraint]) -> Vec<(Position, f32)> {
+    /// A constraint as the indices of its tiles, along with how many mines are still to be placed
+    /// on them and how many of them are still to be decided
+    struct Remaining {
+        mines: usize,
+        open: usize,
+    }
+
+    struct Search<'a> {
+        /// For each tile, the constraints it belongs to
+        touching: Vec<Vec<usize>>,
+        remaining: Vec<Remaining>,
+        mines: Vec<bool>,
+        counts: &'a mut [u64],
+        solutions: u64,
+    }
+
+    impl Search<'_> {
+        fn run(&mut self, next: usize) {
+            if next == self.mines.len() {
+                self.solutions += 1;
+                for (count, &mine) in self.counts.iter_mut().zip(&self.mines) {
+                    *count += mine as u64;
+                }
+                return;
+            }
+            for mine in [false, true] {
+                let satisfiable = self.touching[next].iter().all(|&i| {
+                    let remaining = &self.remaining[i];
+                    if mine {
+                        remaining.mines > 0
+                    } else {
+                        remaining.open > remaining.mines
+                    }
+                });
+                if !satisfiable {
+                    continue;
+                }
+                for &i in &self.touching[next] {
+                    self.remaining[i].open -= 1;
+                    self.remaining[i].mines -= mine as usize;
+                }
+                self.mines[next] = mine;
+                self.run(next + 1);
+                for &i in &self.touching[next] {
+                    self.remaining[i].open += 1;
+                    self.remaining[i].mines += mine as usize;
+                }
+            }
+            self.mines[next] = false;
+        }
+    }
+
+    let mut counts = vec![0; tiles.len()];
+    let mut search = Search {
+        touching: tiles
+            .iter()
+            .map(|tile| {
+                (0..constraints.len())
+                    .filter(|&i| constraints[i].tiles.contains(tile))
+                    .collect()
+            })
+            .collect(),
+        remaining: constraints
+            .iter()
+            .map(|constraint| Remaining {
+                mines: constraint.mines,
+                open: constraint.tiles.len(),
+            })
+            .collect(),
+        mines: vec![false; tiles.len()],
+        counts: &mut counts,
+        solutions: 0,
+    };
+    search.run(0);
+    let solutions = search.solutions.max(1);
+
+    tiles
+        .iter()
+        .zip(counts)
+        .map(|(&tile, count)| (tile, count as f32 / solutions as f32))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::common::Position;
+
+    use super::{Solver, Visible};
+
+    /// Builds a solver from rows of text, where `?` is hidden, `*` is a known mine and digits are
+    /// revealed numbers
+    fn board(rows: &[&str]) -> Solver {
+        Solver::new(rows.iter().enumerate().flat_map(|(y, row)| {
+            row.chars().enumerate().map(move |(x, c)| {
+                let tile = match c {
+                    '?' => Visible::Hidden,
+                    '*' => Visible::Mine,
+                    n => Visible::Revealed(n.to_digit(10).unwrap() as u8),
+                };
+                (Position::new(x as isize, y as isize), tile)
+            })
+        }))
+    }
+
+    #[test]
+    fn single_point() {
+        let mut solver = board(&["???", "?1?", "*??"]);
+        let found = solver.deduce();
+        assert_eq!(found.safe.len(), 7);
+        assert!(found.mines.is_empty());
+    }
+
+    #[test]
+    fn linked_constraints() {
+        // the classic 1-2-1 pattern against a wall
+        let mut solver = board(&["????", "1221", "0000"]);
+        let found = solver.deduce();
+        assert_eq!(
+            found.mines,
+            [Position::new(1, 0), Position::new(2, 0)].into()
+        );
+        assert_eq!(
+            found.safe,
+            [Position::new(0, 0), Position::new(3, 0)].into()
+        );
+    }
+
+    #[test]
+    fn fifty_fifty_is_undecided() {
+        let mut solver = board(&["??", "11", "00"]);
+        assert!(solver.deduce().is_empty());
+    }
+
+    #[test]
+    fn probabilities() {
+        let solver = board(&["??", "??", "11", "00"]);
+        let unknown_total = solver.probabilities(None);
+        assert_eq!(unknown_total.len(), 2);
+        assert_eq!(unknown_total[&Position::new(0, 1)], 0.5);
+
+        // one mine is in the second row, so the other two share the first row
+        let known_total = solver.probabilities(Some(3));
+        assert_eq!(known_total.len(), 4);
+        assert_eq!(known_total[&Position::new(1, 0)], 1.0);
+    }
+
+    #[test]
+    fn hint_prefers_safe_tiles() {
+        let solver = board(&["???", "?1?", "*??"]);
+        assert_eq!(
+            solver.hint(Position::new(2, 2), None),
+            Some((Position::new(2, 2), 0.0))
+        );
+        let guess = board(&["??", "11", "00"]);
+        assert_eq!(
+            guess.hint(Position::new(0, 0), None),
+            Some((Position::new(0, 0), 0.5))
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     common::{Position, Vec2Ext},
@@ -33,7 +34,7 @@ impl MineCell {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Component)]
+#[derive(Clone, Debug, PartialEq, Eq, Component, Serialize, Deserialize)]
 pub enum MineCellState {
     Empty,
     Mine,
@@ -1,4 +1,9 @@
-use super::{field::*, specific::MineCellState, GameOutcome};
+use super::{
+    field::*,
+    generate::{no_guess_layout, Generation, SeededRng},
+    specific::MineCellState,
+    GameOutcome,
+};
 use crate::{
     common::{CheckCell, FlagCell, InitCheckCell},
     cursor::CursorPosition,
@@ -24,8 +29,7 @@ pub fn wipe_minefields(
     minefield.for_each_mut(|mut field| {
         states.for_each_mut(|mut state| *state = MineCellState::Empty);
 
-        let amnt_cells = field.occupied_entries().count();
-        field.remaining_blank = amnt_cells * 8 / 10;
+        field.reset_blank();
     })
 }
 
@@ -34,6 +38,8 @@ pub fn generate_minefield(
     mut write_back: EventWriter<CheckCell>,
     minefields: Query<(Entity, &Minefield<Entity>)>,
     mut states: Query<&mut MineCellState>,
+    generation: Res<Generation>,
+    mut rng: ResMut<SeededRng>,
 ) {
     if let Some(ev) = check.iter().next().cloned() {
         let exclude = ev.positions;
@@ -42,12 +48,24 @@ pub fn generate_minefield(
         }
 
         if let Some((_, field)) = minefields.iter().find(|(field, _)| *field == ev.minefield) {
-            field
-                .choose_multiple(&exclude, &mut rand::thread_rng())
-                .into_iter()
-                .for_each(|(_, cell)| {
-                    *states.get_mut(cell).unwrap() = MineCellState::Mine;
-                });
+            match *generation {
+                Generation::Random => field
+                    .choose_multiple(&exclude, &mut rng.rng)
+                    .into_iter()
+                    .for_each(|(_, cell)| {
+                        *states.get_mut(cell).unwrap() = MineCellState::Mine;
+                    }),
+                Generation::NoGuess => {
+                    let cells = field.iter_positions().collect_vec();
+                    let layout = no_guess_layout(&cells, &exclude, field.mines, &mut rng.rng);
+                    for mine in &layout.mines {
+                        *states.get_mut(field[mine]).unwrap() = MineCellState::Mine;
+                    }
+                    for opening in layout.openings {
+                        write_back.send(CheckCell(CursorPosition(opening, ev.minefield)));
+                    }
+                }
+            }
         }
     }
 }
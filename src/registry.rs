@@ -4,7 +4,7 @@ use bevy::prelude::*;
 use once_cell::sync::Lazy;
 
 use crate::{
-    area_attack::{IAreaAttack, AREA_ATTACK_MARKER},
+    area_attack::{AreaAttackSettings, IAreaAttack, AREA_ATTACK_MARKER},
     server::{GameDescriptor, GameMarker},
 };
 
@@ -16,6 +16,12 @@ pub static REGISTRY: Lazy<GameRegistry> = Lazy::new(|| {
                 name: "Area Attack".to_string(),
                 description: "Race to claim the board for yourself".to_string(),
                 initializer: IAreaAttack::new(),
+                settings: || Box::<AreaAttackSettings>::default(),
+                describe_settings: |args| {
+                    AreaAttackSettings::decode(args)
+                        .ok()
+                        .map(|settings| settings.summary())
+                },
             },
         )]
         .into_iter()
@@ -0,0 +1,241 @@
+//! Recording of finished games and the viewer which plays them back. Each gamemode records its own
+//! inputs into a [Recording] and drives its own board from a [Playback], while this module keeps
+//! the file format and the controls that are shared between them.
+
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy_egui::EguiContext;
+use iyes_loopless::{
+    prelude::{AppLooplessStateExt, IntoConditionalSystem},
+    state::NextState,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    area_attack::Exchange,
+    common::Position,
+    main_menu::{standard_window, Menu},
+    minefield::FieldShape,
+    singleplayer::replay::SingleplayerInput,
+    storage,
+};
+
+const REPLAY_DIR: &str = "replays";
+const SPEEDS: [f32; 5] = [0.25, 0.5, 1.0, 2.0, 4.0];
+
+/// Everything that happened in one game, with the time into the game that it happened at
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Replay {
+    pub seed: u64,
+    pub shape: FieldShape,
+    pub game: RecordedGame,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum RecordedGame {
+    Singleplayer {
+        /// Kept alongside the seed, since a retried game goes on drawing from the same generator
+        /// and so cannot be placed again from the seed alone
+        mines: Vec<Position>,
+        inputs: Vec<(Duration, SingleplayerInput)>,
+    },
+    AreaAttack(Vec<(Duration, Exchange)>),
+}
+
+/// Lets replays from older versions of the game be told apart once the format changes
+#[derive(Serialize, Deserialize)]
+enum VersionedReplay {
+    V1(Replay),
+}
+
+impl Replay {
+    pub fn load(path: &str) -> Option<Self> {
+        match storage::read(path)? {
+            VersionedReplay::V1(replay) => Some(replay),
+        }
+    }
+
+    /// Writes the replay to a new file in the replay directory, named after its mode and seed
+    pub fn save(self) {
+        let mode = match self.game {
+            RecordedGame::Singleplayer { .. } => "singleplayer",
+            RecordedGame::AreaAttack(_) => "area-attack",
+        };
+        let path = (storage::list(REPLAY_DIR).len()..)
+            .map(|n| format!("{REPLAY_DIR}/{n:04}-{mode}-{}.replay", self.seed))
+            .find(|path| !storage::exists(path))
+            .unwrap();
+        storage::write(&path, &VersionedReplay::V1(self));
+    }
+
+    /// The time of the last thing which happened in the game
+    pub fn length(&self) -> Duration {
+        match &self.game {
+            RecordedGame::Singleplayer { inputs, .. } => inputs.last().map(|(time, _)| *time),
+            RecordedGame::AreaAttack(exchanges) => exchanges.last().map(|(time, _)| *time),
+        }
+        .unwrap_or_default()
+    }
+}
+
+/// Inputs of the game in progress, timed by a clock that the gamemode moves along with
+/// [tick_recording]. Gamemodes start and stop recording by inserting and removing the resource.
+#[derive(Resource)]
+pub struct Recording<T> {
+    elapsed: Duration,
+    entries: Vec<(Duration, T)>,
+}
+
+impl<T> Default for Recording<T> {
+    fn default() -> Self {
+        Self {
+            elapsed: Duration::ZERO,
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<T: Clone> Recording<T> {
+    pub fn record(&mut self, entry: T) {
+        self.entries.push((self.elapsed, entry));
+    }
+
+    pub fn entries(&self) -> Vec<(Duration, T)> {
+        self.entries.clone()
+    }
+}
+
+pub fn tick_recording<T: Send + Sync + 'static>(
+    recording: Option<ResMut<Recording<T>>>,
+    time: Res<Time>,
+) {
+    if let Some(mut recording) = recording {
+        recording.elapsed += time.delta();
+    }
+}
+
+/// The replay being watched, and how far into it the viewer is
+#[derive(Resource)]
+pub struct Playback {
+    pub replay: Replay,
+    pub time: Duration,
+    speed: f32,
+    paused: bool,
+}
+
+impl Playback {
+    fn new(replay: Replay) -> Self {
+        Self {
+            replay,
+            time: Duration::ZERO,
+            speed: 1.0,
+            paused: false,
+        }
+    }
+}
+
+pub fn watching_singleplayer(playback: Option<Res<Playback>>) -> bool {
+    matches!(
+        playback.as_deref().map(|playback| &playback.replay.game),
+        Some(RecordedGame::Singleplayer { .. })
+    )
+}
+
+pub fn watching_area_attack(playback: Option<Res<Playback>>) -> bool {
+    matches!(
+        playback.as_deref().map(|playback| &playback.replay.game),
+        Some(RecordedGame::AreaAttack(_))
+    )
+}
+
+fn replay_list_menu(mut commands: Commands, mut ctx: ResMut<EguiContext>) {
+    standard_window(&mut ctx, |ui| {
+        if ui.button("⏴back").clicked() {
+            commands.insert_resource(NextState(Menu::MainMenu));
+        }
+        let paths = storage::list(REPLAY_DIR);
+        if paths.is_empty() {
+            ui.label("No games have been recorded yet");
+            return;
+        }
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            egui::Grid::new("replays").striped(true).show(ui, |ui| {
+                for path in paths.iter().rev() {
+                    let name = path
+                        .trim_start_matches(REPLAY_DIR)
+                        .trim_start_matches(std::path::is_separator)
+                        .trim_end_matches(".replay");
+                    ui.label(name);
+                    if ui.button("Watch").clicked() {
+                        if let Some(replay) = Replay::load(path) {
+                            commands.insert_resource(Playback::new(replay));
+                            commands.insert_resource(NextState(Menu::Replay));
+                        }
+                    }
+                    ui.end_row();
+                }
+            });
+        });
+    });
+}
+
+fn playback_controls(
+    mut commands: Commands,
+    mut ctx: ResMut<EguiContext>,
+    mut playback: ResMut<Playback>,
+    time: Res<Time>,
+) {
+    let length = playback.replay.length();
+    if !playback.paused {
+        let step = time.delta().mul_f32(playback.speed);
+        playback.time = (playback.time + step).min(length);
+    }
+
+    egui::TopBottomPanel::bottom("playback").show(ctx.ctx_mut(), |ui| {
+        ui.horizontal(|ui| {
+            if ui.button("⏴back").clicked() {
+                commands.insert_resource(NextState(Menu::Replays));
+            }
+            let at_end = playback.time >= length;
+            let label = if playback.paused || at_end {
+                "▶"
+            } else {
+                "⏸"
+            };
+            if ui.button(label).clicked() {
+                if at_end {
+                    playback.time = Duration::ZERO;
+                    playback.paused = false;
+                } else {
+                    playback.paused = !playback.paused;
+                }
+            }
+            let mut seconds = playback.time.as_secs_f32();
+            let slider = egui::Slider::new(&mut seconds, 0.0..=length.as_secs_f32())
+                .suffix("s")
+                .max_decimals(1);
+            if ui.add(slider).changed() {
+                playback.time = Duration::from_secs_f32(seconds);
+            }
+            for speed in SPEEDS {
+                ui.selectable_value(&mut playback.speed, speed, format!("{speed}×"));
+            }
+            ui.label(format!("Seed: {}", playback.replay.seed));
+        });
+    });
+}
+
+fn stop_playback(mut commands: Commands) {
+    commands.remove_resource::<Playback>();
+}
+
+pub struct ReplayViewer;
+
+impl Plugin for ReplayViewer {
+    fn build(&self, app: &mut App) {
+        app.add_system(replay_list_menu.run_in_state(Menu::Replays))
+            .add_system(playback_controls.run_in_state(Menu::Replay))
+            .add_exit_system(Menu::Replay, stop_playback);
+    }
+}
@@ -0,0 +1,82 @@
+//! Finding servers on the local network. Servers broadcast an [Announcement] every so often, which
+//! clients listen for while choosing a server to connect to.
+
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// The port that servers broadcast their announcements to
+pub const DISCOVERY_PORT: u16 = 41923;
+/// How often a server announces itself
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+/// How long a server is listed for after its last announcement
+const FORGET_AFTER: Duration = Duration::from_secs(3 * ANNOUNCE_INTERVAL.as_secs());
+
+/// What a server tells the local network about itself. The version comes first so that it keeps its
+/// place as other fields are added.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Announcement {
+    /// The [PROTOCOL_VERSION](super::PROTOCOL_VERSION) of the server
+    pub version: u32,
+    pub name: String,
+    /// The port that the server accepts connections on, at the address the announcement came from
+    pub port: u16,
+    /// The number of games being played on the server
+    pub games: u32,
+}
+
+/// The servers which have announced themselves recently, by the address they can be connected to
+#[derive(Resource, Default)]
+pub struct LanServers {
+    socket: Option<UdpSocket>,
+    servers: HashMap<SocketAddr, (Announcement, Duration)>,
+}
+
+impl LanServers {
+    /// Begins listening for announcements. Only one client on a machine can listen at a time, so
+    /// the others go without.
+    pub fn listen() -> Self {
+        let socket = UdpSocket::bind(("0.0.0.0", DISCOVERY_PORT))
+            .and_then(|socket| socket.set_nonblocking(true).map(|_| socket))
+            .map_err(|e| log::info!("Not listening for servers on the local network: {e}"))
+            .ok();
+        Self {
+            socket,
+            servers: HashMap::new(),
+        }
+    }
+
+    pub fn is_listening(&self) -> bool {
+        self.socket.is_some()
+    }
+
+    /// Reads the announcements which have arrived, and forgets servers which have gone quiet
+    pub fn poll(&mut self, now: Duration) {
+        let Some(socket) = &self.socket else {
+            return;
+        };
+        let mut buf = [0; 512];
+        while let Ok((len, from)) = socket.recv_from(&mut buf) {
+            match rmp_serde::from_slice::<Announcement>(&buf[..len]) {
+                Ok(announcement) => {
+                    let address = SocketAddr::new(from.ip(), announcement.port);
+                    self.servers.insert(address, (announcement, now));
+                }
+                Err(e) => log::debug!("Unreadable announcement from {from}: {e}"),
+            }
+        }
+        self.servers
+            .retain(|_, (_, seen)| now.saturating_sub(*seen) < FORGET_AFTER);
+    }
+
+    pub fn servers(&self) -> impl Iterator<Item = (SocketAddr, &Announcement)> {
+        self.servers
+            .iter()
+            .map(|(&address, (announcement, _))| (address, announcement))
+    }
+}
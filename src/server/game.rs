@@ -18,7 +18,7 @@ use crate::{
 };
 
 use super::{
-    protocol::{ActiveGame, ClientMessage, ServerMessage},
+    protocol::{ActiveGame, ClientMessage, ErrorKind, PlayerId, ServerMessage},
     socket::socket_pc::{Connection, ConnectionInfo},
     IngameEvent,
 };
@@ -27,6 +27,21 @@ pub struct GameDescriptor {
     pub name: String,
     pub description: String,
     pub initializer: Box<dyn GamemodeInitializer>,
+    /// Produces the settings a new game of this kind starts out with
+    pub settings: fn() -> Box<dyn GameSettings>,
+    /// Describes the settings a game was created with, given the arguments it was created with
+    pub describe_settings: fn(&[u8]) -> Option<String>,
+}
+
+/// The settings of a gamemode, as edited by the player who creates a game of that mode
+pub trait GameSettings: Send + Sync {
+    /// Draws the form with which the settings are edited
+    fn ui(&mut self, ui: &mut egui::Ui);
+    /// Checks as much of the settings as the client is able to, returning a message for the player
+    /// if they cannot be used
+    fn check(&self) -> Result<(), String>;
+    /// Serializes the settings into the arguments of [ClientMessage::Create]
+    fn encode(&self) -> Vec<u8>;
 }
 
 impl Debug for GameDescriptor {
@@ -45,7 +60,7 @@ pub struct GameMarker(pub Uuid);
 
 /// A component on a game describing whether or not a game is allowed to be connected to by the
 /// player
-#[derive(Component)]
+#[derive(Component, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Access {
     /// Each game is spawned with this access. It is up to the game to update this to reflect that
     /// it is ready to receive players (by changing to Open access)
@@ -55,10 +70,51 @@ pub enum Access {
     Ingame,
 }
 
+impl Access {
+    /// Describes the access for players choosing a game to join
+    pub fn name(&self) -> &'static str {
+        match self {
+            Access::Initializing => "Starting up",
+            Access::Open => "Open",
+            Access::Full => "Full",
+            Access::Ingame => "In progress",
+        }
+    }
+}
+
+/// How a game is getting on, as shown to players choosing a game to join. It is up to the game to
+/// keep this up to date.
+#[derive(Component, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GameStatus {
+    pub access: Access,
+    pub players: u32,
+    pub max_players: u32,
+    /// The stage that the game is in, as named by its gamemode
+    pub stage: String,
+}
+
+/// The arguments a game was created with, for the game to interpret once it is spawned
+#[derive(Component, Deref)]
+pub struct GameArgs(pub Vec<u8>);
+
+impl PlayerId {
+    /// The id of the player connected through the given entity. This server gives every player the
+    /// bits of the entity of its connection, which no other entity is given while the server runs.
+    pub fn of_connection(connection: Entity) -> Self {
+        Self(connection.to_bits())
+    }
+
+    /// The entity of the connection of the player, on this server
+    pub fn connection(self) -> Entity {
+        Entity::from_bits(self.0)
+    }
+}
+
 #[derive(Bundle)]
 pub struct GameBundle {
     pub marker: GameMarker,
     pub access: Access,
+    pub args: GameArgs,
 }
 
 pub fn game_messages(
@@ -76,8 +132,14 @@ pub fn game_messages(
             Some(Ok(ClientMessage::ForceLeave)) => {
                 commands.entity(**game).remove_children(&[player]);
             }
-            Some(_) => {
-                socket.send_logged(ServerMessage::Malformed);
+            Some(Ok(_)) => {
+                socket.send_logged(ServerMessage::error(
+                    ErrorKind::Unexpected,
+                    "Only messages for the game can be sent while in one",
+                ));
+            }
+            Some(Err(e)) => {
+                socket.send_logged(ServerMessage::error(ErrorKind::Unreadable, e));
             }
             None => (),
         }
@@ -88,17 +150,36 @@ pub fn server_messages(
     mut commands: Commands,
     mut clients: Query<(Entity, &mut Connection), (With<ConnectionInfo>, Without<Parent>)>,
     q_players: Query<&ConnectionInfo>,
-    active_games: Query<(Entity, &GameMarker, &Children)>,
+    active_games: Query<(
+        Entity,
+        &GameMarker,
+        &GameArgs,
+        &Children,
+        Option<&GameStatus>,
+    )>,
+    markers: Query<(&GameMarker, &Access)>,
 ) {
     for (player, mut socket) in clients.iter_mut() {
         match socket.recv_message() {
-            Some(Ok(ClientMessage::Games)) => {
+            // this server lists the games once, and does not keep subscribers up to date
+            Some(Ok(ClientMessage::Games | ClientMessage::SubscribeGames)) => {
                 let msg = ServerMessage::ActiveGames(
                     active_games
                         .iter()
-                        .map(|(id, &marker, player_ids)| ActiveGame {
-                            marker,
-                            id: id.to_bits(),
+                        // a game without a status is still initializing, and cannot be joined yet
+                        .filter_map(|(id, &marker, args, player_ids, status)| {
+                            Some(ActiveGame {
+                                marker,
+                                id: id.to_bits(),
+                                // the creator of a game is the first of its children
+                                host: player_ids
+                                    .first()
+                                    .and_then(|&host| q_players.get(host).ok())
+                                    .map(|host| host.username.clone())
+                                    .unwrap_or_default(),
+                                status: status?.clone(),
+                                settings: args.to_vec(),
+                            })
                         })
                         .collect(),
                 );
@@ -110,24 +191,65 @@ pub fn server_messages(
                     REGISTRY.keys().copied().collect(),
                 ));
             }
-            Some(Ok(ClientMessage::Create { game, args: _args })) => {
-                //TODO: Pass arguments down
+            Some(Ok(ClientMessage::Create { game, args })) => {
+                // the gamemode tells the creator whether it joined, once it has read the settings
                 commands
                     .spawn(GameBundle {
                         marker: game,
                         access: Access::Initializing,
+                        args: GameArgs(args),
                     })
                     .add_child(player);
             }
             Some(Ok(ClientMessage::Join { game })) => {
-                if let Some(mut ent) = commands.get_entity(Entity::from_bits(game)) {
-                    ent.add_child(player);
-                } else {
-                    socket.send_logged(ServerMessage::Malformed);
+                let game_entity = Entity::from_bits(game);
+                let username = q_players.get(player).ok().map(|info| &info.username);
+                let name_taken = active_games.get(game_entity).is_ok_and(|(.., peers, _)| {
+                    peers.iter().any(|&peer| {
+                        q_players.get(peer).ok().map(|info| &info.username) == username
+                    })
+                });
+                // the game itself decides whether the player joins, and tells it so, since several
+                // players may ask for the last place at once
+                match markers.get(game_entity) {
+                    Ok((_, Access::Full)) => socket.send_logged(ServerMessage::join_rejected(
+                        ErrorKind::GameFull,
+                        "The game is full",
+                    )),
+                    Ok((_, Access::Ingame)) => socket.send_logged(ServerMessage::join_rejected(
+                        ErrorKind::GameStarted,
+                        "The game has already begun",
+                    )),
+                    Ok((_, Access::Initializing)) => {
+                        socket.send_logged(ServerMessage::join_rejected(
+                            ErrorKind::UnknownGame,
+                            "The game is not open yet",
+                        ))
+                    }
+                    Ok(_) if name_taken => socket.send_logged(ServerMessage::join_rejected(
+                        ErrorKind::NameTaken,
+                        "Another player in the game goes by that name",
+                    )),
+                    Ok((_, Access::Open)) => {
+                        commands.entity(game_entity).add_child(player);
+                    }
+                    Err(_) => socket.send_logged(ServerMessage::join_rejected(
+                        ErrorKind::UnknownGame,
+                        "There is no game with that id",
+                    )),
                 }
             }
-            Some(_) => {
-                socket.send_logged(ServerMessage::Malformed);
+            Some(Ok(ClientMessage::Ingame { .. })) => {
+                socket.send_logged(ServerMessage::error(ErrorKind::NotInGame, "Not in a game"));
+            }
+            Some(Ok(_)) => {
+                socket.send_logged(ServerMessage::error(
+                    ErrorKind::Unexpected,
+                    "This server does not support that request",
+                ));
+            }
+            Some(Err(e)) => {
+                socket.send_logged(ServerMessage::error(ErrorKind::Unreadable, e));
             }
             _ => (),
         };
@@ -0,0 +1,137 @@
+//! Limits on how much a single client may send to a server, so that no client can keep the server
+//! busy at the expense of everyone else. A client which goes over them is kicked.
+
+use std::time::Instant;
+
+use bevy::prelude::Resource;
+use tungstenite::protocol::{frame::coding::CloseCode, WebSocketConfig};
+
+use super::KickReason;
+
+#[derive(Resource, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// The largest message a client may send, in bytes. The socket refuses larger frames before
+    /// reading them whole.
+    pub max_message_size: usize,
+    /// How many messages a client may send each second on average. A second's worth may be sent
+    /// at once.
+    pub messages_per_second: u32,
+    /// How many bytes a client may send each second on average, allowing bursts in the same way
+    pub bytes_per_second: u32,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_message_size: 64 * 1024,
+            messages_per_second: 60,
+            bytes_per_second: 64 * 1024,
+        }
+    }
+}
+
+impl ConnectionLimits {
+    /// The configuration of a socket which refuses messages that are too large
+    pub fn websocket(&self) -> WebSocketConfig {
+        WebSocketConfig {
+            max_message_size: Some(self.max_message_size),
+            max_frame_size: Some(self.max_message_size),
+            ..Default::default()
+        }
+    }
+}
+
+impl KickReason {
+    /// The code that the connection of a kicked client is closed with
+    pub fn close_code(&self) -> CloseCode {
+        match self {
+            KickReason::MessageTooLarge => CloseCode::Size,
+            KickReason::TooManyMessages | KickReason::TooMuchData => CloseCode::Policy,
+        }
+    }
+}
+
+/// Keeps count of what a client has sent, allowing it as much as its [ConnectionLimits] do. Both
+/// limits are buckets which refill at their rate, up to a second's worth.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: ConnectionLimits,
+    messages: f64,
+    bytes: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            limits,
+            messages: limits.messages_per_second as f64,
+            bytes: limits.bytes_per_second as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Counts a message of the given size which the client sent at `now`, unless it is more than
+    /// the client is allowed
+    pub fn admit(&mut self, size: usize, now: Instant) -> Result<(), KickReason> {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = self.last.max(now);
+        let messages_per_second = self.limits.messages_per_second as f64;
+        let bytes_per_second = self.limits.bytes_per_second as f64;
+        self.messages = (self.messages + elapsed * messages_per_second).min(messages_per_second);
+        self.bytes = (self.bytes + elapsed * bytes_per_second).min(bytes_per_second);
+
+        if size > self.limits.max_message_size {
+            return Err(KickReason::MessageTooLarge);
+        }
+        if self.messages < 1. {
+            return Err(KickReason::TooManyMessages);
+        }
+        if self.bytes < size as f64 {
+            return Err(KickReason::TooMuchData);
+        }
+        self.messages -= 1.;
+        self.bytes -= size as f64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::server::KickReason;
+
+    use super::{ConnectionLimits, RateLimiter};
+
+    #[test]
+    fn bursts_are_allowed_up_to_the_rate() {
+        let limits = ConnectionLimits {
+            max_message_size: 100,
+            messages_per_second: 10,
+            bytes_per_second: 100,
+        };
+        let mut limiter = RateLimiter::new(limits);
+        let start = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(limiter.admit(10, start), Ok(()));
+        }
+        assert_eq!(limiter.admit(10, start), Err(KickReason::TooManyMessages));
+
+        // a tenth of a second gives back one message, and ten bytes
+        let later = start + Duration::from_millis(100);
+        assert_eq!(limiter.admit(20, later), Err(KickReason::TooMuchData));
+        assert_eq!(limiter.admit(10, later), Ok(()));
+        assert_eq!(limiter.admit(101, later), Err(KickReason::MessageTooLarge));
+
+        // the buckets never hold more than a second's worth
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..10 {
+            assert_eq!(limiter.admit(1, much_later), Ok(()));
+        }
+        assert_eq!(
+            limiter.admit(1, much_later),
+            Err(KickReason::TooManyMessages)
+        );
+    }
+}
@@ -4,17 +4,22 @@ use std::{net::IpAddr, str::FromStr};
 
 use bevy::prelude::*;
 
+mod discovery;
 mod game;
+mod limits;
 mod protocol;
 mod socket;
 
+pub use discovery::*;
 pub use game::*;
+pub use limits::*;
 pub use protocol::*;
 pub use socket::socket_pc::*;
 pub use socket::CommonConnection;
 
 pub struct ServerPlugin {
     pub address_name: Option<String>,
+    pub port: u16,
 }
 
 #[cfg(feature = "server")]
@@ -26,7 +31,8 @@ impl Plugin for ServerPlugin {
             .map(|name| IpAddr::from_str(&name).unwrap())
             .unwrap_or_else(|| local_ip_address::local_ip().unwrap());
 
-        app.insert_resource(OpenPort::generate(address))
+        app.insert_resource(OpenPort::generate(address, self.port))
+            .init_resource::<ConnectionLimits>()
             .add_event::<IngameEvent>()
             .add_event::<ConnectionSwitch>()
             .add_system_to_stage(CoreStage::PostUpdate, delay_hierarchy_events)
@@ -41,7 +47,7 @@ impl Plugin for ServerPlugin {
 }
 
 #[cfg(feature = "server")]
-pub fn server_app(address_name: Option<String>) -> App {
+pub fn server_app(address_name: Option<String>, port: u16) -> App {
     use std::time::Duration;
 
     use bevy::app::{RunMode, ScheduleRunnerSettings};
@@ -60,7 +66,7 @@ pub fn server_app(address_name: Option<String>) -> App {
         .add_plugin(AssetPlugin::default())
         .add_plugin(HierarchyPlugin)
         .add_plugin(common::QuicksweeperTypes)
-        .add_plugin(server::ServerPlugin { address_name })
+        .add_plugin(server::ServerPlugin { address_name, port })
         .add_plugin(load::ServerLoad)
         .add_plugin(minefield::MinefieldPlugin)
         // gamemodes
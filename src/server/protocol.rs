@@ -1,35 +1,248 @@
+use std::cmp::Ordering;
+
 use bevy::prelude::*;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::server::GameMarker;
+use crate::{
+    registry::REGISTRY,
+    server::{GameMarker, GameStatus},
+};
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Version of the messages which clients and servers exchange. It must be raised whenever any of
+/// them changes shape, so that a client and a server which cannot understand each other find out
+/// during the handshake rather than on the first message that fails to be read.
+pub const PROTOCOL_VERSION: u32 = 12;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ActiveGame {
     pub marker: GameMarker,
     pub id: u64,
+    /// The username of the player who created the game
+    pub host: String,
+    pub status: GameStatus,
+    /// The arguments that the game was created with, as in [ClientMessage::Create]
+    pub settings: Vec<u8>,
+}
+
+/// A change to the list of games on a server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum GameListChange {
+    /// The game was created, or something about it has changed
+    Updated(ActiveGame),
+    /// The game has ended
+    Removed(u64),
 }
 
-// TODO Better eq/hash implementation based on player id (instead of arbitrary name, which can collide)
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+/// The first message a client sends, which the server answers with a [HandshakeReply]. The version
+/// comes first so that it keeps its place as other fields are added. Players are told apart by
+/// their [PlayerId] rather than by anything in their greeting.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Greeting {
+    pub version: u32,
     pub username: String,
+    /// The gamemodes that the client is able to play
+    pub gamemodes: Vec<GameMarker>,
+}
+
+impl Greeting {
+    pub fn new(username: String) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            username,
+            gamemodes: REGISTRY.keys().copied().collect(),
+        }
+    }
+
+    /// Decides whether this server can talk to the client, returning the gamemodes which both of
+    /// them support
+    pub fn check(&self) -> Result<Vec<GameMarker>, HandshakeRejection> {
+        let versions = (PROTOCOL_VERSION, self.version);
+        match self.version.cmp(&PROTOCOL_VERSION) {
+            Ordering::Less => return Err(HandshakeRejection::ServerNewer(versions.0, versions.1)),
+            Ordering::Greater => {
+                return Err(HandshakeRejection::ServerOlder(versions.0, versions.1))
+            }
+            Ordering::Equal => (),
+        }
+        let gamemodes: Vec<_> = self
+            .gamemodes
+            .iter()
+            .copied()
+            .filter(|marker| REGISTRY.contains_key(marker))
+            .collect();
+        if gamemodes.is_empty() {
+            return Err(HandshakeRejection::NoCommonGamemodes);
+        }
+        Ok(gamemodes)
+    }
 }
 
+/// The answer to a [Greeting]. It is kept apart from [ServerMessage] so that it can still be read
+/// by clients of other versions, and so must only ever gain variants at the end.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum HandshakeReply {
+    /// The client may go on to send [ClientMessage]s
+    Accepted {
+        /// The gamemodes which both the server and the client support
+        gamemodes: Vec<GameMarker>,
+    },
+    /// The server will close the connection after sending this
+    Rejected(HandshakeRejection),
+}
+
+#[derive(thiserror::Error, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeRejection {
+    #[error("The server is newer than this client (protocol {0}, client has {1})")]
+    ServerNewer(u32, u32),
+    #[error("The server is older than this client (protocol {0}, client has {1})")]
+    ServerOlder(u32, u32),
+    #[error(
+        "The server could not read the greeting, so the client is likely from another version"
+    )]
+    Unreadable,
+    #[error("The server does not support any of the gamemodes of this client")]
+    NoCommonGamemodes,
+}
+
+/// Lets a player return to the game it was in after losing its connection, for as long as the
+/// game keeps its place
+pub type SessionToken = u64;
+
+/// Identifies a player to the game it is in and to the other players of that game. Ids are given
+/// out by the server, and no two players who connect to it while it runs are given the same one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PlayerId(pub u64);
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ClientMessage {
-    Create { game: GameMarker, args: Vec<u8> },
-    Join { game: u64 },
-    Ingame { data: Vec<u8> },
+    Create {
+        game: GameMarker,
+        args: Vec<u8>,
+    },
+    Join {
+        game: u64,
+    },
+    /// Returns to a game which the connection was lost to, in place of joining it anew
+    Rejoin {
+        token: SessionToken,
+    },
+    /// Watches a game without taking part in it, which is possible even once the game is full or
+    /// has begun
+    Spectate {
+        game: u64,
+    },
+    Ingame {
+        data: Vec<u8>,
+    },
     ForceLeave,
     Games,
+    /// Lists the games like [ClientMessage::Games], and then keeps the client up to date on them
+    /// with [ServerMessage::GamesChanged] until it enters a game
+    SubscribeGames,
     GameTypes,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ServerMessage {
     ActiveGames(Vec<ActiveGame>),
+    /// Changes to the games listed by [ServerMessage::ActiveGames], sent to subscribed clients
+    GamesChanged(Vec<GameListChange>),
     AvailableGames(Vec<GameMarker>),
-    Malformed,
+    /// A message from the game that the client is in, to be read by the client of its gamemode
+    Ingame {
+        data: Vec<u8>,
+    },
+    /// The client has been let into the game it asked to create, join, watch or return to. Any
+    /// [ServerMessage::Ingame] of the game follows this message.
+    Joined {
+        game: u64,
+        marker: GameMarker,
+        /// Whether the client created the game, and so may begin it
+        you_are_host: bool,
+    },
+    /// The client could not be let into the game it asked to create, join, watch or return to
+    JoinRejected {
+        kind: ErrorKind,
+        /// Explains the rejection to the player
+        message: String,
+    },
+    /// A request of the client could not be carried out
+    Error {
+        kind: ErrorKind,
+        /// Explains the error to the player
+        message: String,
+    },
+    /// The server is shutting down, and will close the connection once the given number of seconds
+    /// have passed. The game the client is in ends by then at the latest.
+    ShuttingDown {
+        seconds: u32,
+    },
+    /// The client sent more than the server allows, and its connection is closed straight after
+    /// this message
+    Kicked(KickReason),
+}
+
+impl ServerMessage {
+    pub fn error(kind: ErrorKind, message: impl ToString) -> Self {
+        Self::Error {
+            kind,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn join_rejected(kind: ErrorKind, message: impl ToString) -> Self {
+        Self::JoinRejected {
+            kind,
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The message could not be read
+    Unreadable,
+    /// The message is not supported by the server, or cannot be sent at this point
+    Unexpected,
+    /// There is no game with the requested id
+    UnknownGame,
+    /// The server does not support the requested gamemode
+    UnknownGamemode,
+    /// The game could not be created with the arguments that were given
+    InvalidSettings,
+    /// The game has no room for another player
+    GameFull,
+    /// The game no longer holds a place for the player who asked to return to it
+    SessionExpired,
+    /// A message meant for a game was sent while not in one
+    NotInGame,
+    /// The server cannot hold any more games
+    ServerFull,
+    /// The server is shutting down, and so lets no one into a game
+    ShuttingDown,
+    /// Another player in the game goes by the same username
+    NameTaken,
+    /// The game has begun, and can no longer be joined
+    GameStarted,
+}
+
+impl ErrorKind {
+    /// Whether the error means that the client is not in the game it believes it is in, such as
+    /// when the game it asked to enter could not be entered
+    pub fn leaves_game(&self) -> bool {
+        !matches!(self, ErrorKind::Unreadable | ErrorKind::Unexpected)
+    }
+}
+
+/// Why a client was kicked by the server
+#[derive(thiserror::Error, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KickReason {
+    #[error("Too many messages were sent too quickly")]
+    TooManyMessages,
+    #[error("Too much data was sent too quickly")]
+    TooMuchData,
+    #[error("A message was larger than the server allows")]
+    MessageTooLarge,
 }
 
 #[derive(Debug)]
@@ -58,3 +271,33 @@ impl IngameEvent {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use bevy::utils::Uuid;
+
+    use crate::{area_attack::AREA_ATTACK_MARKER, server::GameMarker};
+
+    use super::{Greeting, HandshakeRejection, PROTOCOL_VERSION};
+
+    #[test]
+    fn greetings_are_checked() {
+        let unknown = GameMarker(Uuid::from_u128(1));
+        let mut greeting = Greeting::new("player".to_string());
+        greeting.gamemodes.push(unknown);
+        assert_eq!(greeting.check(), Ok(vec![AREA_ATTACK_MARKER]));
+
+        greeting.version = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            greeting.check(),
+            Err(HandshakeRejection::ServerOlder(
+                PROTOCOL_VERSION,
+                PROTOCOL_VERSION + 1
+            ))
+        );
+
+        greeting.version = PROTOCOL_VERSION;
+        greeting.gamemodes = vec![unknown];
+        assert_eq!(greeting.check(), Err(HandshakeRejection::NoCommonGamemodes));
+    }
+}
@@ -24,6 +24,45 @@ impl CommonConnection {
         Self::Pc(socket_pc::Connection::new(inner_socket))
     }
 
+    /// Opens a connection to the server at the given address, blocking until the handshake is done
+    /// on platforms where that is possible, so it should be called from a task rather than a frame.
+    /// The connection should not be used until it is ready. The error is boxed, being far larger
+    /// than the connection it stands in for.
+    pub fn connect(address: &str) -> Result<Self, Box<MessageError>> {
+        let url = format!("ws://{address}/");
+        #[cfg(target_arch = "wasm32")]
+        {
+            Ok(Self::new_web(&url))
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            use std::{io, net::ToSocketAddrs, time::Duration};
+            use tungstenite::HandshakeError;
+
+            let failed = |e: tungstenite::Error| Box::new(MessageError::from(e));
+            let io_failed = |e: io::Error| failed(tungstenite::Error::Io(e));
+            let addr = address
+                .to_socket_addrs()
+                .map_err(io_failed)?
+                .next()
+                .ok_or_else(|| io_failed(io::ErrorKind::NotFound.into()))?;
+            const TIMEOUT: Duration = Duration::from_secs(1);
+            let stream = TcpStream::connect_timeout(&addr, TIMEOUT).map_err(io_failed)?;
+            // a server which accepts the connection but never answers is given up on
+            stream
+                .set_read_timeout(Some(TIMEOUT))
+                .and_then(|()| stream.set_write_timeout(Some(TIMEOUT)))
+                .map_err(io_failed)?;
+            let (socket, _) = tungstenite::client(url, stream).map_err(|e| match e {
+                HandshakeError::Failure(e) => failed(e),
+                // the stream only stops blocking once it has timed out
+                HandshakeError::Interrupted(_) => io_failed(io::ErrorKind::TimedOut.into()),
+            })?;
+            socket.get_ref().set_nonblocking(true).map_err(io_failed)?;
+            Ok(Self::new_pc(socket))
+        }
+    }
+
     pub fn recv_message<D>(&mut self) -> Option<Result<D, MessageError>>
     where
         D: DeserializeOwned,
@@ -66,4 +105,13 @@ impl CommonConnection {
             CommonConnection::Web(sock) => sock.is_ready(),
         }
     }
+
+    /// Whether the connection has been found to be closed, so that nothing more can be sent or
+    /// received through it
+    pub fn is_disconnected(&self) -> bool {
+        match self {
+            CommonConnection::Pc(sock) => sock.is_disconnected(),
+            CommonConnection::Web(sock) => sock.is_disconnected(),
+        }
+    }
 }
@@ -1,6 +1,7 @@
 use std::{
     collections::VecDeque,
     net::{IpAddr, TcpListener, TcpStream},
+    time::Instant,
 };
 
 use bevy::prelude::*;
@@ -9,10 +10,13 @@ use serde::{de::DeserializeOwned, Serialize};
 use tap::Tap;
 use tungstenite::{
     handshake::{server::NoCallback, MidHandshake},
+    protocol::CloseFrame,
     HandshakeError, Message, ServerHandshake, WebSocket,
 };
 
-use crate::server::Greeting;
+use crate::server::{
+    ConnectionLimits, Greeting, HandshakeRejection, KickReason, RateLimiter, ServerMessage,
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum MessageError {
@@ -24,6 +28,10 @@ pub enum MessageError {
     Deserialization(#[from] rmp_serde::decode::Error),
     #[error("Data received from websocket is not encoded in binary format")]
     Encoding,
+    #[error("The handshake was refused: {0}")]
+    Refused(#[from] HandshakeRejection),
+    #[error("The client was kicked: {0}")]
+    Kicked(#[from] KickReason),
 }
 
 impl MessageError {
@@ -32,6 +40,8 @@ impl MessageError {
         match self {
             //TODO: Find all outcomes which suggest the socket cannot be used
             Self::Tungstenite(Error::ConnectionClosed | Error::AlreadyClosed) => true,
+            Self::Tungstenite(Error::Io(e)) => e.kind() != std::io::ErrorKind::WouldBlock,
+            Self::Kicked(_) => true,
             _ => false,
         }
     }
@@ -41,8 +51,8 @@ impl MessageError {
 pub struct OpenPort(TcpListener);
 
 impl OpenPort {
-    pub fn generate(addr: IpAddr) -> Self {
-        let listener = TcpListener::bind((addr, 0)).unwrap();
+    pub fn generate(addr: IpAddr, port: u16) -> Self {
+        let listener = TcpListener::bind((addr, port)).unwrap();
         listener
             .set_nonblocking(true)
             .expect("could not start server in nonblocking mode");
@@ -57,6 +67,8 @@ pub struct Connection {
     repeat_buffer: VecDeque<Vec<u8>>,
     trials: u8,
     disconnected: bool,
+    /// Holds the client to the limits of the server, on connections which the server accepted
+    limiter: Option<RateLimiter>,
 }
 
 impl Connection {
@@ -66,6 +78,15 @@ impl Connection {
             repeat_buffer: default(),
             disconnected: false,
             trials: 0,
+            limiter: None,
+        }
+    }
+
+    /// A connection to a client, which is kicked once it sends more than `limits` allow
+    pub fn limited(socket: WebSocket<TcpStream>, limits: ConnectionLimits) -> Self {
+        Self {
+            limiter: Some(RateLimiter::new(limits)),
+            ..Self::new(socket)
         }
     }
 
@@ -75,9 +96,21 @@ impl Connection {
     {
         let msg = match self.socket.read_message() {
             Ok(msg) => Some(msg),
-            Err(tungstenite::Error::Io(_)) => None,
-            Err(e) => return Some(Err(e.into())),
+            Err(tungstenite::Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => None,
+            Err(tungstenite::Error::Capacity(_)) if self.limiter.is_some() => {
+                return Some(Err(self.kick(KickReason::MessageTooLarge)));
+            }
+            Err(e) => {
+                let e = MessageError::from(e);
+                self.disconnected |= e.disconnected();
+                return Some(Err(e));
+            }
         }?;
+        if let Some(limiter) = &mut self.limiter {
+            if let Err(reason) = limiter.admit(msg.len(), Instant::now()) {
+                return Some(Err(self.kick(reason)));
+            }
+        }
 
         match msg {
             Message::Ping(_) | Message::Pong(_) => None,
@@ -88,6 +121,23 @@ impl Connection {
         }
     }
 
+    /// Tells the client why it is being kicked and closes the connection, which then counts as
+    /// disconnected
+    fn kick(&mut self, reason: KickReason) -> MessageError {
+        log::info!("Kicked a client: {reason}");
+        let _ = self.try_send(ServerMessage::Kicked(reason));
+        let _ = self.socket.close(Some(CloseFrame {
+            code: reason.close_code(),
+            reason: reason.to_string().into(),
+        }));
+        self.disconnected = true;
+        reason.into()
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected
+    }
+
     /// Asks the connection to attempt sending the message as long as possible. Message failure will
     /// be recorded in the given logging implementation. In general this should be used for messages
     /// which are sent less frequently and should not be dropped.
@@ -160,16 +210,18 @@ pub fn try_send_repeated(mut connections: Query<&mut Connection>) {
 
 pub fn receive_connections(
     listener: Res<OpenPort>,
+    limits: Res<ConnectionLimits>,
     mut commands: Commands,
     mut conn_queue: Local<Vec<MidHandshake<ServerHandshake<TcpStream, NoCallback>>>>,
 ) {
     if let Ok((client, _)) = listener.accept() {
-        client.set_nonblocking(true).expect(
-            "Connection failed for reason: Could not connect to client in nonblocking mode",
-        );
-        match tungstenite::accept(client) {
+        if let Err(e) = client.set_nonblocking(true) {
+            log::error!("Could not connect to a client in nonblocking mode: {e}");
+            return;
+        }
+        match tungstenite::accept_with_config(client, Some(limits.websocket())) {
             Ok(socket) => {
-                commands.spawn((Connection::new(socket),));
+                commands.spawn((Connection::limited(socket, *limits),));
             }
             Err(HandshakeError::Interrupted(handshake)) => conn_queue.push(handshake),
             Err(msg) => eprintln!("Connection failed for reason: {msg:?}"),
@@ -180,7 +232,7 @@ pub fn receive_connections(
         .drain(..)
         .filter_map(|handshake| match handshake.handshake() {
             Ok(socket) => {
-                commands.spawn((Connection::new(socket),));
+                commands.spawn((Connection::limited(socket, *limits),));
                 None
             }
             Err(HandshakeError::Interrupted(handshake)) => Some(handshake),
@@ -201,7 +253,7 @@ pub fn upgrade_connections(
     for (id, mut client) in partial_connections.iter_mut() {
         if let Some(result) = client.recv_message() {
             match result {
-                Ok(Greeting { username }) => {
+                Ok(Greeting { username, .. }) => {
                     println!("Connection upgraded! It is now able to become a player");
                     commands.entity(id).insert((ConnectionInfo { username },));
                 }
@@ -45,6 +45,11 @@ impl Connection {
         self.socket.ready_state() == 1
     }
 
+    /// Whether the socket is closing or has closed
+    pub fn is_disconnected(&self) -> bool {
+        self.socket.ready_state() >= 2
+    }
+
     pub fn recv_message<D>(&mut self) -> Option<Result<D, MessageError>>
     where
         D: DeserializeOwned,
@@ -1,23 +1,46 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
 
 use itertools::Itertools;
+use serde::Serialize;
 use tokio::{
     net::TcpListener,
     sync::{
+        broadcast,
         mpsc::{channel, Receiver, Sender},
-        Mutex, RwLock,
+        watch, Mutex, RwLock,
     },
-    task::JoinHandle,
+    task::{JoinHandle, JoinSet},
+    time::{sleep, timeout, timeout_at},
 };
 use tokio_tungstenite as tungsten;
 use unique_id::{sequence::SequenceGenerator, Generator};
 
 use crate::{
     registry::REGISTRY,
-    server::{ActiveGame, GameDescriptor, GameMarker, Greeting},
+    server::{
+        ActiveGame, ErrorKind, GameDescriptor, GameListChange, GameMarker, GameStatus, Greeting,
+        PlayerId, SessionToken,
+    },
+    storage,
 };
 
-use super::{connection::Connection, double_channel::DoubleChannel, game::SessionObjects};
+use super::{
+    config::ServerConfig,
+    connection::Connection,
+    discovery,
+    double_channel::DoubleChannel,
+    game::{GameLimits, GameResult, SessionObjects},
+    shutdown::{stop_signal, Shutdown, ShutdownTrigger},
+};
 
 pub fn player_connector_pair() -> (GameConnector, PlayerReceiver) {
     let (greeting_tx, greeting_rx) = channel(1);
@@ -36,87 +59,428 @@ pub fn player_connector_pair() -> (GameConnector, PlayerReceiver) {
     )
 }
 
+/// A player asking to be let into a game
+#[derive(Clone, Debug)]
+pub struct Arrival {
+    /// Given out by the server as the player connected
+    pub id: PlayerId,
+    pub greeting: Greeting,
+    /// Identifies the player to the game, so that it can come back after losing its connection
+    pub token: SessionToken,
+    pub kind: ArrivalKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArrivalKind {
+    /// The player is taking part in the game for the first time
+    Joining,
+    /// The player has been in the game before and is coming back to it
+    Returning,
+    /// The player only watches the game, and may not take part in it
+    Spectating,
+}
+
+/// Why a game turned a player away
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Refusal {
+    /// The game has no room for the player, or holds no place for a player who is returning
+    NoRoom,
+    /// Another player in the game goes by the same username
+    NameTaken,
+    /// The game has begun, and takes no more players
+    Started,
+}
+
+/// What a game made of a player asking to be let in
+pub enum Admission {
+    Admitted(DoubleChannel<Vec<u8>>),
+    Refused(Refusal),
+    /// The game is no longer running
+    Closed,
+}
+
 pub struct GameConnector {
-    request: Sender<Greeting>,
-    recv: Receiver<DoubleChannel<Vec<u8>>>,
+    request: Sender<Arrival>,
+    recv: Receiver<Result<DoubleChannel<Vec<u8>>, Refusal>>,
 }
 
 /// The other end of [GameConnector], held by the game
 pub struct PlayerReceiver {
-    recv: Receiver<Greeting>,
-    reply: Sender<DoubleChannel<Vec<u8>>>,
+    recv: Receiver<Arrival>,
+    reply: Sender<Result<DoubleChannel<Vec<u8>>, Refusal>>,
     needs_reply: bool,
 }
 
 impl PlayerReceiver {
-    pub async fn recv(&mut self) -> Option<Greeting> {
+    pub async fn recv(&mut self) -> Option<Arrival> {
         self.recv.recv().await.map(|u| {
             self.needs_reply = true;
             u
         }) // TODO feature result_option_inspect
     }
 
+    /// Lets in the player who last asked to be, returning the game's end of its channel. There is
+    /// none if no one asked, or if the server stopped waiting for the answer.
     pub async fn respond(&mut self) -> Option<DoubleChannel<Vec<u8>>> {
         if self.needs_reply {
             self.needs_reply = false;
             let (ch_out, ch_here) = DoubleChannel::double();
-            self.reply.send(ch_out).await;
+            self.reply.send(Ok(ch_out)).await.ok()?;
             Some(ch_here)
         } else {
             None
         }
     }
+
+    /// Turns away the player who last asked to be let in
+    pub async fn refuse(&mut self, refusal: Refusal) {
+        if self.needs_reply {
+            self.needs_reply = false;
+            let _ = self.reply.send(Err(refusal)).await;
+        }
+    }
 }
 
 impl GameConnector {
-    pub async fn connect(&mut self, player: Greeting) -> Option<DoubleChannel<Vec<u8>>> {
-        if self.request.send(player).await.is_ok() {
-            self.recv.recv().await
-        } else {
-            None
+    pub async fn connect(&mut self, player: Arrival) -> Admission {
+        if self.request.send(player).await.is_err() {
+            return Admission::Closed;
+        }
+        match self.recv.recv().await {
+            Some(Ok(channel)) => Admission::Admitted(channel),
+            Some(Err(refusal)) => Admission::Refused(refusal),
+            None => Admission::Closed,
         }
     }
 }
 
 pub struct GameHandle {
     kind: GameMarker,
-    connect: Mutex<GameConnector>,
-    task_handle: JoinHandle<()>,
+    /// The session of the player who created the game
+    host: SessionToken,
+    host_name: String,
+    /// The arguments the game was created with
+    settings: Vec<u8>,
+    status: watch::Receiver<GameStatus>,
+    connect: Arc<Mutex<GameConnector>>,
+}
+
+/// A game which a player has been let into
+pub struct Entry {
+    pub game: u64,
+    pub marker: GameMarker,
+    /// Whether the player is the one who created the game
+    pub host: bool,
+    pub channel: DoubleChannel<Vec<u8>>,
+}
+
+/// A request to enter or create a game which could not be carried out
+#[derive(thiserror::Error, Debug)]
+pub enum StoreError {
+    #[error("There is no game with that id")]
+    UnknownGame,
+    #[error("This server does not support that gamemode")]
+    UnknownGamemode,
+    #[error("{0}")]
+    InvalidSettings(anyhow::Error),
+    #[error("The game has no room for another player")]
+    Full,
+    #[error("The game no longer holds a place for this player")]
+    SessionExpired,
+    #[error("Another player in the game goes by that name")]
+    NameTaken,
+    #[error("The game has already begun")]
+    Started,
+    #[error("The server cannot hold any more games")]
+    TooManyGames,
+    #[error("The server is shutting down")]
+    ShuttingDown,
+}
+
+impl StoreError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            StoreError::UnknownGame => ErrorKind::UnknownGame,
+            StoreError::UnknownGamemode => ErrorKind::UnknownGamemode,
+            StoreError::InvalidSettings(_) => ErrorKind::InvalidSettings,
+            StoreError::Full => ErrorKind::GameFull,
+            StoreError::SessionExpired => ErrorKind::SessionExpired,
+            StoreError::NameTaken => ErrorKind::NameTaken,
+            StoreError::Started => ErrorKind::GameStarted,
+            StoreError::TooManyGames => ErrorKind::ServerFull,
+            StoreError::ShuttingDown => ErrorKind::ShuttingDown,
+        }
+    }
+}
+
+impl GameHandle {
+    fn listing(&self, id: u64) -> ActiveGame {
+        ActiveGame {
+            marker: self.kind,
+            id,
+            host: self.host_name.clone(),
+            status: self.status.borrow().clone(),
+            settings: self.settings.clone(),
+        }
+    }
+}
+
+/// How many changes to the game list are kept for a subscriber which falls behind, before it has
+/// to be sent the whole list again
+const CHANGE_BACKLOG: usize = 64;
+
+/// How long past the shutdown deadline a game or a connection may go on before it is stopped
+const STOP_MARGIN: Duration = Duration::from_secs(2);
+
+/// Where the results of finished games are kept
+const RESULTS_DIR: &str = "results";
+
+/// What is kept of a game once it is over
+#[derive(Serialize)]
+struct GameRecord<'a> {
+    id: u64,
+    marker: GameMarker,
+    host: &'a str,
+    settings: &'a [u8],
+    result: GameResult,
 }
 
-#[derive(Clone, Default)]
+#[derive(Clone)]
 pub struct GameStore {
     store: Arc<RwLock<HashMap<u64, GameHandle>>>,
+    /// The game that each player who has been let into one belongs to
+    sessions: Arc<RwLock<HashMap<SessionToken, u64>>>,
     generator: Arc<SequenceGenerator>,
+    /// The id that the next player to connect is given
+    next_player: Arc<AtomicU64>,
+    /// Changes to the game list, for players who are choosing a game
+    changes: broadcast::Sender<GameListChange>,
+    config: Arc<ServerConfig>,
+    shutdown: Shutdown,
 }
 
 impl GameStore {
+    pub fn new(config: Arc<ServerConfig>, shutdown: Shutdown) -> Self {
+        Self {
+            store: Default::default(),
+            sessions: Default::default(),
+            generator: Default::default(),
+            next_player: Default::default(),
+            changes: broadcast::channel(CHANGE_BACKLOG).0,
+            config,
+            shutdown,
+        }
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Gives out an id to a player who has just connected, which no other player is given
+    pub fn new_player_id(&self) -> PlayerId {
+        PlayerId(self.next_player.fetch_add(1, Ordering::Relaxed))
+    }
+
     pub async fn list(&self) -> Vec<ActiveGame> {
         self.store
             .read()
             .await
             .iter()
-            .map(|(&id, handle)| ActiveGame {
-                marker: handle.kind,
-                id,
-            })
+            .map(|(&id, handle)| handle.listing(id))
             .collect_vec()
     }
 
-    pub async fn join(&self, game_id: &u64, player: Greeting) -> Option<DoubleChannel<Vec<u8>>> {
-        if let Some(game) = self.store.read().await.get(game_id) {
-            if let Some(chan) = game.connect.lock().await.connect(player).await {
-                Some(chan)
-            } else {
-                if let Some(GameHandle { task_handle, .. }) =
-                    self.store.write().await.remove(game_id)
-                {
-                    task_handle.abort();
+    /// Hears about every game which is created, changes, or ends from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<GameListChange> {
+        self.changes.subscribe()
+    }
+
+    /// Tells subscribers about every change to a game, and once it ends, keeps its result and
+    /// forgets it. A game which outlasts the shutdown of the server is stopped.
+    async fn follow(
+        self,
+        game_id: u64,
+        mut status: watch::Receiver<GameStatus>,
+        mut task: JoinHandle<Option<GameResult>>,
+    ) {
+        let mut shutdown = self.shutdown();
+        let overdue = async move {
+            shutdown.expired().await;
+            sleep(STOP_MARGIN).await
+        };
+        tokio::pin!(overdue);
+        let (mut listed, mut stopped) = (true, false);
+        let ended = loop {
+            tokio::select! {
+                changed = status.changed(), if listed => match changed {
+                    Ok(()) => {
+                        if let Some(handle) = self.store.read().await.get(&game_id) {
+                            // nobody may be subscribed, which is fine
+                            let _ = self
+                                .changes
+                                .send(GameListChange::Updated(handle.listing(game_id)));
+                        }
+                    }
+                    // the game is about to end
+                    Err(_) => listed = false,
+                },
+                ended = &mut task => break ended,
+                _ = &mut overdue, if !stopped => {
+                    log::warn!("Game {game_id} did not end in time for the shutdown, and was stopped");
+                    task.abort();
+                    stopped = true;
                 }
-                None
             }
-        } else {
-            None
+        };
+        match ended {
+            Ok(Some(result)) => self.record(game_id, result).await,
+            Ok(None) => (),
+            Err(e) if e.is_cancelled() => (),
+            Err(e) => log::error!("Game {game_id} failed: {e}"),
+        }
+        self.remove(&game_id).await;
+    }
+
+    /// Writes the result of a game to a new file in the results directory
+    async fn record(&self, game_id: u64, result: GameResult) {
+        let store = self.store.read().await;
+        let Some(handle) = store.get(&game_id) else {
+            return;
+        };
+        let finished = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        storage::write(
+            &format!("{RESULTS_DIR}/{finished}-{game_id}.result"),
+            &GameRecord {
+                id: game_id,
+                marker: handle.kind,
+                host: &handle.host_name,
+                settings: &handle.settings,
+                result,
+            },
+        );
+    }
+
+    /// Waits for every game to end, which they do by the shutdown deadline of the server or soon
+    /// after
+    pub async fn drain(&self) {
+        let mut changes = self.subscribe();
+        while !self.store.read().await.is_empty() {
+            // any change, even a missed one, is a reason to look again
+            let _ = changes.recv().await;
+        }
+    }
+
+    async fn remove(&self, game_id: &u64) {
+        if self.store.write().await.remove(game_id).is_some() {
+            let _ = self.changes.send(GameListChange::Removed(*game_id));
+        }
+        self.sessions
+            .write()
+            .await
+            .retain(|_, session_game| session_game != game_id);
+    }
+
+    pub async fn join(
+        &self,
+        game_id: &u64,
+        id: PlayerId,
+        player: Greeting,
+    ) -> Result<Entry, StoreError> {
+        let arrival = Arrival {
+            id,
+            greeting: player,
+            token: rand::random(),
+            kind: ArrivalKind::Joining,
+        };
+        let token = arrival.token;
+        let entry = self.admit(game_id, arrival, StoreError::Full).await?;
+        self.sessions.write().await.insert(token, *game_id);
+        Ok(entry)
+    }
+
+    /// Lets a player back into the game it lost its connection to, if the game still holds its
+    /// place
+    pub async fn rejoin(
+        &self,
+        token: SessionToken,
+        id: PlayerId,
+        player: Greeting,
+    ) -> Result<Entry, StoreError> {
+        let game_id = *self
+            .sessions
+            .read()
+            .await
+            .get(&token)
+            .ok_or(StoreError::SessionExpired)?;
+        let arrival = Arrival {
+            id,
+            greeting: player,
+            token,
+            kind: ArrivalKind::Returning,
+        };
+        self.admit(&game_id, arrival, StoreError::SessionExpired)
+            .await
+    }
+
+    /// Lets a player watch a game. Spectators have no session, since there is no place in the game
+    /// to hold for them.
+    pub async fn spectate(
+        &self,
+        game_id: &u64,
+        id: PlayerId,
+        player: Greeting,
+    ) -> Result<Entry, StoreError> {
+        let arrival = Arrival {
+            id,
+            greeting: player,
+            token: rand::random(),
+            kind: ArrivalKind::Spectating,
+        };
+        self.admit(game_id, arrival, StoreError::Full).await
+    }
+
+    /// Asks a game to let a player in, failing with `refused` if the game has no room for the
+    /// player
+    async fn admit(
+        &self,
+        game_id: &u64,
+        arrival: Arrival,
+        refused: StoreError,
+    ) -> Result<Entry, StoreError> {
+        // no one enters a game while the server is shutting down, spectators included
+        if self.shutdown.deadline().is_some() {
+            return Err(StoreError::ShuttingDown);
+        }
+        let playing = arrival.kind != ArrivalKind::Spectating;
+        // the game may take its time to answer, which the rest of the store must not wait on
+        let (marker, host, connect) = match self.store.read().await.get(game_id) {
+            Some(game) => (
+                game.kind,
+                playing && game.host == arrival.token,
+                game.connect.clone(),
+            ),
+            None => return Err(StoreError::UnknownGame),
+        };
+        let admission = connect.lock().await.connect(arrival).await;
+        match admission {
+            Admission::Admitted(channel) => Ok(Entry {
+                game: *game_id,
+                marker,
+                host,
+                channel,
+            }),
+            Admission::Refused(Refusal::NoRoom) => Err(refused),
+            Admission::Refused(Refusal::NameTaken) => Err(StoreError::NameTaken),
+            Admission::Refused(Refusal::Started) => Err(StoreError::Started),
+            // the game is forgotten once it has been followed to its end
+            Admission::Closed => Err(StoreError::UnknownGame),
         }
     }
 
@@ -124,27 +488,71 @@ impl GameStore {
         &self,
         game: &GameMarker,
         args: Vec<u8>,
+        id: PlayerId,
         info: Greeting,
-    ) -> Option<DoubleChannel<Vec<u8>>> {
+    ) -> Result<Entry, StoreError> {
+        if !self.config.allows(game) {
+            return Err(StoreError::UnknownGamemode);
+        }
+        if self.shutdown.deadline().is_some() {
+            return Err(StoreError::ShuttingDown);
+        }
+        // held until the game is in the store, so that games created at once cannot go past the
+        // limit together
+        let mut store = self.store.write().await;
+        if store.len() >= self.config.max_games {
+            return Err(StoreError::TooManyGames);
+        }
         if let Some(GameDescriptor { initializer, .. }) = REGISTRY.get(game) {
+            // games created without settings are played with those of the server
+            let args = match self.config.default_args.get(game) {
+                Some(defaults) if args.is_empty() => defaults.clone(),
+                _ => args,
+            };
+            let limits = GameLimits {
+                max_players: self.config.max_players,
+            };
+            let host_name = info.username.clone();
+            let host = Arrival {
+                id,
+                greeting: info,
+                token: rand::random(),
+                kind: ArrivalKind::Joining,
+            };
+            let token = host.token;
             let SessionObjects {
                 host_channel,
                 connector,
                 main_task,
-            } = initializer.create(args, info);
+                status,
+            } = initializer
+                .create(args.clone(), host, limits, self.shutdown())
+                .map_err(StoreError::InvalidSettings)?;
             let key = self.generator.next_id() as u64; //TODO generator reset? Or some way to prevent crashes
 
-            self.store.write().await.insert(
-                key,
-                GameHandle {
-                    kind: *game,
-                    connect: Mutex::new(connector),
-                    task_handle: main_task,
-                },
-            );
-            Some(host_channel)
+            let handle = GameHandle {
+                kind: *game,
+                host: token,
+                host_name,
+                settings: args,
+                status: status.clone(),
+                connect: Arc::new(Mutex::new(connector)),
+            };
+            let _ = self
+                .changes
+                .send(GameListChange::Updated(handle.listing(key)));
+            store.insert(key, handle);
+            drop(store);
+            self.sessions.write().await.insert(token, key);
+            tokio::spawn(self.clone().follow(key, status, main_task));
+            Ok(Entry {
+                game: key,
+                marker: *game,
+                host: true,
+                channel: host_channel,
+            })
         } else {
-            None
+            Err(StoreError::UnknownGamemode)
         }
     }
 }
@@ -152,32 +560,327 @@ impl GameStore {
 pub struct App {
     games: GameStore,
     listener: TcpListener,
+    shutdown: ShutdownTrigger,
 }
 
 impl App {
-    pub async fn new(address: String) -> Self {
-        let listener = TcpListener::bind((address, 0)).await.unwrap();
-        log::debug!("Server open at {}", listener.local_addr().unwrap());
+    pub async fn new(config: ServerConfig) -> std::io::Result<Self> {
+        let listener = TcpListener::bind((config.bind, config.port)).await?;
+        log::info!("Server open at {}", listener.local_addr()?);
+        let (shutdown, shutdown_receiver) = Shutdown::new();
 
-        Self {
-            games: Default::default(),
+        Ok(Self {
+            games: GameStore::new(Arc::new(config), shutdown_receiver),
             listener,
-        }
+            shutdown,
+        })
     }
 
+    pub fn address(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections until the process is asked to stop, and then shuts the server down
     pub async fn run(self) {
-        while let Ok((sock, _addr)) = self.listener.accept().await {
-            let games = self.games.clone();
-            tokio::spawn(async {
-                let sock = Connection(tungsten::accept_async(sock).await?);
-                match sock.upgrade(games).await {
-                    Ok(mut player) => {
-                        player.enter().await;
-                        Ok(())
-                    }
-                    Err(e) => Err(e),
+        self.run_until(stop_signal()).await
+    }
+
+    /// Accepts connections until `stop` completes, and then shuts the server down
+    pub async fn run_until(self, stop: impl Future<Output = ()>) {
+        match self.address() {
+            Ok(address) => {
+                tokio::spawn(discovery::announce(
+                    address,
+                    self.games.config().name.clone(),
+                    self.games.clone(),
+                ));
+            }
+            Err(e) => log::warn!("Could not find the address the server is open at: {e}"),
+        }
+        let mut connections = JoinSet::new();
+        tokio::pin!(stop);
+        loop {
+            tokio::select! {
+                accepted = self.listener.accept() => {
+                    let Ok((sock, _addr)) = accepted else {
+                        break;
+                    };
+                    let games = self.games.clone();
+                    let limits = games.config().limits;
+                    let idle_timeout = games.config().idle_timeout;
+                    connections.spawn(async move {
+                        let sock = tungsten::accept_async_with_config(sock, Some(limits.websocket()));
+                        // a client which never gets through the handshake is not waited on forever
+                        let Ok(sock) = timeout(idle_timeout, sock).await else {
+                            log::debug!("A new connection did not open its websocket in time");
+                            return Ok(());
+                        };
+                        let sock = Connection::new(sock?, limits);
+                        match sock.upgrade(games).await {
+                            Ok(mut player) => {
+                                player.enter().await;
+                                Ok(())
+                            }
+                            Err(e) => Err(e),
+                        }
+                    });
                 }
-            });
+                // connections are let go of as they end
+                Some(_) = connections.join_next() => (),
+                _ = &mut stop => break,
+            }
+        }
+        drop(self.listener);
+
+        let grace = self.games.config().shutdown_grace;
+        log::info!("Shutting down, giving games {}s to finish", grace.as_secs());
+        let deadline = self.shutdown.begin(grace);
+        self.games.drain().await;
+        // players are let go of at the deadline, and any which are slow about it are cut off
+        let players = async { while connections.join_next().await.is_some() {} };
+        if timeout_at(deadline + STOP_MARGIN, players).await.is_err() {
+            log::debug!("Some connections did not close in time");
+        }
+        log::info!("The server has shut down");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures_util::future::join_all;
+    use tokio::{io::AsyncReadExt, net::TcpStream, time::timeout};
+    use tungstenite::protocol::frame::coding::CloseCode;
+
+    use crate::{
+        area_attack::AREA_ATTACK_MARKER,
+        server::{ClientMessage, ConnectionLimits, ErrorKind, KickReason, ServerMessage},
+        server_v2::{
+            config::ServerConfig,
+            testing::{TestClient, TestServer},
+        },
+    };
+
+    /// Creates a game with the default settings, returning its id
+    async fn create_game(client: &mut TestClient) -> u64 {
+        client
+            .send(ClientMessage::Create {
+                game: AREA_ATTACK_MARKER,
+                args: Vec::new(),
+            })
+            .await;
+        client
+            .wait_for(|message| match message {
+                ServerMessage::Joined { game, .. } => Some(game),
+                _ => None,
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn dropped_clients_are_let_go() {
+        let server = TestServer::start(ServerConfig::default()).await;
+        let mut host = TestClient::connect(server.address, "host").await;
+        let game = create_game(&mut host).await;
+        drop(host);
+
+        // the server goes on as before, and the game keeps the place of its host
+        let mut other = TestClient::connect(server.address, "other").await;
+        other.send_bytes(vec![0xc1]).await;
+        let kind = other
+            .wait_for(|message| match message {
+                ServerMessage::Error { kind, .. } => Some(kind),
+                _ => None,
+            })
+            .await;
+        assert_eq!(kind, ErrorKind::Unreadable);
+        other.send(ClientMessage::Games).await;
+        let games = other
+            .wait_for(|message| match message {
+                ServerMessage::ActiveGames(games) => Some(games),
+                _ => None,
+            })
+            .await;
+        assert!(games.iter().any(|listed| listed.id == game));
+    }
+
+    #[tokio::test]
+    async fn silent_clients_time_out() {
+        let server = TestServer::start(ServerConfig {
+            idle_timeout: Duration::from_millis(300),
+            ..Default::default()
+        })
+        .await;
+        let mut silent = TestClient::connect(server.address, "silent").await;
+        let mut lively = TestClient::connect(server.address, "lively").await;
+
+        // only the client which reads answers the pings of the server
+        lively.linger(Duration::from_secs(1)).await;
+        let frame = silent.closed().await.expect("no reason was given");
+        assert_eq!(frame.code, CloseCode::Policy);
+
+        lively.send(ClientMessage::Games).await;
+        lively
+            .wait_for(|message| matches!(message, ServerMessage::ActiveGames(_)).then_some(()))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn clients_which_never_greet_are_let_go() {
+        let server = TestServer::start(ServerConfig {
+            idle_timeout: Duration::from_millis(300),
+            ..Default::default()
+        })
+        .await;
+
+        // neither a socket which never opens a websocket, nor a websocket which never greets the
+        // server, is kept open
+        let mut raw = TcpStream::connect(server.address).await.unwrap();
+        let mut scrap = [0; 1024];
+        let ended = timeout(Duration::from_secs(5), async {
+            while raw.read(&mut scrap).await.is_ok_and(|read| read > 0) {}
+        })
+        .await;
+        assert!(ended.is_ok(), "the raw socket was kept open");
+
+        let mut silent = TestClient::connect_silently(server.address).await;
+        let frame = silent.closed().await.expect("no reason was given");
+        assert_eq!(frame.code, CloseCode::Policy);
+    }
+
+    #[tokio::test]
+    async fn flooding_clients_are_kicked() {
+        let server = TestServer::start(ServerConfig {
+            limits: ConnectionLimits {
+                messages_per_second: 10,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .await;
+        let mut flooding = TestClient::connect(server.address, "flooding").await;
+        for _ in 0..30 {
+            flooding.send(ClientMessage::Games).await;
         }
+        let reason = flooding
+            .wait_for(|message| match message {
+                ServerMessage::Kicked(reason) => Some(reason),
+                _ => None,
+            })
+            .await;
+        assert_eq!(reason, KickReason::TooManyMessages);
+        let frame = flooding.closed().await.expect("no reason was given");
+        assert_eq!(frame.code, CloseCode::Policy);
+
+        // everyone else is left alone
+        let mut other = TestClient::connect(server.address, "other").await;
+        other.send(ClientMessage::Games).await;
+        other
+            .wait_for(|message| matches!(message, ServerMessage::ActiveGames(_)).then_some(()))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_are_refused() {
+        let server = TestServer::start(ServerConfig {
+            limits: ConnectionLimits {
+                max_message_size: 1024,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .await;
+        let mut client = TestClient::connect(server.address, "client").await;
+        client.send_bytes(vec![0; 4096]).await;
+        let reason = client
+            .wait_for(|message| match message {
+                ServerMessage::Kicked(reason) => Some(reason),
+                _ => None,
+            })
+            .await;
+        assert_eq!(reason, KickReason::MessageTooLarge);
+        let frame = client.closed().await.expect("no reason was given");
+        assert_eq!(frame.code, CloseCode::Size);
+    }
+
+    #[tokio::test]
+    async fn games_created_at_once_stay_within_the_limit() {
+        let server = TestServer::start(ServerConfig {
+            max_games: 2,
+            ..Default::default()
+        })
+        .await;
+        let mut hosts = Vec::new();
+        for i in 0..6 {
+            hosts.push(TestClient::connect(server.address, &format!("host {i}")).await);
+        }
+        let created = join_all(hosts.iter_mut().map(|host| async move {
+            host.send(ClientMessage::Create {
+                game: AREA_ATTACK_MARKER,
+                args: Vec::new(),
+            })
+            .await;
+            host.wait_for(|message| match message {
+                ServerMessage::Joined { .. } => Some(true),
+                ServerMessage::JoinRejected { kind, .. } => {
+                    assert_eq!(kind, ErrorKind::ServerFull);
+                    Some(false)
+                }
+                _ => None,
+            })
+            .await
+        }))
+        .await;
+        assert_eq!(created.iter().filter(|&&created| created).count(), 2);
+    }
+
+    #[tokio::test]
+    async fn players_are_warned_of_shutdown() {
+        let server = TestServer::start(ServerConfig {
+            shutdown_grace: Duration::from_secs(1),
+            ..Default::default()
+        })
+        .await;
+        let mut host = TestClient::connect(server.address, "host").await;
+        let game = create_game(&mut host).await;
+        let mut watcher = TestClient::connect(server.address, "watcher").await;
+        let address = server.address;
+
+        let stopping = tokio::spawn(server.stop(Duration::from_secs(5)));
+        let seconds = host
+            .wait_for(|message| match message {
+                ServerMessage::ShuttingDown { seconds } => Some(seconds),
+                _ => None,
+            })
+            .await;
+        assert!(seconds <= 1);
+
+        // no one may enter a game while the server is shutting down
+        host.send(ClientMessage::Create {
+            game: AREA_ATTACK_MARKER,
+            args: Vec::new(),
+        })
+        .await;
+        let kind = host
+            .wait_for(|message| match message {
+                ServerMessage::JoinRejected { kind, .. } => Some(kind),
+                _ => None,
+            })
+            .await;
+        assert_eq!(kind, ErrorKind::ShuttingDown);
+        watcher.send(ClientMessage::Spectate { game }).await;
+        let kind = watcher
+            .wait_for(|message| match message {
+                ServerMessage::JoinRejected { kind, .. } => Some(kind),
+                _ => None,
+            })
+            .await;
+        assert_eq!(kind, ErrorKind::ShuttingDown);
+
+        let frame = host.closed().await.expect("no reason was given");
+        assert_eq!(frame.code, CloseCode::Away);
+        stopping.await.unwrap();
+        assert!(tokio::net::TcpStream::connect(address).await.is_err());
     }
 }
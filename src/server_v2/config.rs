@@ -0,0 +1,297 @@
+//! Settings of the server itself, read from a TOML file. Every entry of the file may be left out,
+//! in which case its default is used. An example:
+//!
+//! ```toml
+//! name = "Office server"
+//! bind = "0.0.0.0"
+//! port = 41924
+//! max_games = 8
+//! max_players = 4
+//! gamemodes = ["Area Attack"]
+//! log_level = "info"
+//! # how long games are given to finish once the server is asked to stop
+//! shutdown_grace = 60
+//! # how long a client may go without being heard from before its connection is closed
+//! idle_timeout = 30
+//! # what a single client may send, past which it is kicked
+//! max_message_size = 65536
+//! messages_per_second = 60
+//! bytes_per_second = 65536
+//!
+//! # used by games which are created without settings of their own
+//! [default_settings."Area Attack"]
+//! mines = { Exact = 120 }
+//! attack_begins = { secs = 120 }
+//! ```
+
+use std::{collections::HashMap, net::IpAddr, path::Path, time::Duration};
+
+use anyhow::{anyhow, Context};
+use log::LevelFilter;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    registry::REGISTRY,
+    server::{ConnectionLimits, GameMarker},
+};
+
+use super::discovery::SERVER_NAME;
+
+/// The port that the server accepts connections at, unless told otherwise
+pub const DEFAULT_PORT: u16 = 41924;
+/// The file that the configuration is read from, unless told otherwise
+pub const CONFIG_FILE: &str = "quicksweeper-server.toml";
+
+/// The configuration file as it is written
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    name: String,
+    bind: IpAddr,
+    port: u16,
+    max_games: usize,
+    max_players: u8,
+    /// Names of the gamemodes which may be played. Every gamemode may be played if none are given.
+    gamemodes: Option<Vec<String>>,
+    /// Settings of games which are created without any, by the name of their gamemode
+    default_settings: HashMap<String, toml::Value>,
+    log_level: String,
+    /// In seconds
+    shutdown_grace: u64,
+    /// In seconds
+    idle_timeout: u64,
+    /// In bytes
+    max_message_size: usize,
+    messages_per_second: u32,
+    bytes_per_second: u32,
+}
+
+impl Default for ConfigFile {
+    fn default() -> Self {
+        let limits = ConnectionLimits::default();
+        Self {
+            name: SERVER_NAME.to_string(),
+            bind: [0, 0, 0, 0].into(),
+            port: DEFAULT_PORT,
+            max_games: 64,
+            max_players: u8::MAX,
+            gamemodes: None,
+            default_settings: HashMap::new(),
+            log_level: "debug".to_string(),
+            shutdown_grace: 30,
+            idle_timeout: 30,
+            max_message_size: limits.max_message_size,
+            messages_per_second: limits.messages_per_second,
+            bytes_per_second: limits.bytes_per_second,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// The name the server announces itself with on the local network
+    pub name: String,
+    pub bind: IpAddr,
+    pub port: u16,
+    /// How many games may be played at once
+    pub max_games: usize,
+    /// How many players a single game may hold, whatever its settings ask for
+    pub max_players: u8,
+    pub gamemodes: Vec<GameMarker>,
+    /// The arguments of games which are created without any, by gamemode
+    pub default_args: HashMap<GameMarker, Vec<u8>>,
+    pub log_level: LevelFilter,
+    /// How long the games which are running are given to finish once the server is asked to stop
+    pub shutdown_grace: Duration,
+    /// How long a client may go without being heard from, pings included, before its connection
+    /// is closed
+    pub idle_timeout: Duration,
+    /// What a single client may send to the server
+    pub limits: ConnectionLimits,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ConfigFile::default().resolve().unwrap()
+    }
+}
+
+impl ServerConfig {
+    /// Reads the configuration from the given file. Without one, the default file is read if it
+    /// exists, and the defaults are used if it does not.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(CONFIG_FILE).exists() => Path::new(CONFIG_FILE),
+            None => return Ok(Self::default()),
+        };
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        toml::from_str::<ConfigFile>(&text)
+            .map_err(anyhow::Error::from)
+            .and_then(ConfigFile::resolve)
+            .with_context(|| format!("Could not use the configuration in {}", path.display()))
+    }
+
+    pub fn allows(&self, gamemode: &GameMarker) -> bool {
+        self.gamemodes.contains(gamemode)
+    }
+}
+
+impl ConfigFile {
+    /// Checks the file, and looks up the gamemodes that it names
+    fn resolve(self) -> anyhow::Result<ServerConfig> {
+        let gamemode = |name: &str| {
+            REGISTRY
+                .iter()
+                .find(|(_, descriptor)| descriptor.name == name)
+                .map(|(&marker, _)| marker)
+                .ok_or_else(|| anyhow!("There is no gamemode named {name}"))
+        };
+        let gamemodes = match self.gamemodes {
+            Some(names) => names
+                .iter()
+                .map(|name| gamemode(name))
+                .collect::<anyhow::Result<_>>()?,
+            None => REGISTRY.keys().copied().collect(),
+        };
+        let default_args = self
+            .default_settings
+            .into_iter()
+            .map(|(name, settings)| {
+                let marker = gamemode(&name)?;
+                let args = REGISTRY[&marker]
+                    .initializer
+                    .default_args(settings)
+                    .with_context(|| format!("The default settings of {name} cannot be used"))?;
+                Ok((marker, args))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        if self.max_games == 0 {
+            return Err(anyhow!("The server must be allowed at least one game"));
+        }
+        if self.max_players == 0 {
+            return Err(anyhow!("Games must be allowed at least one player"));
+        }
+        if self.idle_timeout == 0 {
+            return Err(anyhow!("Clients must be given some time to answer"));
+        }
+        if self.messages_per_second == 0 {
+            return Err(anyhow!("Clients must be allowed to send messages"));
+        }
+        if (self.bytes_per_second as usize) < self.max_message_size {
+            return Err(anyhow!(
+                "Clients must be allowed to send a message of the largest size every second"
+            ));
+        }
+        Ok(ServerConfig {
+            name: self.name,
+            bind: self.bind,
+            port: self.port,
+            max_games: self.max_games,
+            max_players: self.max_players,
+            gamemodes,
+            default_args,
+            log_level: self
+                .log_level
+                .parse()
+                .map_err(|_| anyhow!("{} is not a log level", self.log_level))?,
+            shutdown_grace: Duration::from_secs(self.shutdown_grace),
+            idle_timeout: Duration::from_secs(self.idle_timeout),
+            limits: ConnectionLimits {
+                max_message_size: self.max_message_size,
+                messages_per_second: self.messages_per_second,
+                bytes_per_second: self.bytes_per_second,
+            },
+        })
+    }
+}
+
+/// Applies the entries of a TOML table on top of `base`, leaving the rest of `base` as it is.
+/// Tables within the table are applied in the same way, unless they have entries which `base` does
+/// not, as when another variant of an enum is chosen. Those replace what was there instead.
+pub fn overlay<T>(base: &T, overrides: toml::Value) -> anyhow::Result<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    // toml cannot represent every value that settings hold, such as enum variants with data, so
+    // the settings are merged as JSON instead
+    fn merge(base: &mut serde_json::Value, overrides: serde_json::Value) {
+        match (base, overrides) {
+            (serde_json::Value::Object(base), serde_json::Value::Object(overrides))
+                if overrides.keys().all(|key| base.contains_key(key)) =>
+            {
+                for (key, value) in overrides {
+                    match base.get_mut(&key) {
+                        Some(existing) => merge(existing, value),
+                        None => {
+                            base.insert(key, value);
+                        }
+                    }
+                }
+            }
+            (base, overrides) => *base = overrides,
+        }
+    }
+
+    let mut merged = serde_json::to_value(base)?;
+    merge(&mut merged, serde_json::to_value(overrides)?);
+    Ok(serde_json::from_value(merged)?)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        area_attack::{AreaAttackSettings, AREA_ATTACK_MARKER},
+        minefield::MineCount,
+    };
+
+    use super::{overlay, ConfigFile, DEFAULT_PORT};
+
+    #[test]
+    fn defaults_are_overlaid() {
+        let overrides = toml::from_str(
+            r#"
+            field = "square"
+            mines = { Exact = 120 }
+            attack_begins = { secs = 120 }
+            "#,
+        )
+        .unwrap();
+        let settings = overlay(&AreaAttackSettings::default(), overrides).unwrap();
+        assert_eq!(
+            settings,
+            AreaAttackSettings {
+                field: Some("square".to_string()),
+                mines: MineCount::Exact(120),
+                attack_begins: Duration::from_secs(120),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn config_is_resolved() {
+        let file: ConfigFile = toml::from_str(
+            r#"
+            name = "Office server"
+            gamemodes = ["Area Attack"]
+            log_level = "warn"
+            "#,
+        )
+        .unwrap();
+        let config = file.resolve().unwrap();
+        assert_eq!(config.name, "Office server");
+        assert_eq!(config.port, DEFAULT_PORT);
+        assert_eq!(config.gamemodes, vec![AREA_ATTACK_MARKER]);
+        assert_eq!(config.log_level, log::LevelFilter::Warn);
+
+        let file: ConfigFile = toml::from_str(r#"gamemodes = ["Chess"]"#).unwrap();
+        assert!(file.resolve().is_err());
+        let file: ConfigFile = toml::from_str("max_games = 0").unwrap();
+        assert!(file.resolve().is_err());
+        assert!(toml::from_str::<ConfigFile>("colour = 3").is_err());
+    }
+}
@@ -1,28 +1,68 @@
+use std::{io, time::Duration};
+
 use futures_util::{Sink, SinkExt, Stream, StreamExt};
 use serde::{de::DeserializeOwned, Serialize};
-use tokio::net::TcpStream;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{timeout, Instant},
+};
 use tokio_tungstenite::WebSocketStream;
-use tungstenite::Message;
+pub use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::{protocol::CloseFrame, Message};
+
+use crate::server::{
+    ConnectionLimits, Greeting, HandshakeRejection, HandshakeReply, KickReason, MessageError,
+    RateLimiter,
+};
 
-use crate::server::MessageError;
+use super::{app::GameStore, Activity, Player};
 
-use super::{app::GameStore, Player};
+/// How long a client is given to answer a close frame before its connection is dropped anyway
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
 
-pub struct Connection(pub WebSocketStream<TcpStream>);
+pub struct Connection {
+    socket: WebSocketStream<TcpStream>,
+    limiter: RateLimiter,
+    /// Whether a frame was refused for its size, and so left unread
+    oversized: bool,
+}
 
 impl Connection {
+    /// Wraps the socket of a client, which should have been accepted with the socket
+    /// configuration of `limits`
+    pub fn new(socket: WebSocketStream<TcpStream>, limits: ConnectionLimits) -> Self {
+        Self {
+            socket,
+            limiter: RateLimiter::new(limits),
+            oversized: false,
+        }
+    }
+
+    /// Reads the next frame from the client, which is nothing if it is not a message, such as a
+    /// ping. A client which closes the connection gives [tungstenite::Error::ConnectionClosed],
+    /// and one which is lost gives any other error of the socket. A client which sends more than
+    /// it is allowed gives [MessageError::Kicked], and should be closed.
     pub async fn recv_message<D>(&mut self) -> Option<Result<D, MessageError>>
     where
         D: DeserializeOwned,
     {
-        // TODO when connection is closed, this will yield none -- handle gracefully
-        let msg = match self.0.next().await.unwrap() {
-            Ok(msg) => Some(msg),
-            Err(e) => return Some(Err(e.into())),
-        }?;
+        let msg = match self.socket.next().await {
+            Some(Ok(msg)) => msg,
+            Some(Err(tungstenite::Error::Capacity(_))) => {
+                self.oversized = true;
+                return Some(Err(KickReason::MessageTooLarge.into()));
+            }
+            Some(Err(e)) => return Some(Err(e.into())),
+            None => return Some(Err(tungstenite::Error::AlreadyClosed.into())),
+        };
+        if let Err(reason) = self.limiter.admit(msg.len(), Instant::now().into_std()) {
+            return Some(Err(reason.into()));
+        }
 
         match msg {
             Message::Ping(_) | Message::Pong(_) => None,
+            Message::Close(_) => Some(Err(tungstenite::Error::ConnectionClosed.into())),
             Message::Binary(v) => {
                 Some(rmp_serde::from_slice(&v).map_err(MessageError::Deserialization))
             }
@@ -38,25 +78,125 @@ impl Connection {
         Ok(())
     }
 
+    /// Closes the connection, telling the client why, and waits a little for the client to
+    /// answer. A connection which the client has already closed is only flushed, so that the
+    /// answer to its close frame reaches it.
+    pub async fn close(&mut self, code: CloseCode, reason: &str) {
+        let frame = CloseFrame {
+            code,
+            reason: reason.to_string().into(),
+        };
+        match self.socket.close(Some(frame)).await {
+            Ok(())
+            | Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {}
+            Err(e) => {
+                log::debug!("Could not close a connection cleanly: {e}");
+                return;
+            }
+        }
+        // the socket ends once the client has answered, and dropping it before then would cut off
+        // the close frame if the client is still sending
+        let answered = async {
+            while let Some(Ok(_)) = self.socket.next().await {}
+            // for the same reason, what is left of a frame which was too large to read is read past
+            if self.oversized {
+                let stream = self.socket.get_mut();
+                let _ = stream.shutdown().await;
+                let mut scrap = [0; 1024];
+                while stream.read(&mut scrap).await.is_ok_and(|read| read > 0) {}
+            }
+        };
+        let _ = timeout(CLOSE_TIMEOUT, answered).await;
+    }
+
+    /// Asks the client to show that it is still there, which it does by answering with a pong
+    pub async fn ping(&mut self) -> Result<(), MessageError> {
+        self.socket.send(Message::Ping(Vec::new())).await?;
+        Ok(())
+    }
+
+    /// Answers the greeting of a newly connected client, which becomes a player if the server is
+    /// able to talk to it
     pub async fn upgrade(mut self, game_list: GameStore) -> Result<Player, MessageError> {
-        loop {
-            if let Some(msg) = self.recv_message().await {
-                if let Ok(address) = self.0.get_ref().peer_addr() {
-                    log::debug!("Connection from {address} was successfully upgraded and can now join games");
-                } else {
-                    log::debug!("Connection from unknown source was successfully upgraded and can now join games")
+        let greeting = async {
+            loop {
+                if let Some(msg) = self.recv_message::<Greeting>().await {
+                    break msg;
                 }
-                break match msg {
-                    Ok(greeting) => Ok(Player {
-                        socket: self,
-                        info: greeting,
-                        game_list,
-                        game_channel: None,
-                    }),
-                    Err(e) => Err(e),
-                };
             }
+        };
+        let greeting = match timeout(game_list.config().idle_timeout, greeting).await {
+            Ok(greeting) => greeting,
+            Err(_) => {
+                log::debug!("A new connection did not greet the server in time");
+                self.close(
+                    CloseCode::Policy,
+                    "The client did not greet the server in time",
+                )
+                .await;
+                return Err(tungstenite::Error::Io(io::ErrorKind::TimedOut.into()).into());
+            }
+        };
+        let greeting = match greeting {
+            Ok(greeting) => greeting,
+            Err(MessageError::Kicked(reason)) => {
+                log::info!("Kicked a new connection: {reason}");
+                self.close(reason.close_code(), &reason.to_string()).await;
+                return Err(reason.into());
+            }
+            Err(e) if e.disconnected() => return Err(e),
+            Err(e) => {
+                log::debug!("Could not read the greeting of a new connection: {e}");
+                return Err(self.reject(HandshakeRejection::Unreadable).await);
+            }
+        };
+        // gamemodes which are not played on this server are as good as unknown to it
+        let gamemodes = greeting.check().and_then(|mut gamemodes| {
+            gamemodes.retain(|marker| game_list.config().allows(marker));
+            if gamemodes.is_empty() {
+                Err(HandshakeRejection::NoCommonGamemodes)
+            } else {
+                Ok(gamemodes)
+            }
+        });
+        let gamemodes = match gamemodes {
+            Ok(gamemodes) => gamemodes,
+            Err(reason) => {
+                log::debug!("Turned {} away: {reason}", greeting.username);
+                return Err(self.reject(reason).await);
+            }
+        };
+        let greeting = Greeting {
+            gamemodes: gamemodes.clone(),
+            ..greeting
+        };
+        self.send_ser(HandshakeReply::Accepted { gamemodes })
+            .await?;
+
+        if let Ok(address) = self.socket.get_ref().peer_addr() {
+            log::debug!(
+                "Connection from {address} was successfully upgraded and can now join games"
+            );
+        } else {
+            log::debug!(
+                "Connection from unknown source was successfully upgraded and can now join games"
+            )
         }
+        Ok(Player {
+            id: game_list.new_player_id(),
+            socket: self,
+            info: greeting,
+            game_list,
+            activity: Activity::Browsing { subscription: None },
+            last_heard: Instant::now(),
+        })
+    }
+
+    async fn reject(&mut self, reason: HandshakeRejection) -> MessageError {
+        let _ = self
+            .send_ser(HandshakeReply::Rejected(reason.clone()))
+            .await;
+        reason.into()
     }
 }
 
@@ -67,7 +207,7 @@ impl Stream for Connection {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        self.0.poll_next_unpin(cx)
+        self.socket.poll_next_unpin(cx)
     }
 }
 
@@ -78,24 +218,24 @@ impl Sink<Vec<u8>> for Connection {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), Self::Error>> {
-        self.0.poll_ready_unpin(cx)
+        self.socket.poll_ready_unpin(cx)
     }
 
     fn start_send(mut self: std::pin::Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
-        self.0.start_send_unpin(Message::Binary(item))
+        self.socket.start_send_unpin(Message::Binary(item))
     }
 
     fn poll_flush(
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), Self::Error>> {
-        self.0.poll_flush_unpin(cx)
+        self.socket.poll_flush_unpin(cx)
     }
 
     fn poll_close(
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), Self::Error>> {
-        self.0.poll_close_unpin(cx)
+        self.socket.poll_close_unpin(cx)
     }
 }
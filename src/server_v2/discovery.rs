@@ -0,0 +1,47 @@
+//! Announcing the server on the local network, so that players there can find it without being
+//! told its address
+
+use std::net::{Ipv4Addr, SocketAddr};
+
+use tokio::{net::UdpSocket, time::interval};
+
+use crate::server::{Announcement, ANNOUNCE_INTERVAL, DISCOVERY_PORT, PROTOCOL_VERSION};
+
+use super::app::GameStore;
+
+/// The name a server announces itself with, unless it is configured with another
+pub const SERVER_NAME: &str = "Quicksweeper server";
+
+/// Broadcasts an [Announcement] of the server listening at `address` until the server shuts down
+pub async fn announce(address: SocketAddr, name: String, games: GameStore) {
+    // sent from the address of the server, which is where clients take it to be
+    let socket = match UdpSocket::bind((address.ip(), 0)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::warn!("Could not announce the server on the local network: {e}");
+            return;
+        }
+    };
+    if let Err(e) = socket.set_broadcast(true) {
+        log::warn!("Could not announce the server on the local network: {e}");
+        return;
+    }
+
+    let mut ticker = interval(ANNOUNCE_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let announcement = Announcement {
+            version: PROTOCOL_VERSION,
+            name: name.clone(),
+            port: address.port(),
+            games: games.list().await.len() as u32,
+        };
+        let destination = (Ipv4Addr::BROADCAST, DISCOVERY_PORT);
+        if let Err(e) = socket
+            .send_to(&rmp_serde::to_vec(&announcement).unwrap(), destination)
+            .await
+        {
+            log::debug!("Could not send an announcement: {e}");
+        }
+    }
+}
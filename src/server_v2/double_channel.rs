@@ -1,14 +1,17 @@
 use tokio::sync::mpsc::{self, error::SendError};
 
+/// How many messages may wait in each direction of a channel before senders are held back
+pub const CHANNEL_CAPACITY: usize = 256;
+
 pub struct DoubleChannel<T> {
-    sender: mpsc::UnboundedSender<T>,
-    receiver: mpsc::UnboundedReceiver<T>,
+    sender: mpsc::Sender<T>,
+    receiver: mpsc::Receiver<T>,
 }
 
 impl<T> DoubleChannel<T> {
     pub fn double() -> (Self, Self) {
-        let (tx1, rx1) = mpsc::unbounded_channel();
-        let (tx2, rx2) = mpsc::unbounded_channel();
+        let (tx1, rx1) = mpsc::channel(CHANNEL_CAPACITY);
+        let (tx2, rx2) = mpsc::channel(CHANNEL_CAPACITY);
 
         (
             Self {
@@ -22,12 +25,13 @@ impl<T> DoubleChannel<T> {
         )
     }
 
-    pub fn sender(&self) -> mpsc::UnboundedSender<T> {
+    pub fn sender(&self) -> mpsc::Sender<T> {
         self.sender.clone()
     }
 
-    pub fn send(&mut self, message: T) -> Result<(), SendError<T>> {
-        self.sender.send(message)
+    /// Sends a message, waiting for the other side to make room for it if the channel is full
+    pub async fn send(&mut self, message: T) -> Result<(), SendError<T>> {
+        self.sender.send(message).await
     }
 
     pub async fn recv(&mut self) -> Option<T> {
@@ -1,21 +1,30 @@
-use itertools::Itertools;
+use std::collections::HashMap;
+
 use once_cell::sync::Lazy;
 
 use crate::minefield::FieldShape;
 
-pub static FIELDS: Lazy<Vec<FieldShape>> = Lazy::new(|| {
+/// Every field shape the server knows of, named by the file it was loaded from
+pub static FIELDS: Lazy<HashMap<String, FieldShape>> = Lazy::new(|| {
     std::fs::read_dir("assets/fields")
         .map(|dir| {
             dir.flat_map(|entry| {
                 entry
                     .map_err(|_| ())
                     .and_then(|u| {
+                        let name = u
+                            .path()
+                            .file_stem()
+                            .ok_or(())?
+                            .to_string_lossy()
+                            .into_owned();
                         FieldShape::try_from(std::fs::read(u.path()).unwrap().as_slice())
+                            .map(|shape| (name, shape))
                             .map_err(|_| ())
                     })
                     .ok()
             })
-            .collect_vec()
+            .collect()
         })
-        .unwrap_or(Vec::new())
+        .unwrap_or_default()
 });
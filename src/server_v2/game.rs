@@ -1,8 +1,13 @@
-use tokio::task::JoinHandle;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::watch, task::JoinHandle};
 
-use crate::server::Greeting;
+use crate::server::GameStatus;
 
-use super::{app::GameConnector, double_channel::DoubleChannel};
+use super::{
+    app::{Arrival, GameConnector},
+    double_channel::DoubleChannel,
+    shutdown::Shutdown,
+};
 
 pub struct SessionObjects {
     /// The channel with which the game communicates as a host. This object has no explicit
@@ -10,14 +15,63 @@ pub struct SessionObjects {
     /// player that initialized the game.
     pub host_channel: DoubleChannel<Vec<u8>>,
     pub connector: GameConnector,
-    pub main_task: JoinHandle<()>,
+    /// The task which plays the game, and which ends with the result of the game, if there is
+    /// anything worth keeping of it
+    pub main_task: JoinHandle<Option<GameResult>>,
+    /// How the game is getting on, which the game keeps up to date for the game listing
+    pub status: watch::Receiver<GameStatus>,
 }
 
 /// Trait which provides the initialization behavior (and thus the general behavior) of a particular
 /// gamemode. The sole function is meant to spawn a task which provides the main behavior of the
 /// game, and return the join handle for that task as well as some objects for manipulation of the
-/// game. Since it is meant to spawn a task, this function must be called from within a tokio
-/// context.
+/// game. If the parameters cannot be used to create a game, an error is returned instead, which is
+/// reported to the player. Since it is meant to spawn a task, this function must be called from
+/// within a tokio context.
+///
+/// Players who lose their connection may ask to be let back in with the token they arrived with.
+/// It is up to the game how long it holds their place, and to refuse them once it no longer does.
+/// A player who leaves for good sends [LEAVE_NOTICE] before its channel closes, and needs no place
+/// held for it.
+/// Spectators are let in the same way, but should only be shown the game and never take part in it.
+///
+/// The server may limit games further than their settings do, which the game is told of through
+/// [GameLimits].
+///
+/// Once the server begins shutting down, the game should end as soon as it sensibly can, and by
+/// the deadline of the [Shutdown] at the latest. A game which is still running a little after the
+/// deadline is stopped, and its result is lost.
 pub trait GamemodeInitializer: Send + Sync {
-    fn create(&self, params: Vec<u8>, initializer: Greeting) -> SessionObjects;
+    fn create(
+        &self,
+        params: Vec<u8>,
+        host: Arrival,
+        limits: GameLimits,
+        shutdown: Shutdown,
+    ) -> anyhow::Result<SessionObjects>;
+
+    /// Turns the settings written in the configuration of the server into the parameters of a game.
+    /// Settings which are left out keep their defaults.
+    fn default_args(&self, settings: toml::Value) -> anyhow::Result<Vec<u8>>;
+}
+
+/// Sent to a game by a player who is leaving it for good, rather than losing its connection. The
+/// server refuses empty game messages from clients, so it cannot be mistaken for one.
+pub const LEAVE_NOTICE: Vec<u8> = Vec::new();
+
+/// Limits that the server places on every game, whatever its settings ask for
+#[derive(Clone, Copy, Debug)]
+pub struct GameLimits {
+    pub max_players: u8,
+}
+
+/// How a game went, which the server keeps once the game is over
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GameResult {
+    /// The stage that the game ended in
+    pub stage: String,
+    /// Whether the game was cut short by the server shutting down
+    pub interrupted: bool,
+    /// The username and score of every player who was still in the game, best first
+    pub standings: Vec<(String, u32)>,
 }
@@ -1,100 +1,386 @@
-use futures_util::SinkExt;
+use std::time::Duration;
+
 use simple_logger::SimpleLogger;
-use tokio::runtime::Runtime;
+use tokio::{
+    runtime::Runtime,
+    sync::broadcast::{self, error::RecvError},
+    time::{interval, sleep_until, Instant, MissedTickBehavior},
+};
 
 use crate::server::MessageError;
 use crate::{
     registry::REGISTRY,
-    server::{ClientMessage, Greeting, ServerMessage},
+    server::{
+        ClientMessage, ErrorKind, GameListChange, Greeting, KickReason, PlayerId, ServerMessage,
+    },
 };
 
-use self::app::{App, GameStore};
-use self::connection::Connection;
+use self::app::{App, Entry, GameStore, StoreError};
+use self::config::ServerConfig;
+use self::connection::{CloseCode, Connection};
 use self::double_channel::DoubleChannel;
+use self::game::LEAVE_NOTICE;
 
 pub mod app;
+pub mod config;
 mod connection;
+mod discovery;
 pub mod double_channel;
 mod fields;
 pub mod game;
+pub mod shutdown;
+#[cfg(test)]
+pub mod testing;
 
 pub use fields::FIELDS;
 
+/// How many times the client is pinged within the idle timeout, so that a pong or two may be late
+/// without the connection being closed
+const PINGS_PER_TIMEOUT: u32 = 3;
+
 pub struct Player {
     socket: Connection,
+    id: PlayerId,
     info: Greeting,
     game_list: GameStore,
-    game_channel: Option<DoubleChannel<Vec<u8>>>,
+    activity: Activity,
+    /// The last time anything was received from the client, pongs included
+    last_heard: Instant,
+}
+
+/// What a player is doing on the server, which decides what it is sent besides the answers to its
+/// requests
+enum Activity {
+    Browsing {
+        /// Changes to the game list, if the player wants to hear of them
+        subscription: Option<broadcast::Receiver<GameListChange>>,
+    },
+    Playing {
+        channel: DoubleChannel<Vec<u8>>,
+    },
+    /// The player is done with the server, and its connection is to be closed
+    Leaving(Departure),
+}
+
+/// Why the connection of a player ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Departure {
+    /// The client closed the connection, and so will not be back
+    Closed,
+    /// The connection failed
+    Lost,
+    /// Nothing was heard from the client for longer than the idle timeout
+    TimedOut,
+    /// The client sent more than it is allowed to
+    Kicked(KickReason),
+    ShuttingDown,
+}
+
+/// Something that the activity of a player has for it
+enum News {
+    /// A message of the game the player is in, or none if the game has ended
+    Game(Option<Vec<u8>>),
+    GameList(Result<GameListChange, RecvError>),
 }
 
 impl Player {
-    /// Begin listening to messages
+    /// Begin listening to messages, until either the client or the server goes away
     async fn enter(&mut self) {
-        loop {
-            if let Some(ref mut recv) = self.game_channel {
-                tokio::select! {
-                    Some(msg) = recv.recv() => {
-                        self.socket.send(msg).await;
-                    }
-                    Some(maybe_msg) = self.socket.recv_message() => {
-                        self.handle_client_message(maybe_msg).await;
-                    }
+        let mut shutdown = self.game_list.shutdown();
+        let mut closing_at = None;
+        let idle_timeout = self.game_list.config().idle_timeout;
+        let mut heartbeat = interval(idle_timeout / PINGS_PER_TIMEOUT);
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let departure = loop {
+            if let Activity::Leaving(departure) = self.activity {
+                break departure;
+            }
+            tokio::select! {
+                deadline = shutdown.begun(), if closing_at.is_none() => {
+                    closing_at = Some(deadline);
+                    let seconds = deadline.saturating_duration_since(Instant::now()).as_secs();
+                    self.send(ServerMessage::ShuttingDown {
+                        seconds: seconds as u32,
+                    })
+                    .await;
+                }
+                _ = closing(closing_at) => self.leave(Departure::ShuttingDown).await,
+                _ = heartbeat.tick() => self.check_pulse(idle_timeout).await,
+                news = news(&mut self.activity) => match news {
+                    News::Game(Some(data)) => self.send(ServerMessage::Ingame { data }).await,
+                    // the game has ended
+                    News::Game(None) => self.activity = Activity::Browsing { subscription: None },
+                    News::GameList(change) => self.send_change(change).await,
+                },
+                maybe_msg = self.socket.recv_message() => {
+                    self.last_heard = Instant::now();
+                    // pings and the like are not messages
+                    let Some(maybe_msg) = maybe_msg else {
+                        continue;
+                    };
+                    self.handle_client_message(maybe_msg).await;
                 }
-            } else if let Some(msg) = self.socket.recv_message().await {
-                self.handle_client_message(msg).await;
             }
+        };
+        self.close(departure).await;
+    }
+
+    /// Ends the activity of the player, which tells the game it was in that it is gone
+    async fn leave(&mut self, departure: Departure) {
+        if matches!(departure, Departure::Closed | Departure::Kicked(_)) {
+            // the game need not hold a place for a player who is not coming back
+            self.leave_game().await;
         }
+        self.activity = Activity::Leaving(departure);
+    }
+
+    /// Leaves the game that the player is in for good, if it is in one
+    async fn leave_game(&mut self) {
+        if let Activity::Playing { channel } = &mut self.activity {
+            let _ = channel.send(LEAVE_NOTICE).await;
+            self.activity = Activity::Browsing { subscription: None };
+        }
+    }
+
+    async fn close(&mut self, departure: Departure) {
+        log::debug!("{} left the server: {departure:?}", self.info.username);
+        match departure {
+            Departure::Closed => self.socket.close(CloseCode::Normal, "").await,
+            Departure::Lost => (),
+            Departure::TimedOut => {
+                self.socket
+                    .close(CloseCode::Policy, "The client stopped answering")
+                    .await
+            }
+            Departure::Kicked(reason) => {
+                log::info!("Kicked {}: {reason}", self.info.username);
+                let _ = self.socket.send_ser(ServerMessage::Kicked(reason)).await;
+                self.socket
+                    .close(reason.close_code(), &reason.to_string())
+                    .await
+            }
+            Departure::ShuttingDown => {
+                self.socket
+                    .close(CloseCode::Away, "The server has shut down")
+                    .await
+            }
+        }
+    }
+
+    /// Closes the connection of a client which has not been heard from in too long, and otherwise
+    /// asks it to show that it is still there
+    async fn check_pulse(&mut self, idle_timeout: Duration) {
+        if self.last_heard.elapsed() >= idle_timeout {
+            self.leave(Departure::TimedOut).await;
+        } else if let Err(e) = self.socket.ping().await {
+            self.lost(e).await;
+        }
+    }
+
+    /// Sends a message to the client, giving up on the connection if it cannot be used anymore
+    async fn send(&mut self, message: ServerMessage) {
+        match self.socket.send_ser(message).await {
+            Ok(()) => (),
+            Err(MessageError::Serialization(e)) => {
+                log::error!("Could not write a message to {}: {e}", self.info.username)
+            }
+            Err(e) => self.lost(e).await,
+        }
+    }
+
+    /// Gives up on a connection which failed
+    async fn lost(&mut self, error: MessageError) {
+        log::debug!("The connection to {} failed: {error}", self.info.username);
+        self.leave(Departure::Lost).await;
     }
 
     async fn handle_client_message(&mut self, message: Result<ClientMessage, MessageError>) {
+        let message = match message {
+            Ok(message) => message,
+            Err(MessageError::Tungstenite(tungstenite::Error::ConnectionClosed)) => {
+                self.leave(Departure::Closed).await;
+                return;
+            }
+            Err(MessageError::Kicked(reason)) => {
+                self.leave(Departure::Kicked(reason)).await;
+                return;
+            }
+            Err(e @ MessageError::Tungstenite(_)) => {
+                self.lost(e).await;
+                return;
+            }
+            Err(e) => {
+                log::debug!("Could not read a message from {}: {e}", self.info.username);
+                self.send_error(ErrorKind::Unreadable, e).await;
+                return;
+            }
+        };
         match message {
-            Ok(ClientMessage::Create { game, args }) => {
-                self.game_channel = self
+            ClientMessage::Create { game, args } => {
+                let created = self
                     .game_list
-                    .create_new(&game, args, self.info.clone())
+                    .create_new(&game, args, self.id, self.info.clone())
                     .await;
+                self.enter_game(created).await;
             }
-            Ok(ClientMessage::ForceLeave) => (),
-            Ok(ClientMessage::GameTypes) => {
-                self.socket
-                    .send_ser(ServerMessage::AvailableGames(
-                        REGISTRY.keys().copied().collect(),
-                    ))
-                    .await;
+            ClientMessage::ForceLeave => self.leave_game().await,
+            ClientMessage::GameTypes => {
+                let gamemodes = REGISTRY
+                    .keys()
+                    .copied()
+                    .filter(|marker| self.info.gamemodes.contains(marker))
+                    .collect();
+                self.send(ServerMessage::AvailableGames(gamemodes)).await;
             }
-            Ok(ClientMessage::Games) => {
-                self.socket
-                    .send_ser(ServerMessage::ActiveGames(self.game_list.list().await))
+            ClientMessage::Games => self.send_games().await,
+            ClientMessage::SubscribeGames => match &mut self.activity {
+                Activity::Browsing { subscription } => {
+                    // subscribing first, so that no change can fall between the list and the
+                    // changes
+                    *subscription = Some(self.game_list.subscribe());
+                    self.send_games().await;
+                }
+                _ => {
+                    self.send_error(
+                        ErrorKind::Unexpected,
+                        "Games cannot be followed from a game",
+                    )
+                    .await
+                }
+            },
+            ClientMessage::Ingame { data } if data.is_empty() => {
+                // an empty message is how the game hears that a player left, and so is never
+                // passed on from a client
+                self.send_error(ErrorKind::Unreadable, "Game messages cannot be empty")
                     .await;
             }
-            Ok(ClientMessage::Ingame { data }) => {
-                if let Some(chan) = &mut self.game_channel {
-                    chan.send(data);
+            ClientMessage::Ingame { data } => {
+                if let Activity::Playing { channel } = &mut self.activity {
+                    // waits for the game to make room, which keeps a client that sends faster
+                    // than the game keeps up from being read any faster
+                    let _ = channel.send(data).await;
                 } else {
-                    self.socket.send_ser(ServerMessage::Malformed).await;
+                    self.send_error(ErrorKind::NotInGame, "Not in a game").await;
                 }
             }
-            Ok(ClientMessage::Join { game }) => {
-                if let Some(game) = self.game_list.join(&game, self.info.clone()).await {
-                    self.game_channel = Some(game);
-                } else {
-                    self.socket.send_ser(ServerMessage::Malformed).await;
+            ClientMessage::Join { game } => {
+                let joined = self.game_list.join(&game, self.id, self.info.clone()).await;
+                self.enter_game(joined).await;
+            }
+            ClientMessage::Spectate { game } => {
+                let spectating = self
+                    .game_list
+                    .spectate(&game, self.id, self.info.clone())
+                    .await;
+                self.enter_game(spectating).await;
+            }
+            ClientMessage::Rejoin { token } => {
+                let rejoined = self
+                    .game_list
+                    .rejoin(token, self.id, self.info.clone())
+                    .await;
+                self.enter_game(rejoined).await;
+            }
+        }
+    }
+
+    async fn send_games(&mut self) {
+        // games which the client could not play are kept from it
+        let games = self
+            .game_list
+            .list()
+            .await
+            .into_iter()
+            .filter(|game| self.info.gamemodes.contains(&game.marker))
+            .collect();
+        self.send(ServerMessage::ActiveGames(games)).await;
+    }
+
+    async fn send_change(&mut self, change: Result<GameListChange, RecvError>) {
+        let change = match change {
+            Ok(GameListChange::Updated(game)) if !self.info.gamemodes.contains(&game.marker) => {
+                return
+            }
+            Ok(change) => change,
+            Err(RecvError::Lagged(_)) => {
+                // the changes that were missed are made up for by the whole list
+                self.send_games().await;
+                return;
+            }
+            Err(RecvError::Closed) => {
+                self.activity = Activity::Browsing { subscription: None };
+                return;
+            }
+        };
+        self.send(ServerMessage::GamesChanged(vec![change])).await;
+    }
+
+    async fn enter_game(&mut self, game: Result<Entry, StoreError>) {
+        match game {
+            Ok(Entry {
+                game,
+                marker,
+                host,
+                channel,
+            }) => {
+                // a player is only ever in one game
+                self.leave_game().await;
+                // sent before any message of the game, which is only read once this returns
+                self.send(ServerMessage::Joined {
+                    game,
+                    marker,
+                    you_are_host: host,
+                })
+                .await;
+                // a player in a game is no longer choosing one
+                if !matches!(self.activity, Activity::Leaving(_)) {
+                    self.activity = Activity::Playing { channel };
                 }
             }
-            Err(_) => (),
+            Err(e) => {
+                log::debug!("{} could not enter a game: {e}", self.info.username);
+                self.send(ServerMessage::join_rejected(e.kind(), e)).await;
+            }
         }
     }
+
+    async fn send_error(&mut self, kind: ErrorKind, message: impl ToString) {
+        self.send(ServerMessage::error(kind, message)).await;
+    }
+}
+
+/// The next thing that the activity of a player has for it, if it has anything
+async fn news(activity: &mut Activity) -> News {
+    match activity {
+        Activity::Playing { channel } => News::Game(channel.recv().await),
+        Activity::Browsing {
+            subscription: Some(subscription),
+        } => News::GameList(subscription.recv().await),
+        _ => std::future::pending().await,
+    }
+}
+
+/// Waits for the time at which the server closes the connection, if it is shutting down
+async fn closing(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
 }
 
 #[allow(dead_code)]
-pub fn srv_start(address: String) {
+pub fn srv_start(config: ServerConfig) {
     SimpleLogger::new()
-        .with_level(log::LevelFilter::Debug)
+        .with_level(config.log_level)
         .init()
         .expect("logging framework failed to start");
-    Runtime::new().unwrap().block_on(srv_main(address))
+    Runtime::new().unwrap().block_on(srv_main(config))
 }
 
-async fn srv_main(address: String) {
-    App::new(address).await.run().await;
+async fn srv_main(config: ServerConfig) {
+    match App::new(config).await {
+        Ok(app) => app.run().await,
+        Err(e) => log::error!("Could not open the server: {e}"),
+    }
 }
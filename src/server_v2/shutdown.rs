@@ -0,0 +1,87 @@
+//! Lets every part of the server know when it is shutting down. Once it begins, players are told
+//! how long is left, no more games may be created or joined, and the games which are running are
+//! given until the deadline to finish.
+
+use std::{future::pending, time::Duration};
+
+use tokio::{
+    sync::watch,
+    time::{sleep_until, Instant},
+};
+
+/// Begins the shutdown of the server, held by whatever decides when that happens
+pub struct ShutdownTrigger(watch::Sender<Option<Instant>>);
+
+impl ShutdownTrigger {
+    /// Tells everyone holding a [Shutdown] that the server is shutting down, and that it will be
+    /// gone once the grace period is over. Returns the deadline.
+    pub fn begin(&self, grace: Duration) -> Instant {
+        let deadline = Instant::now() + grace;
+        self.0.send_replace(Some(deadline));
+        deadline
+    }
+}
+
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<Option<Instant>>);
+
+impl Shutdown {
+    pub fn new() -> (ShutdownTrigger, Self) {
+        let (trigger, shutdown) = watch::channel(None);
+        (ShutdownTrigger(trigger), Self(shutdown))
+    }
+
+    /// The time by which the server will be gone, if it has begun shutting down
+    pub fn deadline(&self) -> Option<Instant> {
+        *self.0.borrow()
+    }
+
+    /// Waits until the server begins shutting down, returning the deadline. Returns straight away
+    /// if it already has, and never if the server goes away without warning.
+    pub async fn begun(&mut self) -> Instant {
+        loop {
+            if let Some(deadline) = self.deadline() {
+                return deadline;
+            }
+            if self.0.changed().await.is_err() {
+                return pending().await;
+            }
+        }
+    }
+
+    /// Waits until the server has begun shutting down and the deadline has passed
+    pub async fn expired(&mut self) {
+        sleep_until(self.begun().await).await
+    }
+}
+
+/// Waits for the process to be asked to stop, with Ctrl+C or, on unix, SIGTERM
+pub async fn stop_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                log::warn!("Could not listen for SIGTERM: {e}");
+                pending::<()>().await
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = pending::<()>();
+
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            log::warn!("Could not listen for Ctrl+C: {e}");
+            pending::<()>().await
+        }
+    };
+
+    tokio::select! {
+        _ = interrupt => (),
+        _ = terminate => (),
+    }
+}
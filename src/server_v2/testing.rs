@@ -0,0 +1,152 @@
+//! A server to run tests against, and real websocket clients to connect to it with
+
+use std::{net::SocketAddr, time::Duration};
+
+use futures_util::{SinkExt, StreamExt};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{net::TcpStream, sync::oneshot, task::JoinHandle, time::timeout};
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tungstenite::{protocol::CloseFrame, Message};
+
+use crate::server::{ClientMessage, Greeting, HandshakeReply, ServerMessage};
+
+use super::{app::App, config::ServerConfig};
+
+/// How long a test waits for something that it expects of the server
+pub const PATIENCE: Duration = Duration::from_secs(5);
+
+/// A server running in the background, on a port of its own
+pub struct TestServer {
+    pub address: SocketAddr,
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl TestServer {
+    pub async fn start(config: ServerConfig) -> Self {
+        let app = App::new(ServerConfig {
+            bind: [127, 0, 0, 1].into(),
+            port: 0,
+            ..config
+        })
+        .await
+        .unwrap();
+        let address = app.address().unwrap();
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(app.run_until(async {
+            let _ = stopped.await;
+        }));
+        Self {
+            address,
+            stop,
+            task,
+        }
+    }
+
+    /// Shuts the server down as if the process had been asked to stop, and waits until it has
+    pub async fn stop(self, within: Duration) {
+        let _ = self.stop.send(());
+        timeout(within, self.task)
+            .await
+            .expect("the server did not shut down in time")
+            .unwrap();
+    }
+}
+
+pub struct TestClient(WebSocketStream<MaybeTlsStream<TcpStream>>);
+
+impl TestClient {
+    /// Connects to the server and gets through the handshake
+    pub async fn connect(address: SocketAddr, username: &str) -> Self {
+        let (socket, _) = connect_async(format!("ws://{address}")).await.unwrap();
+        let mut client = Self(socket);
+        client.send_raw(&Greeting::new(username.to_string())).await;
+        let reply = client.recv_raw::<HandshakeReply>().await;
+        assert!(
+            matches!(reply, Some(HandshakeReply::Accepted { .. })),
+            "{reply:?}"
+        );
+        client
+    }
+
+    /// Opens a websocket to the server without greeting it
+    pub async fn connect_silently(address: SocketAddr) -> Self {
+        let (socket, _) = connect_async(format!("ws://{address}")).await.unwrap();
+        Self(socket)
+    }
+
+    pub async fn send(&mut self, message: ClientMessage) {
+        self.send_raw(&message).await
+    }
+
+    pub async fn send_raw<S: Serialize>(&mut self, message: &S) {
+        self.send_bytes(rmp_serde::to_vec(message).unwrap()).await
+    }
+
+    pub async fn send_bytes(&mut self, bytes: Vec<u8>) {
+        self.0.send(Message::Binary(bytes)).await.unwrap()
+    }
+
+    /// The next message of the server, or none once the connection is closed
+    pub async fn recv(&mut self) -> Option<ServerMessage> {
+        self.recv_raw().await
+    }
+
+    async fn recv_raw<D: DeserializeOwned>(&mut self) -> Option<D> {
+        loop {
+            match self.next_frame().await {
+                Some(Message::Binary(data)) => return Some(rmp_serde::from_slice(&data).unwrap()),
+                Some(Message::Close(_)) | None => return None,
+                // pings are answered by the socket as it reads
+                Some(_) => (),
+            }
+        }
+    }
+
+    /// Waits for a message that `pick` picks out of those the server sends, skipping the rest
+    pub async fn wait_for<T>(&mut self, mut pick: impl FnMut(ServerMessage) -> Option<T>) -> T {
+        loop {
+            let message = self.recv().await.expect("the connection was closed");
+            if let Some(picked) = pick(message) {
+                return picked;
+            }
+        }
+    }
+
+    /// Waits for the server to close the connection, returning why it did
+    pub async fn closed(&mut self) -> Option<CloseFrame<'static>> {
+        loop {
+            match self.next_frame().await {
+                Some(Message::Close(frame)) => return frame,
+                None => return None,
+                Some(_) => (),
+            }
+        }
+    }
+
+    /// Keeps reading for a while, which answers the pings of the server, and ignores everything
+    /// else that it sends
+    pub async fn linger(&mut self, duration: Duration) {
+        let reading = async {
+            loop {
+                if let None | Some(Message::Close(_)) = self.next_frame().await {
+                    panic!("the connection was closed");
+                }
+            }
+        };
+        let _ = timeout(duration, reading).await;
+    }
+
+    /// Closes the connection the way that a client which leaves would
+    pub async fn close(mut self) {
+        self.0.close(None).await.unwrap();
+        while self.next_frame().await.is_some() {}
+    }
+
+    async fn next_frame(&mut self) -> Option<Message> {
+        timeout(PATIENCE, self.0.next())
+            .await
+            .expect("the server did not answer in time")
+            .and_then(Result::ok)
+    }
+}
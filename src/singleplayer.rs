@@ -1,25 +1,43 @@
 use crate::{
     area_attack::puppet::Puppet,
-    common::{InitCheckCell, NeedsMaterial, Vec2Ext},
+    common::{InitCheckCell, NeedsMaterial, Position, Vec2Ext},
     cursor::*,
     load::{Field, Textures},
-    minefield::specific::MineCellState,
+    minefield::{
+        solver::{Solver, Visible},
+        specific::MineCellState,
+    },
 };
 use crate::{
     main_menu::Menu,
     minefield::{
+        generate::{Seed, SeededRng},
         specific::{MineCell, TILE_SIZE},
         systems::*,
-        FieldShape, GameOutcome, Minefield,
+        Difficulty, FieldShape, GameOutcome, Minefield,
     },
+    replay::{tick_recording, watching_singleplayer},
 };
 use bevy::{gltf::Gltf, prelude::*};
 use iyes_loopless::{
-    prelude::{AppLooplessStateExt, ConditionSet, IntoConditionalSystem},
+    prelude::{AppLooplessStateExt, ConditionHelpers, ConditionSet, IntoConditionalSystem},
     state::NextState,
 };
 
 mod menu;
+pub mod replay;
+pub mod save;
+mod score;
+
+use replay::{
+    despawn_replay, play_replay, record_inputs, save_replay, spawn_replay, start_recording,
+    stop_recording, ReplayBoard, SingleplayerInput,
+};
+use save::{discard_save, resume_game, SavedGame};
+use score::{
+    count_bbbv, record_high_score, reset_statistics, tick_clock, HighScores, PlayedField,
+    Statistics,
+};
 
 #[derive(Clone, PartialEq, Eq, Debug, Hash)]
 pub enum Singleplayer {
@@ -54,15 +72,18 @@ fn create_entities(
     mut materials: ResMut<Assets<StandardMaterial>>,
     template_handles: Res<Field>,
     textures: Res<Textures>,
+    (difficulty, seed): (Res<Difficulty>, Res<Seed>),
     mut camera: Query<&mut Transform, With<Camera>>,
 ) {
+    let mut rng = SeededRng::new(seed.0.unwrap_or_else(rand::random));
+
     // create minefield
-    let field_template = field_templates
-        .get(template_handles.take_one(&mut rand::thread_rng()))
-        .unwrap();
+    let field_handle = template_handles.take_one(&mut rng.rng);
+    let field_template = field_templates.get(field_handle).unwrap();
     let minefield = Minefield::new_shaped(
         |&pos| commands.spawn(MineCell::new_empty(pos, &textures)).id(),
         field_template,
+        *difficulty,
     );
 
     // get starting position
@@ -71,34 +92,81 @@ fn create_entities(
         .unwrap_or_else(|| minefield.iter_positions().next().unwrap());
 
     let minefield_entity = commands.spawn(()).insert(minefield).id();
+    commands.insert_resource(rng);
+    commands.insert_resource(PlayedField(field_handle.clone()));
 
-    // move camera to cursor
-    let mut camera = camera.single_mut();
-    camera.translation.x = TILE_SIZE * init_position.x as f32;
-    camera.translation.z = TILE_SIZE * init_position.y as f32;
+    spawn_cursor(
+        &mut commands,
+        &mut materials,
+        &textures,
+        &mut camera.single_mut(),
+        minefield_entity,
+        init_position,
+    );
+}
+
+/// Creates the player's cursor on the given tile, and moves the camera there to meet it
+fn spawn_cursor(
+    commands: &mut Commands,
+    materials: &mut Assets<StandardMaterial>,
+    textures: &Textures,
+    camera: &mut Transform,
+    minefield: Entity,
+    position: Position,
+) {
+    camera.translation.x = TILE_SIZE * position.x as f32;
+    camera.translation.z = TILE_SIZE * position.y as f32;
 
-    // create cursor
     commands.spawn(CursorBundle {
         cursor: Cursor {
             color: Color::YELLOW_GREEN,
-            owning_minefield: minefield_entity,
+            owning_minefield: minefield,
             tile_material: materials.add(StandardMaterial {
                 emissive: Color::YELLOW_GREEN,
                 ..default()
             }),
         },
-        position: init_position,
+        position,
         texture: SceneBundle {
             scene: textures.cursor.clone(),
             transform: Transform {
-                translation: init_position.absolute(TILE_SIZE, TILE_SIZE).extend_xz(1.0),
+                translation: position.absolute(TILE_SIZE, TILE_SIZE).extend_xz(1.0),
                 ..default()
             },
             ..default()
-        }, // ,
+        },
     });
 }
 
+fn count_clicks(kb: Res<Input<KeyCode>>, keybinds: Res<Bindings>, mut stats: ResMut<Statistics>) {
+    if kb.just_pressed(keybinds.check) || kb.just_pressed(keybinds.flag) {
+        stats.clicks += 1;
+    }
+}
+
+fn show_hint(
+    kb: Res<Input<KeyCode>>,
+    keybinds: Res<Bindings>,
+    cursor: Query<(&Cursor, &Position), Without<Puppet>>,
+    fields: Query<&Minefield<Entity>>,
+    states: Query<&MineCellState>,
+    mut markers: HintMarkers,
+) {
+    if !kb.just_pressed(keybinds.hint) {
+        return;
+    }
+    let Ok((cursor, &position)) = cursor.get_single() else {
+        return;
+    };
+    let Ok(field) = fields.get(cursor.owning_minefield) else {
+        return;
+    };
+    let solver = Solver::read(field, |tile| states.get(tile).ok().map(Visible::from));
+    if let Some((hint, chance)) = solver.hint(position, Some(field.mines)) {
+        markers.show(hint, chance);
+    }
+}
+
 pub fn update_tiles(
     mut commands: Commands,
     mut changed_cells: Query<
@@ -141,18 +209,49 @@ impl Plugin for SingleplayerMode {
             .add_system(update_tiles.run_not_in_state(Menu::Loading))
             // state
             .add_loopless_state(Inactive)
+            .init_resource::<Difficulty>()
+            .init_resource::<Seed>()
+            .init_resource::<Statistics>()
+            .insert_resource(HighScores::load())
             // menu after game complete
             .add_plugin(menu::MenuPlugin)
             // state change startup and cleanup
-            .add_exit_system(Inactive, create_entities)
+            .add_exit_system(
+                Inactive,
+                create_entities.run_unless_resource_exists::<SavedGame>(),
+            )
+            .add_exit_system(
+                Inactive,
+                discard_save.run_unless_resource_exists::<SavedGame>(),
+            )
+            .add_exit_system(Inactive, resume_game.run_if_resource_exists::<SavedGame>())
+            .add_enter_system(GameFailed, discard_save)
+            .add_enter_system(GameSuccess, discard_save)
             .add_system(generate_minefield.run_in_state(PreGame))
             .add_enter_system(PreGame, wipe_minefields)
+            .add_enter_system(PreGame, reset_statistics)
+            .add_enter_system(Game, count_bbbv)
+            .add_enter_system(GameSuccess, record_high_score)
             .add_system(advance_to_game.run_in_state(PreGame))
             .add_system(advance_to_end.run_in_state(Game))
             .add_enter_system(GameFailed, display_mines)
             // logic for leaving game
             .add_enter_system(Inactive, destroy_cursors)
             .add_enter_system(Inactive, destroy_minefields)
+            .add_enter_system(Inactive, destroy_hints)
+            // recording and playback
+            .add_enter_system(PreGame, start_recording)
+            .add_enter_system(Inactive, stop_recording)
+            .add_enter_system(GameFailed, save_replay)
+            .add_enter_system(GameSuccess, save_replay)
+            .add_system(record_inputs.before(reveal_cell))
+            .add_enter_system(Menu::Replay, spawn_replay.run_if(watching_singleplayer))
+            .add_system(
+                play_replay
+                    .run_in_state(Menu::Replay)
+                    .run_if_resource_exists::<ReplayBoard>(),
+            )
+            .add_exit_system(Menu::Replay, despawn_replay.run_if(watching_singleplayer))
             // in-game logic
             .add_system_set(
                 ConditionSet::new()
@@ -161,6 +260,11 @@ impl Plugin for SingleplayerMode {
                     .with_system(reveal_cell.run_in_state(Game))
                     .with_system(init_check_cell.run_in_state(PreGame))
                     .with_system(check_cell.run_in_state(Game))
+                    .with_system(show_hint.run_in_state(Game))
+                    .with_system(count_clicks.run_in_state(Game))
+                    .with_system(tick_clock.run_in_state(Game))
+                    .with_system(tick_recording::<SingleplayerInput>.run_in_state(Game))
+                    .with_system(clear_hints::<MineCellState>)
                     .into(),
             );
     }
@@ -10,10 +10,16 @@ use crate::{
     Singleplayer,
 };
 
+use super::{
+    save::CurrentGame,
+    score::{HighScores, Summary},
+};
+
 fn fail_screen(
     mut commands: Commands,
     ctx: ResMut<EguiContext>,
     minefield: Query<&Minefield<Entity>>,
+    summary: Summary,
 ) {
     let remaining = minefield.single().remaining_blank;
 
@@ -21,23 +27,58 @@ fn fail_screen(
         &mut commands,
         ctx,
         format!("You failed with {remaining} tiles left"),
+        &summary,
+        false,
     );
 }
 
-fn success_screen(mut commands: Commands, ctx: ResMut<EguiContext>) {
+fn success_screen(mut commands: Commands, ctx: ResMut<EguiContext>, summary: Summary) {
     create_screen(
         &mut commands,
         ctx,
-        "Congratulations!".to_string(), // TODO: Calculate score
+        "Congratulations!".to_string(),
+        &summary,
+        true,
     );
 }
 
-fn create_screen(commands: &mut Commands, mut ctx: ResMut<EguiContext>, message: String) {
+fn create_screen(
+    commands: &mut Commands,
+    mut ctx: ResMut<EguiContext>,
+    message: String,
+    summary: &Summary,
+    succeeded: bool,
+) {
     use Singleplayer::*;
+    let stats = &summary.stats;
     standard_window(&mut ctx, |ui| {
         ui.vertical_centered(|ui| {
             let initial_height = ui.available_height();
             ui.label(RichText::new(message).size(32.0).color(Color32::GOLD));
+            ui.label(format!("Difficulty: {}", *summary.difficulty));
+            ui.label(format!("Seed: {}", summary.rng.seed));
+            ui.label(format!("Time: {:.2}s", stats.elapsed.as_secs_f32()));
+            if succeeded {
+                ui.label(format!(
+                    "3BV: {} ({:.2}/s)",
+                    stats.bbbv,
+                    stats.bbbv as f32 / stats.elapsed.as_secs_f32().max(1.0)
+                ));
+                ui.label(format!("Efficiency: {:.0}%", stats.efficiency() * 100.0));
+                ui.label(format!("Score: {}", stats.score()));
+            } else {
+                ui.label(format!("3BV: {}", stats.bbbv));
+            }
+            if succeeded && summary.is_best() {
+                ui.label(RichText::new("New best!").color(Color32::GOLD));
+            } else if let Some(best) = summary.best() {
+                ui.label(format!(
+                    "Best on {}: {} in {:.2}s",
+                    summary.field_name(),
+                    best.score,
+                    best.time.as_secs_f32()
+                ));
+            }
             if ui.button("Retry").clicked() {
                 commands.insert_resource(NextState(PreGame));
             }
@@ -51,7 +92,52 @@ fn create_screen(commands: &mut Commands, mut ctx: ResMut<EguiContext>, message:
     });
 }
 
-fn pause_menu(mut commands: Commands, mut ctx: ResMut<EguiContext>) {
+fn high_scores_menu(
+    mut commands: Commands,
+    mut ctx: ResMut<EguiContext>,
+    high_scores: Res<HighScores>,
+) {
+    standard_window(&mut ctx, |ui| {
+        if ui.button("⏴back").clicked() {
+            commands.insert_resource(NextState(Menu::MainMenu));
+        }
+        if high_scores.iter().next().is_none() {
+            ui.label("No games have been won yet");
+            return;
+        }
+        egui::Grid::new("high scores").striped(true).show(ui, |ui| {
+            for header in [
+                "Field",
+                "Difficulty",
+                "Score",
+                "Time",
+                "3BV",
+                "Efficiency",
+                "Seed",
+            ] {
+                ui.strong(header);
+            }
+            ui.end_row();
+            for ((field, difficulty), record) in high_scores.iter() {
+                ui.label(field);
+                ui.label(difficulty.to_string());
+                ui.label(record.score.to_string());
+                ui.label(format!("{:.2}s", record.time.as_secs_f32()));
+                ui.label(record.bbbv.to_string());
+                ui.label(format!("{:.0}%", record.efficiency * 100.0));
+                ui.label(record.seed.to_string());
+                ui.end_row();
+            }
+        });
+    });
+}
+
+fn pause_menu(
+    mut commands: Commands,
+    mut ctx: ResMut<EguiContext>,
+    state: Res<CurrentState<Singleplayer>>,
+    game: CurrentGame,
+) {
     standard_window(&mut ctx, |ui| {
         ui.vertical_centered(|ui| {
             if ui.button("resume").clicked() {
@@ -62,6 +148,10 @@ fn pause_menu(mut commands: Commands, mut ctx: ResMut<EguiContext>) {
                 commands.insert_resource(NextState(Menu::Ingame));
             };
             if ui.button("exit").clicked() {
+                // a game which has not begun yet has nothing worth keeping
+                if state.0 == Singleplayer::Game {
+                    game.save();
+                }
                 commands.insert_resource(NextState(Menu::MainMenu));
                 commands.insert_resource(NextState(Singleplayer::Inactive));
             };
@@ -75,6 +165,7 @@ impl Plugin for MenuPlugin {
     fn build(&self, app: &mut App) {
         app.add_system(fail_screen.run_in_state(Singleplayer::GameFailed))
             .add_system(success_screen.run_in_state(Singleplayer::GameSuccess))
+            .add_system(high_scores_menu.run_in_state(Menu::HighScores))
             .add_system(pause_menu.run_if(
                 |s1: Res<CurrentState<Singleplayer>>, s2: Res<CurrentState<Menu>>| {
                     s1.0 != Singleplayer::Inactive && s2.0 == Menu::Pause
@@ -0,0 +1,330 @@
+//! Recording of singleplayer games as they are played, and the board which plays them back. A
+//! game is only recorded from its start, so a game continued from a save is not kept.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    area_attack::puppet::Puppet,
+    common::{CheckCell, FlagCell, InitCheckCell, Position},
+    cursor::Cursor,
+    load::Textures,
+    minefield::{
+        generate::SeededRng,
+        specific::{MineCell, MineCellState},
+        FieldShape, MineCount, Minefield,
+    },
+    replay::{Playback, RecordedGame, Recording, Replay},
+};
+
+use super::{score::PlayedField, spawn_cursor};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum SingleplayerInput {
+    /// The first reveal of the game, which places the mines around it
+    Init(Vec<Position>),
+    Check(Position),
+    Flag(Position),
+}
+
+impl SingleplayerInput {
+    fn position(&self) -> Option<Position> {
+        match self {
+            SingleplayerInput::Init(positions) => positions.first().copied(),
+            &SingleplayerInput::Check(position) | &SingleplayerInput::Flag(position) => {
+                Some(position)
+            }
+        }
+    }
+}
+
+pub fn start_recording(mut commands: Commands) {
+    commands.insert_resource(Recording::<SingleplayerInput>::default());
+}
+
+pub fn stop_recording(mut commands: Commands) {
+    commands.remove_resource::<Recording<SingleplayerInput>>();
+}
+
+/// Must run before [reveal_cell](crate::minefield::systems::reveal_cell), which takes every
+/// [CheckCell] for itself
+pub fn record_inputs(
+    mut init: EventReader<InitCheckCell>,
+    mut check: EventReader<CheckCell>,
+    mut flag: EventReader<FlagCell>,
+    recording: Option<ResMut<Recording<SingleplayerInput>>>,
+) {
+    let Some(mut recording) = recording else {
+        return;
+    };
+    for ev in init.iter() {
+        recording.record(SingleplayerInput::Init(ev.positions.clone()));
+    }
+    for CheckCell(position) in check.iter() {
+        recording.record(SingleplayerInput::Check(position.0));
+    }
+    for FlagCell(position) in flag.iter() {
+        recording.record(SingleplayerInput::Flag(position.0));
+    }
+}
+
+pub fn save_replay(
+    recording: Option<Res<Recording<SingleplayerInput>>>,
+    rng: Res<SeededRng>,
+    field: Res<PlayedField>,
+    shapes: Res<Assets<FieldShape>>,
+    fields: Query<&Minefield<Entity>>,
+    states: Query<&MineCellState>,
+) {
+    let (Some(recording), Some(shape), Ok(minefield)) =
+        (recording, shapes.get(&field), fields.get_single())
+    else {
+        return;
+    };
+    let mut mines: Vec<_> = minefield
+        .iter_positions()
+        .filter(
+            |&position| matches!(states.get(minefield[&position]), Ok(state) if state.is_mine()),
+        )
+        .collect();
+    mines.sort();
+    Replay {
+        seed: rng.seed,
+        shape: shape.clone(),
+        game: RecordedGame::Singleplayer {
+            mines,
+            inputs: recording.entries(),
+        },
+    }
+    .save();
+}
+
+/// The board of a replay at the time it is being watched at, worked out from the recorded inputs
+/// by the same rules as [reveal_cell](crate::minefield::systems::reveal_cell) and
+/// [flag_cell](crate::minefield::systems::flag_cell)
+#[derive(Resource)]
+pub struct ReplayBoard {
+    states: HashMap<Position, MineCellState>,
+    /// Time of the replay that the board is at
+    shown: Duration,
+    /// Index of the first input which has not been played yet
+    next: usize,
+}
+
+impl ReplayBoard {
+    fn new(cells: impl Iterator<Item = Position>, mines: &[Position]) -> Self {
+        let mut board = Self {
+            states: cells.map(|cell| (cell, MineCellState::Empty)).collect(),
+            shown: Duration::ZERO,
+            next: 0,
+        };
+        board.restart(mines);
+        board
+    }
+
+    fn restart(&mut self, mines: &[Position]) {
+        for state in self.states.values_mut() {
+            *state = MineCellState::Empty;
+        }
+        for mine in mines {
+            self.states.insert(*mine, MineCellState::Mine);
+        }
+        self.shown = Duration::ZERO;
+        self.next = 0;
+    }
+
+    fn apply(&mut self, input: &SingleplayerInput) {
+        match *input {
+            // the reveals which begin the game are recorded on their own
+            SingleplayerInput::Init(_) => (),
+            SingleplayerInput::Check(position) => self.check(position),
+            SingleplayerInput::Flag(position) => self.flag(position),
+        }
+    }
+
+    fn check(&mut self, start: Position) {
+        let mut queue = VecDeque::from([start]);
+        while let Some(position) = queue.pop_front() {
+            let neighbors: Vec<_> = position
+                .neighbors()
+                .into_iter()
+                .filter_map(|neighbor| Some((neighbor, self.states.get(&neighbor)?.clone())))
+                .collect();
+            let Some(state) = self.states.get_mut(&position) else {
+                continue;
+            };
+            match *state {
+                MineCellState::Empty => {
+                    let mine_neighbors = neighbors
+                        .iter()
+                        .filter(|(_, state)| state.is_mine())
+                        .count() as u8;
+                    if mine_neighbors == 0 {
+                        queue.extend(
+                            neighbors
+                                .into_iter()
+                                .filter_map(|(pos, state)| (!state.is_flagged()).then_some(pos)),
+                        );
+                    }
+                    *state = MineCellState::Revealed(mine_neighbors);
+                }
+                MineCellState::Revealed(x) => {
+                    let flags = neighbors
+                        .iter()
+                        .filter(|(_, state)| state.is_flagged())
+                        .count();
+                    if flags == x as usize {
+                        queue.extend(
+                            neighbors
+                                .into_iter()
+                                .filter_map(|(pos, state)| (!state.is_marked()).then_some(pos)),
+                        );
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn flag(&mut self, position: Position) {
+        use MineCellState::*;
+        if let Some(state) = self.states.get_mut(&position) {
+            *state = match *state {
+                Empty => FlaggedEmpty,
+                FlaggedEmpty => Empty,
+                Mine => FlaggedMine,
+                FlaggedMine => Mine,
+                Revealed(x) => Revealed(x),
+            };
+        }
+    }
+}
+
+pub fn spawn_replay(
+    mut commands: Commands,
+    playback: Res<Playback>,
+    textures: Res<Textures>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut camera: Query<&mut Transform, With<Camera>>,
+) {
+    let RecordedGame::Singleplayer { mines, .. } = &playback.replay.game else {
+        return;
+    };
+    let shape = &playback.replay.shape;
+    let minefield = Minefield::new_shaped(
+        |&position| {
+            commands
+                .spawn(MineCell::new_empty(position, &textures))
+                .id()
+        },
+        shape,
+        MineCount::Exact(mines.len()),
+    );
+    let start = shape
+        .center()
+        .unwrap_or_else(|| minefield.iter_positions().next().unwrap());
+    commands.insert_resource(ReplayBoard::new(minefield.iter_positions(), mines));
+    let minefield_entity = commands.spawn(minefield).id();
+
+    spawn_cursor(
+        &mut commands,
+        &mut materials,
+        &textures,
+        &mut camera.single_mut(),
+        minefield_entity,
+        start,
+    );
+}
+
+/// Brings the board up to the time of the replay, starting over from the beginning when the
+/// viewer has gone back in time
+pub fn play_replay(
+    playback: Res<Playback>,
+    mut board: ResMut<ReplayBoard>,
+    fields: Query<&Minefield<Entity>>,
+    mut states: Query<&mut MineCellState>,
+    mut cursor: Query<&mut Position, (With<Cursor>, Without<Puppet>)>,
+) {
+    let RecordedGame::Singleplayer { mines, inputs } = &playback.replay.game else {
+        return;
+    };
+    let restarted = playback.time < board.shown;
+    if restarted {
+        board.restart(mines);
+    }
+    let mut last = None;
+    while let Some((_, input)) = inputs
+        .get(board.next)
+        .filter(|(time, _)| *time <= playback.time)
+    {
+        board.apply(input);
+        board.next += 1;
+        last = input.position().or(last);
+    }
+    board.shown = playback.time;
+    if !restarted && last.is_none() {
+        return;
+    }
+
+    let Ok(field) = fields.get_single() else {
+        return;
+    };
+    for (position, state) in &board.states {
+        if let Ok(mut shown) = states.get_mut(field[position]) {
+            if *shown != *state {
+                *shown = state.clone();
+            }
+        }
+    }
+    if let (Some(last), Ok(mut cursor)) = (last, cursor.get_single_mut()) {
+        *cursor = last;
+    }
+}
+
+pub fn despawn_replay(
+    mut commands: Commands,
+    entities: Query<Entity, Or<(With<Minefield<Entity>>, With<MineCellState>, With<Cursor>)>>,
+) {
+    for entity in &entities {
+        commands.entity(entity).despawn_recursive();
+    }
+    commands.remove_resource::<ReplayBoard>();
+}
+
+#[cfg(test)]
+mod test {
+    use crate::common::Position;
+
+    use super::{MineCellState, ReplayBoard, SingleplayerInput};
+
+    #[test]
+    fn replayed_inputs_follow_the_rules_of_the_game() {
+        let cells = (0..3).flat_map(|x| (0..3).map(move |y| Position::new(x, y)));
+        let mine = Position::new(1, 1);
+        let mut board = ReplayBoard::new(cells, &[mine]);
+
+        // every tile borders the mine, so a reveal opens nothing else
+        board.apply(&SingleplayerInput::Check(Position::ZERO));
+        assert_eq!(board.states[&Position::ZERO], MineCellState::Revealed(1));
+        assert_eq!(board.states[&Position::new(1, 0)], MineCellState::Empty);
+
+        // once the mine is flagged, revealing the number again reveals its other neighbors
+        board.apply(&SingleplayerInput::Flag(mine));
+        board.apply(&SingleplayerInput::Check(Position::ZERO));
+        assert_eq!(board.states[&mine], MineCellState::FlaggedMine);
+        assert_eq!(
+            board.states[&Position::new(1, 0)],
+            MineCellState::Revealed(1)
+        );
+        assert_eq!(
+            board.states[&Position::new(0, 1)],
+            MineCellState::Revealed(1)
+        );
+        assert_eq!(board.states[&Position::new(2, 2)], MineCellState::Empty);
+    }
+}
@@ -0,0 +1,150 @@
+//! Keeps a singleplayer game which was left part of the way through, so that it can be continued
+//! from the main menu. Only one game is kept at a time, and it is thrown away once a new game is
+//! started or the kept one is finished.
+
+use std::{collections::HashMap, time::Duration};
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    area_attack::puppet::Puppet,
+    common::Position,
+    cursor::Cursor,
+    load::{Field, Textures},
+    minefield::{
+        generate::SeededRng,
+        specific::{MineCell, MineCellState},
+        Difficulty, FieldShape, MineCount, Minefield,
+    },
+    storage,
+};
+
+use super::{
+    score::{PlayedField, Statistics, Summary},
+    spawn_cursor,
+};
+
+const SAVE_PATH: &str = "savegame.mpk";
+
+#[derive(Resource, Serialize, Deserialize)]
+pub struct SavedGame {
+    shape: FieldShape,
+    /// Name of the field the game is played on, which its high score is kept under
+    field_name: String,
+    tiles: Vec<(Position, MineCellState)>,
+    mines: usize,
+    remaining_blank: usize,
+    cursor: Position,
+    elapsed: Duration,
+    clicks: usize,
+    difficulty: Difficulty,
+    /// The generator is started again from its seed when the game is continued, so retries after
+    /// that will not match those of the original game
+    seed: u64,
+}
+
+impl SavedGame {
+    pub fn exists() -> bool {
+        storage::exists(SAVE_PATH)
+    }
+
+    pub fn load() -> Option<Self> {
+        storage::read(SAVE_PATH)
+    }
+}
+
+/// Everything about the game in progress which is needed to save it
+#[derive(SystemParam)]
+pub struct CurrentGame<'w, 's> {
+    fields: Query<'w, 's, &'static Minefield<Entity>>,
+    states: Query<'w, 's, &'static MineCellState>,
+    cursor: Query<'w, 's, &'static Position, (With<Cursor>, Without<Puppet>)>,
+    shapes: Res<'w, Assets<FieldShape>>,
+    summary: Summary<'w, 's>,
+}
+
+impl CurrentGame<'_, '_> {
+    pub fn save(&self) {
+        let (Ok(field), Ok(&cursor)) = (self.fields.get_single(), self.cursor.get_single()) else {
+            return;
+        };
+        let Some(shape) = self.shapes.get(&self.summary.field) else {
+            return;
+        };
+        let saved = SavedGame {
+            shape: shape.clone(),
+            field_name: self.summary.field_name(),
+            tiles: field
+                .iter_positions()
+                .filter_map(|position| {
+                    Some((position, self.states.get(field[&position]).ok()?.clone()))
+                })
+                .collect(),
+            mines: field.mines,
+            remaining_blank: field.remaining_blank,
+            cursor,
+            elapsed: self.summary.stats.elapsed,
+            clicks: self.summary.stats.clicks,
+            difficulty: *self.summary.difficulty,
+            seed: self.summary.rng.seed,
+        };
+        storage::write(SAVE_PATH, &saved);
+    }
+}
+
+pub fn discard_save() {
+    storage::remove(SAVE_PATH);
+}
+
+/// Rebuilds the board of a saved game, in place of creating a new one
+pub fn resume_game(
+    mut commands: Commands,
+    saved: Res<SavedGame>,
+    template_handles: Res<Field>,
+    asset_server: Res<AssetServer>,
+    textures: Res<Textures>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut camera: Query<&mut Transform, With<Camera>>,
+) {
+    let states: HashMap<_, _> = saved.tiles.iter().cloned().collect();
+    let mut minefield = Minefield::new_shaped(
+        |position| {
+            let mut tile = commands.spawn(MineCell::new_empty(*position, &textures));
+            if let Some(state) = states.get(position) {
+                tile.insert(state.clone());
+            }
+            tile.id()
+        },
+        &saved.shape,
+        MineCount::Exact(saved.mines),
+    );
+    minefield.remaining_blank = saved.remaining_blank;
+    let minefield_entity = commands.spawn(minefield).id();
+
+    spawn_cursor(
+        &mut commands,
+        &mut materials,
+        &textures,
+        &mut camera.single_mut(),
+        minefield_entity,
+        saved.cursor,
+    );
+
+    // the field may have been removed since, in which case its high score cannot be kept
+    let field_handle = template_handles
+        .handles
+        .iter()
+        .find(|&handle| Field::name(handle, &asset_server).as_ref() == Some(&saved.field_name))
+        .cloned()
+        .unwrap_or_default();
+    commands.insert_resource(PlayedField(field_handle));
+    commands.insert_resource(SeededRng::new(saved.seed));
+    commands.insert_resource(saved.difficulty);
+    commands.insert_resource(Statistics {
+        elapsed: saved.elapsed,
+        clicks: saved.clicks,
+        ..default()
+    });
+    commands.remove_resource::<SavedGame>();
+}
@@ -0,0 +1,254 @@
+//! Timing and scoring of singleplayer games, along with the table of best results which is kept
+//! between runs of the game
+
+use std::{
+    collections::{btree_map::Entry, BTreeMap, HashSet},
+    marker::PhantomData,
+    time::Duration,
+};
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    common::Position,
+    load::Field,
+    minefield::{generate::SeededRng, specific::MineCellState, Difficulty, FieldShape, Minefield},
+    storage,
+};
+
+const HIGH_SCORES_PATH: &str = "highscores.mpk";
+
+/// The field that the current game is played on
+#[derive(Resource, Deref)]
+pub struct PlayedField(pub Handle<FieldShape>);
+
+/// How the current game is going
+#[derive(Resource, Default, Debug)]
+pub struct Statistics {
+    /// Time spent playing, which starts with the first tile revealed and stops while paused
+    pub elapsed: Duration,
+    /// The least number of clicks needed to clear the board, known as its 3BV
+    pub bbbv: usize,
+    /// Every reveal or flag made by the player, including the first reveal
+    pub clicks: usize,
+}
+
+impl Statistics {
+    /// How much of the board was cleared per click, where anything above 1 means that the player
+    /// needed fewer clicks than the 3BV by flagging and revealing around numbers
+    pub fn efficiency(&self) -> f32 {
+        self.bbbv as f32 / self.clicks.max(1) as f32
+    }
+
+    /// 3BV per second, scaled by efficiency. A player who clears a board at a steady pace without
+    /// wasting clicks scores the same on any size of board.
+    pub fn score(&self) -> u32 {
+        let seconds = self.elapsed.as_secs_f32().max(1.0);
+        (self.bbbv as f32 / seconds * self.efficiency() * 1000.0).round() as u32
+    }
+}
+
+/// A single finished game, as it is kept in the high score table
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Record {
+    pub score: u32,
+    pub time: Duration,
+    pub bbbv: usize,
+    pub efficiency: f32,
+    /// Lets the board be played again
+    pub seed: u64,
+}
+
+impl Record {
+    pub fn new(stats: &Statistics, seed: u64) -> Self {
+        Self {
+            score: stats.score(),
+            time: stats.elapsed,
+            bbbv: stats.bbbv,
+            efficiency: stats.efficiency(),
+            seed,
+        }
+    }
+}
+
+/// The best result for every field and difficulty which has been beaten
+#[derive(Resource, Serialize, Deserialize, Default, Debug)]
+pub struct HighScores(BTreeMap<(String, Difficulty), Record>);
+
+impl HighScores {
+    /// Reads the table from disk. A missing or unreadable file is treated as an empty table.
+    pub fn load() -> Self {
+        storage::read(HIGH_SCORES_PATH).unwrap_or_default()
+    }
+
+    pub fn save(&self) {
+        storage::write(HIGH_SCORES_PATH, self)
+    }
+
+    pub fn best(&self, field: &str, difficulty: Difficulty) -> Option<&Record> {
+        self.0.get(&(field.to_string(), difficulty))
+    }
+
+    /// Keeps the record if it beats the previous best, returning whether it did
+    pub fn submit(&mut self, field: String, difficulty: Difficulty, record: Record) -> bool {
+        match self.0.entry((field, difficulty)) {
+            Entry::Vacant(entry) => {
+                entry.insert(record);
+                true
+            }
+            Entry::Occupied(mut entry) if record.score > entry.get().score => {
+                entry.insert(record);
+                true
+            }
+            Entry::Occupied(_) => false,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&(String, Difficulty), &Record)> {
+        self.0.iter()
+    }
+}
+
+/// Everything that is shown about a game once it is over
+#[derive(SystemParam)]
+pub struct Summary<'w, 's> {
+    pub stats: Res<'w, Statistics>,
+    pub difficulty: Res<'w, Difficulty>,
+    pub rng: Res<'w, SeededRng>,
+    pub field: Res<'w, PlayedField>,
+    asset_server: Res<'w, AssetServer>,
+    high_scores: Res<'w, HighScores>,
+    #[system_param(ignore)]
+    _marker: PhantomData<&'s ()>,
+}
+
+impl Summary<'_, '_> {
+    pub fn field_name(&self) -> String {
+        played_field_name(&self.field, &self.asset_server)
+    }
+
+    pub fn best(&self) -> Option<&Record> {
+        self.high_scores.best(&self.field_name(), *self.difficulty)
+    }
+
+    /// Whether the game that was just won is the best on its field and difficulty
+    pub fn is_best(&self) -> bool {
+        self.best() == Some(&Record::new(&self.stats, self.rng.seed))
+    }
+}
+
+fn played_field_name(field: &PlayedField, asset_server: &AssetServer) -> String {
+    Field::name(field, asset_server).unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Counts the 3BV of a board: one click for every opening, and one for every number which does not
+/// border an opening
+pub fn bbbv(cells: &HashSet<Position>, mines: &HashSet<Position>) -> usize {
+    let is_zero = |position: &Position| {
+        !mines.contains(position)
+            && !position
+                .neighbors()
+                .iter()
+                .any(|neighbor| mines.contains(neighbor))
+    };
+
+    let mut cleared = HashSet::new();
+    let mut clicks = 0;
+    for start in cells.iter().filter(|cell| is_zero(cell)) {
+        if cleared.contains(start) {
+            continue;
+        }
+        clicks += 1;
+        let mut queue = vec![*start];
+        while let Some(position) = queue.pop() {
+            if !cells.contains(&position) || !cleared.insert(position) {
+                continue;
+            }
+            if is_zero(&position) {
+                queue.extend(position.neighbors());
+            }
+        }
+    }
+    clicks
+        + cells
+            .iter()
+            .filter(|cell| !mines.contains(cell) && !cleared.contains(cell))
+            .count()
+}
+
+pub fn reset_statistics(mut commands: Commands) {
+    commands.insert_resource(Statistics {
+        // the reveal which begins the game
+        clicks: 1,
+        ..default()
+    });
+}
+
+/// Works out the 3BV once the mines have been placed, or once a saved game has been resumed
+pub fn count_bbbv(
+    mut stats: ResMut<Statistics>,
+    fields: Query<&Minefield<Entity>>,
+    states: Query<&MineCellState>,
+) {
+    let Ok(field) = fields.get_single() else {
+        return;
+    };
+    let cells: HashSet<_> = field.iter_positions().collect();
+    let mines = cells
+        .iter()
+        .copied()
+        .filter(|&cell| {
+            matches!(
+                states.get(field[&cell]),
+                Ok(MineCellState::Mine | MineCellState::FlaggedMine)
+            )
+        })
+        .collect();
+    stats.bbbv = bbbv(&cells, &mines);
+}
+
+/// Keeps the result of a won game if it is the best so far
+pub fn record_high_score(
+    stats: Res<Statistics>,
+    difficulty: Res<Difficulty>,
+    rng: Res<SeededRng>,
+    field: Res<PlayedField>,
+    asset_server: Res<AssetServer>,
+    mut high_scores: ResMut<HighScores>,
+) {
+    let field = played_field_name(&field, &asset_server);
+    if high_scores.submit(field, *difficulty, Record::new(&stats, rng.seed)) {
+        high_scores.save();
+    }
+}
+
+pub fn tick_clock(mut stats: ResMut<Statistics>, time: Res<Time>) {
+    stats.elapsed += time.delta();
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use crate::common::Position;
+
+    use super::bbbv;
+
+    #[test]
+    fn bbbv_counts_openings_and_lone_numbers() {
+        let cells: HashSet<_> = (0..5)
+            .flat_map(|x| (0..3).map(move |y| Position::new(x, y)))
+            .collect();
+        // a wall of mines down the middle leaves one opening on each side, with every number
+        // bordering one of them
+        let wall: HashSet<_> = (0..3).map(|y| Position::new(2, y)).collect();
+        assert_eq!(bbbv(&cells, &wall), 2);
+
+        // with two walls there are no openings at all, so every number is a click of its own
+        let walls = (0..3)
+            .flat_map(|y| [Position::new(1, y), Position::new(3, y)])
+            .collect();
+        assert_eq!(bbbv(&cells, &walls), 9);
+    }
+}
@@ -0,0 +1,73 @@
+//! Files which the game keeps between runs, stored next to where the game is run from. Browsers
+//! give no files to keep things in, so nothing survives the page closing.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Reads a file, treating a missing or unreadable one as if there were nothing in it
+pub fn read<T: DeserializeOwned>(path: &str) -> Option<T> {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        rmp_serde::from_slice(&std::fs::read(path).ok()?).ok()
+    }
+    #[cfg(target_arch = "wasm32")]
+    {
+        let _ = path;
+        None
+    }
+}
+
+/// Writes a file, creating the directories it is in if they do not exist yet
+pub fn write<T: Serialize>(path: &str, contents: &T) {
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Err(e) = std::path::Path::new(path)
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::write(path, rmp_serde::to_vec(contents).unwrap()))
+    {
+        log::warn!("Could not save {path}: {e}");
+    }
+    #[cfg(target_arch = "wasm32")]
+    let _ = (path, contents);
+}
+
+pub fn remove(path: &str) {
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Err(e) = std::fs::remove_file(path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            log::warn!("Could not remove {path}: {e}");
+        }
+    }
+    #[cfg(target_arch = "wasm32")]
+    let _ = path;
+}
+
+pub fn exists(path: &str) -> bool {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        std::path::Path::new(path).exists()
+    }
+    #[cfg(target_arch = "wasm32")]
+    {
+        let _ = path;
+        false
+    }
+}
+
+/// Paths of the files in a directory, sorted by name
+pub fn list(dir: &str) -> Vec<String> {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let mut paths: Vec<_> = std::fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| Some(entry.ok()?.path().to_str()?.to_string()))
+            .collect();
+        paths.sort();
+        paths
+    }
+    #[cfg(target_arch = "wasm32")]
+    {
+        let _ = dir;
+        Vec::new()
+    }
+}